@@ -21,6 +21,8 @@ fn get_cpp_files_in_directory(directory_path: &str) -> Vec<String> {
 
 fn main() {
     let directory_path = "./job-system-lib/";
+    // cc only asks to be rerun for its env vars, which hides changes to the sources
+    println!("cargo:rerun-if-changed={}", directory_path);
     cc::Build::new()
         .files(get_cpp_files_in_directory(directory_path).as_slice())
         .std("c++17")
@@ -88,9 +88,7 @@ void JobSystem::setResultFromWorker(std::string id, std::string result) {
 std::string JobSystem::runJob(std::string jobType, std::string jobInput) {
     std::string id = this->QueueJob(jobType, jobInput);
     // Wait for job to complete
-    while (!this->IsJobComplete(id)) {
-        std::this_thread::yield();
-    }
+    this->WaitForJob(id, -1);
     // Get job result
     this->resultsMutex.lock();
     std::string result = this->results[id];
@@ -99,6 +97,16 @@ std::string JobSystem::runJob(std::string jobType, std::string jobInput) {
     return result;
 }
 
+std::string JobSystem::CompleteJob(std::string jobId) {
+    // Hand the result over and forget about the job
+    this->resultsMutex.lock();
+    std::string result = this->results[jobId];
+    this->results.erase(jobId);
+    this->resultsMutex.unlock();
+    this->DestroyJob(jobId);
+    return result;
+}
+
 std::optional<std::unique_ptr<Job>> JobSystem::ClaimAJob() {
     this->_jobsQueuedMutex.lock();
     if (this->_jobsQueued.size() > 0) {
@@ -124,6 +132,25 @@ bool JobSystem::IsJobComplete(std::string jobId) const {
     return false;
 }
 
+bool JobSystem::WaitForJob(std::string jobId, long long timeoutMs) const {
+    std::unique_lock<std::mutex> lock(this->_jobsCompletedMutex);
+    auto isComplete = [&]() {
+        for (auto &job : this->_jobsCompleted) {
+            if (job.get()->id == jobId) {
+                return true;
+            }
+        }
+        return false;
+    };
+
+    if (timeoutMs < 0) {
+        this->_jobsCompletedCondition.wait(lock, isComplete);
+        return true;
+    }
+    return this->_jobsCompletedCondition.wait_for(
+        lock, std::chrono::milliseconds(timeoutMs), isComplete);
+}
+
 bool JobSystem::HasJobsActive() {
     std::lock_guard<std::mutex> guard(this->_jobsQueuedMutex);
     std::lock_guard<std::mutex> guard2(this->_workerThreadsMutex);
@@ -148,6 +175,7 @@ void JobSystem::MarkJobComplete(std::unique_ptr<Job> job) {
     this->_jobsCompletedMutex.lock();
     this->_jobsCompleted.emplace_back(std::move(job));
     this->_jobsCompletedMutex.unlock();
+    this->_jobsCompletedCondition.notify_all();
 }
 
 void JobSystem::addHistoryEntry(JobHistoryEntry entry) {
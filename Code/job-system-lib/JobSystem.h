@@ -3,6 +3,8 @@
 #include "JobWorkerThread.h"
 #include "Types.h"
 #include <atomic>
+#include <chrono>
+#include <condition_variable>
 #include <deque>
 #include <memory>
 #include <mutex>
@@ -31,6 +33,10 @@ class JobSystem {
 
     bool IsJobComplete(std::string jobId) const;
 
+    // Blocks until the job is complete, returns false if the timeout passed
+    // first. A negative timeout waits forever.
+    bool WaitForJob(std::string jobId, long long timeoutMs) const;
+
     JobStatus GetJobStatus(std::string jobId) const;
 
     bool HasJobsActive();
@@ -63,6 +69,7 @@ class JobSystem {
 
     mutable std::mutex _jobsQueuedMutex;
     mutable std::mutex _jobsCompletedMutex;
+    mutable std::condition_variable _jobsCompletedCondition;
 
     std::vector<JobHistoryEntry> m_jobHistory;
     mutable std::mutex m_jobHistoryMutex;
@@ -8,6 +8,7 @@
 
 extern "C" {
 const char *run_rust_job(const char *job_type, const char *job_input);
+void free_rust_string(const char *string);
 }
 
 JobWorkerThread::JobWorkerThread(std::string id) {
@@ -44,6 +45,7 @@ void JobWorkerThread::Work() {
 
             // Move job to complete queue
             system->setResultFromWorker(this->_job->id, result);
+            free_rust_string(result);
             system->MarkJobComplete(std::move(this->_job));
             this->_job = std::unique_ptr<Job>();
             hasJobToDo = true;
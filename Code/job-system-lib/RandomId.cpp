@@ -8,8 +8,10 @@ std::string generateRandomID(int length) {
     static const char charset[] =
         "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
     static const int charsetSize = sizeof(charset) - 1;
-    static std::mt19937 generator(static_cast<unsigned>(std::time(0)));
-    static std::uniform_int_distribution<int> distribution(0, charsetSize - 1);
+    // Jobs are queued from several threads, so each one gets its own generator
+    static thread_local std::mt19937 generator(std::random_device{}());
+    static thread_local std::uniform_int_distribution<int> distribution(
+        0, charsetSize - 1);
 
     std::string randomID;
     for (int i = 0; i < length; ++i) {
@@ -2,6 +2,7 @@
 #include "RandomId.h"
 #include <iostream>
 #include <random>
+#include <stdlib.h>
 #include <string.h>
 
 extern "C" {
@@ -23,8 +24,10 @@ bool HasJobsActive() {
 }
 
 void Destroy() {
-    JobSystem *system = JobSystem::CreateOrGet();
-    delete system;
+    // Workers use the system until they stop, so they are stopped and joined first
+    JobSystem::Destroy();
+    delete JobSystem::s_jobSystem;
+    JobSystem::s_jobSystem = nullptr;
 }
 
 bool IsJobComplete(char *jobId) {
@@ -32,6 +35,11 @@ bool IsJobComplete(char *jobId) {
     return system->IsJobComplete(jobId);
 }
 
+bool WaitForJob(char *jobId, long long timeoutMs) {
+    JobSystem *system = JobSystem::CreateOrGet();
+    return system->WaitForJob(jobId, timeoutMs);
+}
+
 int GetJobStatus(char *jobId) {
     try {
         JobSystem *system = JobSystem::CreateOrGet();
@@ -53,10 +61,19 @@ void DestroyJob(char *jobId) {
     system->DestroyJob(jobId);
 }
 
+char *CompleteJob(char *jobId) {
+    JobSystem *system = JobSystem::CreateOrGet();
+    auto result = system->CompleteJob(jobId);
+    return strdup(result.c_str());
+}
+
 char *RunJob(char *jobType, char *jobInput) {
     JobSystem *system = JobSystem::CreateOrGet();
     auto result = system->runJob(jobType, jobInput);
     return strdup(result.c_str());
 }
 
+// Strings returned by the functions above are the caller's to free
+void FreeString(char *string) { free(string); }
+
 }
@@ -10,11 +10,12 @@ use crate::output::MappedJsonError;
 use crate::system::job_core::Job;
 
 use anyhow::Result;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub enum Model {
     ChatGpt,
     Mistral,
@@ -76,7 +77,7 @@ fn get_api_model_from_model(model: &Model) -> String {
     }
 }
 
-pub fn make_ai_request(prompt: &Vec<Message>, model: &Model) -> Result<ApiResponse> {
+pub fn make_ai_request(prompt: &[Message], model: &Model) -> Result<ApiResponse> {
     let url = format!("{}/v1/chat/completions", get_url_from_model(model));
     let client = reqwest::blocking::Client::builder()
         .timeout(Duration::from_secs(800))
@@ -84,7 +85,7 @@ pub fn make_ai_request(prompt: &Vec<Message>, model: &Model) -> Result<ApiRespon
     let input = ApiInput {
         model: get_api_model_from_model(model),
         max_tokens: 800,
-        messages: prompt.clone(),
+        messages: prompt.to_vec(),
     };
     let auth_token = env::var("OPENAI_TOKEN")?;
     let response = client
@@ -108,7 +109,7 @@ pub fn make_ai_request(prompt: &Vec<Message>, model: &Model) -> Result<ApiRespon
 
 // Compiler fixing
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct FixCodeJob {
     pub model: Model,
     pub output_json: MappedJsonError,
@@ -122,7 +123,7 @@ impl Job for FixCodeJob {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct FixCodeResult {
     pub code: String,
     pub explanation: String,
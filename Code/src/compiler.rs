@@ -1,5 +1,5 @@
 use anyhow::Result;
-use serde_json::json;
+use schemars::JsonSchema;
 use std::path::PathBuf;
 
 use serde::Deserialize;
@@ -7,20 +7,20 @@ use serde::Serialize;
 
 use crate::system::job_core::Job;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Location {
     pub file: PathBuf,
     pub line: i32,
     pub column: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct LocationPair {
     pub caret: Location,
     finish: Option<Location>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub enum ErrorKind {
     #[serde(rename = "note")]
     Note,
@@ -30,22 +30,28 @@ pub enum ErrorKind {
     Warning,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ClangOutputJson {
     kind: ErrorKind,
     pub message: String,
     pub locations: Vec<LocationPair>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct CompileJob {
     pub files: Vec<PathBuf>,
     pub fix_warnings: bool,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct CompileResult {
+    pub errors: Vec<ClangOutputJson>,
+}
+
 impl Job for CompileJob {
     fn run(&self) -> Result<serde_json::Value> {
-        self.compile().map(|output| json!({"errors": output}))
+        let errors = self.compile()?;
+        Ok(serde_json::to_value(CompileResult { errors })?)
     }
 }
 
@@ -1,5 +1,14 @@
-use anyhow::Result;
-use std::{io::Write, path::PathBuf};
+use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{output::MappedJsonError, system::job_core::Job};
 
 pub fn get_all_cpp_files_in_folder_path(path: &PathBuf) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
@@ -21,66 +30,178 @@ pub fn get_all_cpp_files_in_folder_path(path: &PathBuf) -> Result<Vec<PathBuf>>
     Ok(files)
 }
 
+// Writes an approved fix in a fix flow
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ApplyFixJob {
+    output_json: MappedJsonError,
+    code: String,
+    #[serde(default)]
+    approved: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ApplyFixResult {
+    pub applied: bool,
+}
+
+impl Job for ApplyFixJob {
+    fn run(&self) -> Result<Value> {
+        self.apply_with(replace_code)
+    }
+}
+
+impl ApplyFixJob {
+    // Fix flows pass a writer that goes through the workspace, so --dry-run
+    // and undo cover them too
+    pub fn apply_with(&self, write: impl FnOnce(&Path, &str) -> Result<()>) -> Result<Value> {
+        if !self.approved {
+            return Ok(serde_json::to_value(ApplyFixResult { applied: false })?);
+        }
+        if self.code.trim().is_empty() {
+            return Err(anyhow!("The fix for {} is empty", self.output_json.filepath.to_string_lossy()));
+        }
+        write(&self.output_json.filepath, &self.code)?;
+        Ok(serde_json::to_value(ApplyFixResult { applied: true })?)
+    }
+}
+
+const BOM: char = '\u{feff}';
+
+pub fn replace_code(path: &Path, new_code: &str) -> Result<()> {
+    let old_code = fs::read_to_string(path)?;
+    write_atomic(path, &splice_code(&old_code, new_code))
+}
+
+// Smartly insert the new code. If the first and last lines of the new code can be
+// found in the old code only that range is replaced, otherwise the whole file is.
+// The BOM, line endings and trailing newline of the old code are kept.
+pub fn splice_code(old_code: &str, new_code: &str) -> String {
+    let (bom, old_body) = match old_code.strip_prefix(BOM) {
+        Some(body) => (BOM.to_string(), body),
+        None => (String::new(), old_code),
+    };
+    let newline = if old_body.contains("\r\n") { "\r\n" } else { "\n" };
+    let trailing_newline = old_body.is_empty() || old_body.ends_with('\n');
 
-pub fn replace_code(path: &PathBuf, new_code: String) {
-    // Smartly insert the new code
-    let mut file = std::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(path)
-        .expect("Open file");
+    let old_lines: Vec<&str> = old_body.lines().collect();
+    let new_lines: Vec<&str> = new_code.trim_start_matches(BOM).lines().collect();
 
+    let lines = match find_replaced_range(&old_lines, &new_lines) {
+        Some((start, end)) => {
+            let mut lines = old_lines.clone();
+            lines.splice(start..=end, new_lines);
+            lines
+        }
+        None => new_lines,
+    };
+
+    let mut result = bom + &lines.join(newline);
+    if trailing_newline && !lines.is_empty() {
+        result.push_str(newline);
+    }
+    result
+}
+
+fn find_replaced_range(old_lines: &[&str], new_lines: &[&str]) -> Option<(usize, usize)> {
     // Match the first and last lines
-    let new_code_lines: Vec<&str> = new_code.lines().collect();
+    let first = new_lines.iter().position(|line| !line.trim().is_empty())?;
+    let last = new_lines.iter().rposition(|line| !line.trim().is_empty())?;
+    let span = last - first + 1;
 
-    let new_first_line = new_code_lines
-        .iter()
-        .filter(|line| !line.is_empty())
-        .next()
-        .unwrap();
-    let new_last_line = new_code_lines
+    let start = old_lines.iter().position(|line| *line == new_lines[first])?;
+
+    // The last line is looked for after the first one. Lines like `}` show up
+    // all over a file, so the nearest one that leaves the replaced range at
+    // least half as long as the fix wins.
+    let ends: Vec<usize> = old_lines[start..]
         .iter()
-        .rev()
-        .filter(|line| !line.is_empty())
-        .next()
-        .unwrap();
-
-    let mut start_index = 0;
-    let mut end_index = 0;
-
-    let mut old_code_lines: Vec<String> = std::fs::read_to_string(path)
-        .expect("Read file")
-        .lines()
-        .map(|line| line.to_owned())
+        .enumerate()
+        .filter(|(_, line)| **line == new_lines[last])
+        .map(|(i, _)| start + i)
         .collect();
+    let end = ends
+        .iter()
+        .find(|end| (*end - start + 1) * 2 >= span)
+        .or(ends.last())?;
 
-    for (i, line) in old_code_lines.iter().enumerate() {
-        if line.is_empty() {
-            continue;
-        }
-        if line == new_first_line {
-            start_index = i;
-            continue;
-        }
-        if line == new_last_line {
-            end_index = i;
-            break;
-        }
+    Some((start, *end))
+}
+
+// Writes to a temp file next to the target and renames it over the original,
+// so a crash never leaves a half written file. Permissions are kept.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.as_file().sync_all()?;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        temp.as_file().set_permissions(metadata.permissions())?;
     }
 
-    // If still not found, just replace the whole file
-    if start_index == 0 && end_index == 0 {
-        file.write_all(new_code.as_bytes()).expect("Write file");
-        return;
+    temp.persist(path)
+        .map_err(|e| anyhow!("Could not write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "#include <cstdio>\n\nint add(int a, int b) {\n    return a - b;\n}\n\nint main() {\n    printf(\"%d\", add(1, 2));\n}\n";
+
+    #[test]
+    fn fix_in_the_middle_keeps_the_rest() {
+        let fix = "int add(int a, int b) {\n    return a + b;\n}";
+        assert_eq!(
+            splice_code(FILE, fix),
+            FILE.replace("return a - b", "return a + b")
+        );
+    }
+
+    #[test]
+    fn fix_that_grows_keeps_later_functions() {
+        let fix = "int add(int a, int b) {\n    int sum = a + b;\n    return sum;\n}";
+        let spliced = splice_code(FILE, fix);
+        assert!(spliced.contains("int sum = a + b;\n    return sum;\n}\n\nint main() {"));
+        assert!(spliced.ends_with("add(1, 2));\n}\n"));
+    }
+
+    #[test]
+    fn bom_is_kept() {
+        let old = format!("{}{}", BOM, FILE);
+        let fix = format!("{}int add(int a, int b) {{\n    return a + b;\n}}", BOM);
+        let spliced = splice_code(&old, &fix);
+        assert!(spliced.starts_with(BOM));
+        assert_eq!(spliced.matches(BOM).count(), 1);
+        assert!(spliced.contains("return a + b;"));
     }
 
-    // Remove the range and replace with the new code
-    old_code_lines.splice(
-        start_index..end_index + 1,
-        new_code_lines.iter().map(|s| s.to_string()),
-    );
+    #[test]
+    fn crlf_is_kept() {
+        let old = FILE.replace('\n', "\r\n");
+        let fix = "int add(int a, int b) {\n    return a + b;\n}";
+        assert_eq!(
+            splice_code(&old, fix),
+            old.replace("return a - b", "return a + b")
+        );
+    }
 
-    // Write the new code
-    file.write_all(old_code_lines.join("\n").as_bytes())
-        .expect("Write file");
+    #[test]
+    fn missing_trailing_newline_is_kept() {
+        let old = FILE.trim_end();
+        let fix = "int main() {\n    printf(\"%d\", add(2, 2));\n}\n";
+        let spliced = splice_code(old, fix);
+        assert!(spliced.ends_with("add(2, 2));\n}"));
+        assert!(spliced.starts_with("#include <cstdio>\n\nint add"));
+    }
+
+    #[test]
+    fn unknown_code_replaces_the_file() {
+        assert_eq!(splice_code(FILE, "int main() {}\n"), "int main() {}\n");
+    }
 }
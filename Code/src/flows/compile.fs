@@ -0,0 +1,9 @@
+// The flow used when no project or user flow is named `compile`. Copy it to
+// .code-agent/flows/compile.fs to change it for one project.
+digraph {
+  input;
+  Compile [kind="task"];
+  Output [kind="task"];
+
+  input -> Compile -> Output;
+}
@@ -0,0 +1,16 @@
+// An example fix flow, run it with `code-agent --fix-flow src/flows/fix.fs`.
+// Every time round the files are compiled, the first error goes to FixCode and
+// the fix is written once it is approved. The cycle ends when nothing is left
+// to fix, the user quits or max is reached.
+digraph fix {
+  cycle [kind="loop", label="(.errors == null || .errors.length > 0) && .quit != true", max="10"];
+  anyErrors [kind="if", label=".errors.length > 0"];
+  done [kind="set", label="quit = true"];
+
+  input -> cycle;
+  cycle -> Compile [label="body"];
+  Compile -> Output -> anyErrors;
+  anyErrors -> SelectError [label="true"];
+  anyErrors -> done [label="false"];
+  SelectError -> FixCode -> Approve -> ApplyFix;
+}
@@ -0,0 +1,163 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+pub const DEFAULT_FLOW: &str = "compile";
+
+// Flows compiled into the binary so a new install works without asking ChatGPT
+const BUILT_IN: &[(&str, &str)] = &[(DEFAULT_FLOW, include_str!("./compile.fs"))];
+
+pub struct Flow {
+    pub name: String,
+    pub script: String,
+    pub path: Option<PathBuf>, // None for built-in flows
+}
+
+fn flows_dir(directory: &Path) -> PathBuf {
+    directory.join(".code-agent").join("flows")
+}
+
+// Names end up in file paths so they stay plain words
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(anyhow!("Flow names can only use letters, digits, - and _, got {}", name)),
+    }
+}
+
+// Looks in the project's .code-agent/flows, then the one in the home directory,
+// then the flows built into the binary
+pub fn find(directory: &Path, name: &str) -> Result<Flow> {
+    let home = env::var("HOME").ok().map(PathBuf::from);
+    find_from(directory, home.as_deref(), name)
+}
+
+fn find_from(directory: &Path, home: Option<&Path>, name: &str) -> Result<Flow> {
+    check_name(name)?;
+    let file = format!("{}.fs", name);
+    let dirs = [Some(flows_dir(directory)), home.map(flows_dir)];
+    for path in dirs.into_iter().flatten().map(|dir| dir.join(&file)) {
+        if path.exists() {
+            return Ok(Flow {
+                name: name.to_owned(),
+                script: fs::read_to_string(&path)?,
+                path: Some(path),
+            });
+        }
+    }
+
+    let Some((_, script)) = BUILT_IN.iter().find(|(built_in, _)| *built_in == name) else {
+        return Err(anyhow!(
+            "No flow named {}, add it as {}",
+            name,
+            flows_dir(directory).join(file).to_string_lossy()
+        ));
+    };
+    warn_old_script();
+    Ok(Flow {
+        name: name.to_owned(),
+        script: script.to_string(),
+        path: None,
+    })
+}
+
+// Scripts used to be saved to ~/.fsprompt and used by every project
+fn warn_old_script() {
+    let Ok(home) = env::var("HOME") else {
+        return;
+    };
+    if Path::new(&home).join(".fsprompt").exists() {
+        println!(
+            "~/.fsprompt is no longer read, move it to ~/.code-agent/flows/{}.fs to keep using it",
+            DEFAULT_FLOW
+        );
+    }
+}
+
+// Where a project flow with this name lives
+pub fn path(directory: &Path, name: &str) -> Result<PathBuf> {
+    check_name(name)?;
+    Ok(flows_dir(directory).join(format!("{}.fs", name)))
+}
+
+// New flows are saved to the project so other projects keep their own
+pub fn save(directory: &Path, name: &str, script: &str) -> Result<PathBuf> {
+    let path = path(directory, name)?;
+    fs::create_dir_all(flows_dir(directory))?;
+    fs::write(&path, script)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{find_from, flows_dir, save, BUILT_IN, DEFAULT_FLOW};
+    use crate::flowscript::check_flowscript;
+
+    fn write_flow(directory: &TempDir, name: &str, script: &str) {
+        fs::create_dir_all(flows_dir(directory.path())).unwrap();
+        fs::write(flows_dir(directory.path()).join(format!("{}.fs", name)), script).unwrap();
+    }
+
+    // The example fix flow isn't built in but is shipped next to them
+    #[test]
+    fn shipped_flows_are_valid() {
+        let example = ("fix", include_str!("./fix.fs"));
+        for (name, script) in BUILT_IN.iter().chain([&example]) {
+            assert!(check_flowscript(script, None).is_ok(), "{} does not check", name);
+        }
+    }
+
+    #[test]
+    fn the_project_comes_before_home_and_home_before_built_in() {
+        let (project, home) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let built_in = find_from(project.path(), Some(home.path()), DEFAULT_FLOW).unwrap();
+        assert_eq!(built_in.script, BUILT_IN[0].1);
+        assert!(built_in.path.is_none());
+
+        write_flow(&home, DEFAULT_FLOW, "from home");
+        let flow = find_from(project.path(), Some(home.path()), DEFAULT_FLOW).unwrap();
+        assert_eq!(flow.script, "from home");
+        assert_eq!(flow.path.unwrap(), flows_dir(home.path()).join("compile.fs"));
+
+        write_flow(&project, DEFAULT_FLOW, "from the project");
+        let flow = find_from(project.path(), Some(home.path()), DEFAULT_FLOW).unwrap();
+        assert_eq!(flow.script, "from the project");
+        assert_eq!(flow.path.unwrap(), flows_dir(project.path()).join("compile.fs"));
+    }
+
+    // What `--flow NAME` picks, and where a missing one should go
+    #[test]
+    fn flows_are_found_by_name() {
+        let (project, home) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        write_flow(&home, "lint", "home lint");
+        let path = save(project.path(), "strict", "project strict").unwrap();
+
+        let flow = find_from(project.path(), Some(home.path()), "strict").unwrap();
+        assert_eq!((flow.name.as_str(), flow.script.as_str()), ("strict", "project strict"));
+        assert_eq!(flow.path.unwrap(), path);
+        assert_eq!(find_from(project.path(), Some(home.path()), "lint").unwrap().script, "home lint");
+        assert!(find_from(project.path(), None, "lint").is_err());
+
+        let Err(error) = find_from(project.path(), Some(home.path()), "missing") else {
+            panic!("Found a flow that was never written");
+        };
+        let error = error.to_string();
+        assert!(error.contains("No flow named missing"), "{}", error);
+        assert!(error.contains(&flows_dir(project.path()).join("missing.fs").to_string_lossy().to_string()));
+
+        for name in ["../compile", "a b", "", "x.fs"] {
+            assert!(find_from(project.path(), Some(home.path()), name).is_err(), "{}", name);
+        }
+    }
+}
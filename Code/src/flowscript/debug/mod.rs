@@ -0,0 +1,250 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use dialoguer::Editor;
+use serde_json::Value;
+
+use super::{
+    nodes::{Next, Node},
+    scheduler::{Run, Stop},
+    transform::NodeInfo,
+};
+
+const HELP: &str = "s step, c continue, e edit payload, k skip node, p print payload, q quit";
+
+// What to do with a paused node
+enum Action {
+    Run(Value),
+    Skip(Value),
+}
+
+pub struct Debugger {
+    breakpoints: HashSet<String>,
+    stepping: AtomicBool,
+    // Branches run on their own threads, only one of them gets the prompt at a time
+    prompt: Mutex<Box<dyn BufRead + Send>>,
+    // Every node name a breakpoint could mean, as the graph is built
+    names: Mutex<HashSet<String>>,
+}
+
+impl Debugger {
+    // Starts paused on the first node when there is nowhere to stop
+    pub fn new(breakpoints: Vec<String>) -> Arc<Debugger> {
+        Debugger::reading(breakpoints, Box::new(io::BufReader::new(io::stdin())))
+    }
+
+    fn reading(breakpoints: Vec<String>, keys: Box<dyn BufRead + Send>) -> Arc<Debugger> {
+        Arc::new(Debugger {
+            stepping: AtomicBool::new(breakpoints.is_empty()),
+            breakpoints: breakpoints.into_iter().collect(),
+            prompt: Mutex::new(keys),
+            names: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Once the graph is built, a `--break` that names no node is most likely a typo
+    pub fn check_breakpoints(&self) -> Result<()> {
+        let names = self.names.lock().unwrap();
+        let mut unknown: Vec<&String> = self.breakpoints.iter().filter(|b| !names.contains(*b)).collect();
+        unknown.sort();
+        match unknown.first() {
+            None => Ok(()),
+            Some(name) => Err(anyhow!("There is no node {} to break on", name)),
+        }
+    }
+
+    // `--break Output` stops on Output in every graph, `--break tagger.Output`
+    // only inside the tagger subgraph
+    fn has_breakpoint(&self, info: &NodeInfo) -> bool {
+        let marked = info.attributes.get("breakpoint").is_some_and(|b| b == "true");
+        let local = info.name.rsplit('.').next().unwrap_or(&info.name);
+        marked || self.breakpoints.contains(&info.name) || self.breakpoints.contains(local)
+    }
+
+    // Attribute breakpoints are only known once the graph is built
+    pub fn wrap(self: &Arc<Self>, info: NodeInfo, inner: Box<dyn Node>) -> Box<dyn Node> {
+        let mut names = self.names.lock().unwrap();
+        names.insert(info.name.clone());
+        names.insert(info.name.rsplit('.').next().unwrap_or(&info.name).to_owned());
+        drop(names);
+
+        let breakpoint = self.has_breakpoint(&info);
+        if breakpoint && self.breakpoints.is_empty() {
+            self.stepping.store(false, Ordering::SeqCst);
+        }
+        Box::new(DebugNode {
+            info,
+            breakpoint,
+            inner,
+            debugger: self.clone(),
+        })
+    }
+
+    fn pause(&self, info: &NodeInfo, mut payload: Value) -> Result<Action> {
+        let mut keys = self.prompt.lock().unwrap();
+        println!("Paused before {} ({})", info.name, info.kind);
+        println!("{}", serde_json::to_string_pretty(&payload)?);
+
+        loop {
+            print!("(flowscript) ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            // Running out of input lets the script finish
+            if keys.read_line(&mut line)? == 0 {
+                println!();
+                self.stepping.store(false, Ordering::SeqCst);
+                return Ok(Action::Run(payload));
+            }
+
+            match line.trim() {
+                "s" | "step" => {
+                    self.stepping.store(true, Ordering::SeqCst);
+                    return Ok(Action::Run(payload));
+                }
+                "c" | "continue" => {
+                    self.stepping.store(false, Ordering::SeqCst);
+                    return Ok(Action::Run(payload));
+                }
+                "k" | "skip" => {
+                    if matches!(info.kind, "if" | "switch" | "match") {
+                        println!("{} picks a branch, it can't be skipped", info.name);
+                        continue;
+                    }
+                    self.stepping.store(true, Ordering::SeqCst);
+                    return Ok(Action::Skip(payload));
+                }
+                "e" | "edit" => payload = edit_payload(payload)?,
+                "p" | "print" => println!("{}", serde_json::to_string_pretty(&payload)?),
+                // Like an interrupt, on_error edges don't catch it
+                "q" | "quit" => return Err(Stop::Quit(info.name.clone()).into()),
+                "" | "h" | "help" => println!("{}", HELP),
+                other => println!("Unknown command {}, {}", other, HELP),
+            }
+        }
+    }
+}
+
+// Opens the payload in $EDITOR, keeps the old one if the edit doesn't parse
+fn edit_payload(payload: Value) -> Result<Value> {
+    let text = serde_json::to_string_pretty(&payload)?;
+    let Some(edited) = Editor::new().extension(".json").edit(&text)? else {
+        println!("Edit cancelled");
+        return Ok(payload);
+    };
+    match serde_json::from_str(&edited) {
+        Ok(edited) => {
+            println!("{}", serde_json::to_string_pretty(&edited)?);
+            Ok(edited)
+        }
+        Err(e) => {
+            println!("Not valid JSON, payload unchanged: {}", e);
+            Ok(payload)
+        }
+    }
+}
+
+pub struct DebugNode {
+    info: NodeInfo,
+    breakpoint: bool,
+    inner: Box<dyn Node>,
+    debugger: Arc<Debugger>,
+}
+
+impl Node for DebugNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        if !self.breakpoint && !self.debugger.stepping.load(Ordering::SeqCst) {
+            return self.inner.execute(input, run);
+        }
+
+        match self.debugger.pause(&self.info, input)? {
+            Action::Run(payload) => self.inner.execute(payload, run),
+            // A skipped node passes the payload on untouched
+            Action::Skip(payload) => Ok(match self.info.next {
+                Some(ref next) => Next::To(next.clone(), payload),
+                None => Next::End(payload),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::flowscript::{prepare, scheduler::tests::one_run_at_a_time};
+
+    const SCRIPT: &str = r#"digraph {
+  input -> first -> second -> third;
+  first [kind="set", label="steps = 'first'"];
+  second [kind="set", label="steps = .steps + ', second'"];
+  third [kind="set", label="steps = .steps + ', third'"];
+}"#;
+
+    // Runs a script in the debugger with `keys` typed at its prompt
+    fn debug(script: &str, breakpoints: &[&str], keys: &'static str) -> Result<Value> {
+        let breakpoints = breakpoints.iter().map(|b| b.to_string()).collect();
+        let debugger = Debugger::reading(breakpoints, Box::new(keys.as_bytes()));
+        let (graph, context) = prepare(script, None, &|info, inner| debugger.wrap(info, inner))?;
+        debugger.check_breakpoints()?;
+        context.run(&graph, json!({ "files": [], "fix_warnings": false }))
+    }
+
+    #[test]
+    fn skipped_nodes_pass_the_payload_on() {
+        let _run = one_run_at_a_time();
+        // Paused on second by the breakpoint, skipped, then paused on third by stepping
+        let result = debug(SCRIPT, &["second"], "k\nc\n").unwrap();
+        assert_eq!(result["steps"], json!("first, third"));
+
+        // Without breakpoints every node pauses until continue, input first
+        let result = debug(SCRIPT, &[], "s\nk\nc\n").unwrap();
+        assert_eq!(result["steps"], json!("null, second, third"));
+    }
+
+    #[test]
+    fn branching_nodes_can_not_be_skipped() {
+        let _run = one_run_at_a_time();
+        let script = r#"digraph {
+  input -> check;
+  check [kind="if", label=".fix_warnings"];
+  check -> yes [label="true"];
+  check -> no [label="false"];
+  yes [kind="set", label="took = 'yes'"];
+  no [kind="set", label="took = 'no'"];
+}"#;
+        let result = debug(script, &["check"], "k\nc\n").unwrap();
+        assert_eq!(result["took"], json!("no"));
+    }
+
+    #[test]
+    fn breakpoints_have_to_name_a_node() {
+        let _run = one_run_at_a_time();
+        let error = debug(SCRIPT, &["second", "secnod"], "").unwrap_err();
+        assert_eq!(error.to_string(), "There is no node secnod to break on");
+    }
+
+    #[test]
+    fn quitting_skips_on_error() {
+        let _run = one_run_at_a_time();
+        let script = r#"digraph {
+  input -> tidy -> done;
+  tidy -> failed [kind="on_error"];
+  done [kind="set", label="outcome = 'done'"];
+  failed [kind="set", label="outcome = 'failed'"];
+  subgraph tidy {
+    input -> clean;
+    clean [kind="set", label="remove fix_warnings"];
+  }
+}"#;
+        let error = debug(script, &["tidy.clean"], "q\n").unwrap_err();
+        assert!(matches!(error.downcast_ref::<Stop>(), Some(Stop::Quit(node)) if node == "tidy.clean"));
+    }
+}
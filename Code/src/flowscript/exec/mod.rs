@@ -0,0 +1,303 @@
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::parser::{conditional, parse_duration, ExecDef};
+
+// Processes that don't set a timeout and aren't covered by the config
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// `~/.code-agent/exec.json`, never one from the project so a cloned repository
+// can't allow itself to run things. Nothing is allowed to run until it's listed
+// in `allow`, `"*"` allows everything.
+#[derive(Deserialize, Default, Debug)]
+pub struct ExecConfig {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    timeout: Option<String>,
+    // Only PATH is passed on to the process
+    #[serde(default)]
+    clear_env: bool,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    let home = env::var("HOME").ok()?;
+    Some(Path::new(&home).join(".code-agent").join("exec.json"))
+}
+
+fn load_config() -> Result<ExecConfig, String> {
+    let Some(path) = config_path().filter(|p| p.exists()) else {
+        return Ok(ExecConfig::default());
+    };
+    let text = fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let mut config: ExecConfig =
+        serde_json::from_str(&text).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    if let Some(ref timeout) = config.timeout {
+        if parse_duration(timeout).is_none() {
+            return Err(format!("The timeout in {} must be a duration like 30s", path.display()));
+        }
+    }
+    config.path = Some(path);
+    Ok(config)
+}
+
+// Read once, scripts are checked and run by the same process
+pub fn config() -> Result<&'static ExecConfig, String> {
+    static CONFIG: OnceLock<Result<ExecConfig, String>> = OnceLock::new();
+    CONFIG.get_or_init(load_config).as_ref().map_err(|e| e.clone())
+}
+
+impl ExecConfig {
+    // Commands have to be listed exactly as the script writes them
+    pub fn check(&self, command: &str) -> Result<(), String> {
+        if self.allow.iter().any(|allowed| allowed == "*" || allowed == command) {
+            return Ok(());
+        }
+        let file = match self.path {
+            Some(ref path) => path.display().to_string(),
+            None => "~/.code-agent/exec.json".to_owned(),
+        };
+        Err(format!("{} is not allowed to run, add it to `allow` in {}", command, file))
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout.as_deref().and_then(parse_duration).unwrap_or(DEFAULT_TIMEOUT)
+    }
+}
+
+// Templates ----------------------------
+
+enum Piece {
+    Text(String),
+    Expression(String),
+}
+
+// `{{` and `}}` are literal braces
+fn pieces(template: &str) -> Result<Vec<Piece>, String> {
+    let mut result = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut expression = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => expression.push(c),
+                        None => return Err(format!("Unclosed {{ in {}", template)),
+                    }
+                }
+                if !text.is_empty() {
+                    result.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                result.push(Piece::Expression(expression.trim().to_owned()));
+            }
+            '}' => return Err(format!("Unmatched }} in {}", template)),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        result.push(Piece::Text(text));
+    }
+    Ok(result)
+}
+
+pub fn check_template(template: &str) -> Result<(), String> {
+    for piece in pieces(template)? {
+        if let Piece::Expression(expression) = piece {
+            conditional::check_condition(&expression).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn as_arg(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+// An arg that is only `{.files}` becomes one arg per element of the array
+fn render(template: &str, payload: &Value) -> Result<Vec<String>> {
+    let pieces = pieces(template).map_err(|e| anyhow!(e))?;
+    let mut values = Vec::new();
+    for piece in &pieces {
+        values.push(match piece {
+            Piece::Text(text) => Value::String(text.clone()),
+            Piece::Expression(expression) => conditional::evaluate_expression(expression, payload)
+                .map_err(|e| anyhow!("Could not fill in {{{}}}: {}", expression, e))?,
+        });
+    }
+
+    Ok(match (pieces.as_slice(), values.as_slice()) {
+        ([Piece::Expression(_)], [Value::Array(items)]) => items.iter().map(as_arg).collect(),
+        _ => vec![values.iter().map(as_arg).collect()],
+    })
+}
+
+// Running ----------------------------
+
+fn read_all(stream: Option<impl Read + Send + 'static>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut stream) = stream {
+            let _ = stream.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    })
+}
+
+// A process that ran is a result even when it exits with an error code, only
+// not being able to run it or running out of time is a failure
+pub fn run(def: &ExecDef, payload: &Value) -> Result<Value> {
+    run_with(config().map_err(|e| anyhow!(e))?, def, payload)
+}
+
+fn run_with(config: &ExecConfig, def: &ExecDef, payload: &Value) -> Result<Value> {
+    config.check(&def.command).map_err(|e| anyhow!(e))?;
+
+    let mut args = Vec::new();
+    for template in &def.args {
+        args.extend(render(template, payload)?);
+    }
+
+    let mut command = Command::new(&def.command);
+    command
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if config.clear_env {
+        command.env_clear();
+        if let Ok(path) = env::var("PATH") {
+            command.env("PATH", path);
+        }
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("Could not run {}: {}", def.command, e))?;
+
+    // Read while waiting so a full pipe can't block the process
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let timeout = def.options.timeout.unwrap_or(config.timeout());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("{} timed out after {:?}", def.command, timeout));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let output = json!({
+        "stdout": stdout.join().unwrap_or_default(),
+        "stderr": stderr.join().unwrap_or_default(),
+        "exit_code": status.code(),
+    });
+    let Some(ref into) = def.into else {
+        return Ok(output);
+    };
+    let mut result = payload.clone();
+    conditional::set_path(into, &mut result, output).map_err(|e| anyhow!("Could not write to {} {}", into, e))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flowscript::parser::TaskOptions;
+
+    fn allowing(commands: &[&str]) -> ExecConfig {
+        ExecConfig {
+            allow: commands.iter().map(|c| c.to_string()).collect(),
+            ..ExecConfig::default()
+        }
+    }
+
+    fn sh(script: &str, into: Option<&str>) -> ExecDef {
+        ExecDef {
+            command: "sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            into: into.map(str::to_owned),
+            options: TaskOptions::default(),
+        }
+    }
+
+    #[test]
+    fn templates_fill_in_expressions_and_expand_lone_arrays() {
+        let payload = json!({ "name": "main", "files": ["a.cpp", "b.cpp"], "count": 2 });
+        assert_eq!(render("{.name}.o", &payload).unwrap(), ["main.o"]);
+        assert_eq!(render("-j{.count + 1}", &payload).unwrap(), ["-j3"]);
+        assert_eq!(render("{{literal}}", &payload).unwrap(), ["{literal}"]);
+        assert_eq!(render("{.files}", &payload).unwrap(), ["a.cpp", "b.cpp"]);
+        // Inside other text an array stays one arg
+        assert_eq!(render("--files={.files}", &payload).unwrap(), [r#"--files=["a.cpp","b.cpp"]"#]);
+    }
+
+    #[test]
+    fn templates_with_unmatched_braces_are_refused() {
+        assert_eq!(check_template("{.name").unwrap_err(), "Unclosed { in {.name");
+        assert_eq!(check_template("}{.name}{").unwrap_err(), "Unmatched } in }{.name}{");
+        // Counting braces would let this one through
+        assert_eq!(check_template("{.a}{{{.b").unwrap_err(), "Unclosed { in {.a}{{{.b");
+        assert!(check_template("{.name} {{x}}").is_ok());
+    }
+
+    #[test]
+    fn only_allowed_commands_run() {
+        assert!(allowing(&["sh"]).check("sh").is_ok());
+        assert!(allowing(&["*"]).check("make").is_ok());
+        let error = allowing(&["sh"]).check("rm").unwrap_err();
+        assert!(error.starts_with("rm is not allowed to run"), "{}", error);
+        assert!(run_with(&allowing(&[]), &sh("true", None), &json!({})).is_err());
+    }
+
+    #[test]
+    fn output_goes_into_the_payload_at_into() {
+        let payload = json!({ "build": { "target": "main" } });
+        let result = run_with(&allowing(&["sh"]), &sh("echo built; exit 3", Some(".build.run")), &payload).unwrap();
+        assert_eq!(result["build"]["target"], json!("main"));
+        assert_eq!(result["build"]["run"], json!({ "stdout": "built\n", "stderr": "", "exit_code": 3 }));
+
+        let result = run_with(&allowing(&["sh"]), &sh("echo oops >&2", None), &payload).unwrap();
+        assert_eq!(result, json!({ "stdout": "", "stderr": "oops\n", "exit_code": 0 }));
+    }
+
+    #[test]
+    fn processes_that_run_too_long_are_killed() {
+        let mut def = sh("sleep 5", None);
+        def.options.timeout = Some(Duration::from_millis(100));
+        let started = Instant::now();
+        let error = run_with(&allowing(&["sh"]), &def, &json!({})).unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
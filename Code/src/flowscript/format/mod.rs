@@ -0,0 +1,517 @@
+use pest::{iterators::Pair, Parser};
+
+use super::{
+    modules::Program,
+    parser::{raw_id, ConnectionType, Defs, FlowscriptParser, NodeDef, Rule},
+};
+
+const KEYWORDS: [&str; 7] = ["strict", "digraph", "graph", "subgraph", "node", "edge", "import"];
+
+// IDs are written bare when they can be, quoted otherwise
+fn format_id(raw: &str) -> String {
+    let mut chars = raw.chars();
+    let plain = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&raw.to_lowercase().as_str());
+    match plain {
+        true => raw.to_owned(),
+        false => format!("\"{}\"", raw),
+    }
+}
+
+// Values are always quoted, html strings stay html
+fn format_value(pair: Pair<Rule>) -> String {
+    let html = pair.clone().into_inner().flatten().any(|p| p.as_rule() == Rule::html_id);
+    match html {
+        true => format!("<{}>", raw_id(pair)),
+        false => format!("\"{}\"", raw_id(pair)),
+    }
+}
+
+// Every attribute list of a statement is written as one
+fn format_attributes(pair: Pair<Rule>) -> String {
+    let attrs: Vec<String> = pair
+        .into_inner()
+        .filter(|p| p.as_rule() == Rule::attributes)
+        .flat_map(|p| p.into_inner())
+        .filter(|p| p.as_rule() == Rule::attribute)
+        .map(|attr| {
+            let mut name = String::new();
+            let mut value = String::new();
+            for part in attr.into_inner() {
+                match part.as_rule() {
+                    Rule::attribute_name => name = raw_id(part),
+                    Rule::attribute_value => value = format_value(part),
+                    _ => {}
+                }
+            }
+            format!("{}={}", format_id(&name), value)
+        })
+        .collect();
+    match attrs.is_empty() {
+        true => String::new(),
+        false => format!(" [{}]", attrs.join(", ")),
+    }
+}
+
+fn format_line(pair: Pair<Rule>, indent: &str) -> String {
+    let mut names = Vec::new();
+    let mut target = String::new();
+    for part in pair.clone().into_inner() {
+        match part.as_rule() {
+            Rule::variable => names.push(format_id(&raw_id(part))),
+            Rule::node_group => {
+                let group: Vec<String> = part
+                    .into_inner()
+                    .filter(|p| p.as_rule() == Rule::variable)
+                    .map(|p| format_id(&raw_id(p)))
+                    .collect();
+                names.push(format!("{{ {} }}", group.join(" ")));
+            }
+            Rule::attribute_target => target = part.as_str().to_lowercase(),
+            _ => {}
+        }
+    }
+    let head = match target.is_empty() {
+        true => names.join(" -> "),
+        false => target,
+    };
+    format!("{}{}{};", indent, head, format_attributes(pair))
+}
+
+fn find(pair: &Pair<Rule>, rule: Rule) -> String {
+    pair.clone()
+        .into_inner()
+        .find(|p| p.as_rule() == rule)
+        .map(raw_id)
+        .unwrap_or_default()
+}
+
+// A statement with the comments written above it and after it on the same line
+#[derive(Default)]
+struct Item {
+    leading: Vec<String>,
+    text: String,
+    trailing: Option<String>,
+}
+
+impl Item {
+    fn write(&self, indent: &str) -> String {
+        let mut result = String::new();
+        for comment in &self.leading {
+            result.push_str(&format!("{}{}\n", indent, comment));
+        }
+        result.push_str(&self.text);
+        if let Some(ref comment) = self.trailing {
+            result.push_str(&format!(" {}", comment));
+        }
+        result
+    }
+}
+
+// Imports, graph attributes, node definitions, connections and subgraphs, each
+// group in the order it was written and separated by a blank line. Comments
+// move with the statement they belong to. Once `node [..]` or `edge [..]`
+// defaults are used the order matters, so everything stays where it was.
+fn format_body(pairs: Vec<Pair<Rule>>, indent: &str) -> String {
+    let keep_order = pairs.iter().any(|pair| {
+        pair.as_rule() == Rule::attribute_statement
+            && !find_target(pair).eq_ignore_ascii_case("graph")
+    });
+
+    let mut groups: [Vec<Item>; 5] = Default::default();
+    let mut pending = Vec::new();
+    let mut last: Option<(usize, usize)> = None; // Group and line of the last statement
+
+    for pair in pairs {
+        let group = match pair.as_rule() {
+            Rule::COMMENT => {
+                add_comment(pair, last, &mut groups, &mut pending);
+                continue;
+            }
+            _ if keep_order && pair.as_rule() != Rule::subgraph_def => 0,
+            Rule::import_def => 0,
+            Rule::graph_attribute | Rule::attribute_statement => 1,
+            Rule::variable_def => 2,
+            Rule::connection_def => 3,
+            Rule::subgraph_def => 4,
+            _ => continue,
+        };
+
+        let (end, comments) = statement_end(&pair);
+        let text = match pair.as_rule() {
+            Rule::import_def => format!("{}import \"{}\";", indent, find(&pair, Rule::import_path)),
+            Rule::graph_attribute => {
+                let value = pair
+                    .clone()
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::attribute_value)
+                    .map(format_value)
+                    .unwrap_or_default();
+                format!("{}{}={};", indent, format_id(&find(&pair, Rule::attribute_name)), value)
+            }
+            Rule::subgraph_def => {
+                let name = format_id(&find(&pair, Rule::program_name));
+                // Comments after the last statement are children of the subgraph itself
+                let close = closing_brace(&pair);
+                let mut body = Vec::new();
+                for part in pair.clone().into_inner() {
+                    let start = part.as_span().start();
+                    match part.as_rule() {
+                        Rule::subgraph_body => body.extend(part.into_inner()),
+                        Rule::COMMENT if start > name_end(&pair) && start < close => body.push(part),
+                        _ => {}
+                    }
+                }
+                format!(
+                    "{}subgraph {} {{\n{}{}}}",
+                    indent,
+                    name,
+                    format_body(body, &format!("{}  ", indent)),
+                    indent
+                )
+            }
+            _ => format_line(pair, indent),
+        };
+        groups[group].push(Item {
+            leading: std::mem::take(&mut pending),
+            text,
+            trailing: None,
+        });
+        last = Some((group, end));
+        for comment in comments {
+            add_comment(comment, last, &mut groups, &mut pending);
+        }
+    }
+
+    let mut sections: Vec<String> = groups[..4]
+        .iter()
+        .filter(|group| !group.is_empty())
+        .map(|group| {
+            group
+                .iter()
+                .map(|item| item.write(indent))
+                .collect::<Vec<_>>()
+                .join("\n")
+                + "\n"
+        })
+        .collect();
+    sections.extend(groups[4].iter().map(|subgraph| subgraph.write(indent) + "\n"));
+    if !pending.is_empty() {
+        let comments: Vec<String> = pending.iter().map(|c| format!("{}{}\n", indent, c)).collect();
+        sections.push(comments.concat());
+    }
+    sections.join("\n")
+}
+
+// A comment on the line a statement ends on stays behind it, the rest go above
+// the next statement
+fn add_comment(
+    comment: Pair<Rule>,
+    last: Option<(usize, usize)>,
+    groups: &mut [Vec<Item>; 5],
+    pending: &mut Vec<String>,
+) {
+    let text = comment.as_str().trim().to_owned();
+    // A # comment starts with the line break before it
+    let offset = comment.as_str().len() - comment.as_str().trim_start().len();
+    let line = pest::Position::new(comment.get_input(), comment.as_span().start() + offset)
+        .map(|p| p.line_col().0)
+        .unwrap_or_default();
+    match last {
+        Some((group, end)) if end == line => {
+            let item = groups[group].last_mut().expect("Group has the statement");
+            match item.trailing {
+                Some(ref mut trailing) => {
+                    trailing.push(' ');
+                    trailing.push_str(&text);
+                }
+                None => item.trailing = Some(text),
+            }
+        }
+        _ => pending.push(text),
+    }
+}
+
+// A statement can end on an optional part, so the comments after it are parsed
+// as its children. Gives the line the statement really ends on and those comments.
+fn statement_end<'a>(pair: &Pair<'a, Rule>) -> (usize, Vec<Pair<'a, Rule>>) {
+    if pair.as_rule() == Rule::subgraph_def {
+        let close = closing_brace(pair);
+        let line = pest::Position::new(pair.get_input(), close)
+            .map(|p| p.line_col().0)
+            .unwrap_or_default();
+        let comments = pair
+            .clone()
+            .into_inner()
+            .filter(|p| p.as_rule() == Rule::COMMENT && p.as_span().start() > close)
+            .collect();
+        return (line, comments);
+    }
+
+    let inner: Vec<Pair<Rule>> = pair.clone().into_inner().flatten().collect();
+    let end = inner
+        .iter()
+        .filter(|p| p.as_rule() != Rule::COMMENT)
+        .map(|p| p.as_span().end_pos())
+        .max_by_key(|p| p.pos())
+        .unwrap_or(pair.as_span().end_pos());
+    let comments = inner.into_iter().filter(|p| p.as_rule() == Rule::COMMENT).collect();
+    (end.line_col().0, comments)
+}
+
+// The `}` closing a subgraph, skipping comments that might contain one
+fn closing_brace(pair: &Pair<Rule>) -> usize {
+    let input = pair.get_input();
+    let mut position = pair
+        .clone()
+        .into_inner()
+        .find(|p| p.as_rule() == Rule::subgraph_body)
+        .map(|p| p.as_span().end())
+        .unwrap_or(pair.as_span().start());
+    let comments = pair.clone().into_inner().filter(|p| p.as_rule() == Rule::COMMENT);
+    for comment in comments {
+        if comment.as_span().start() < position {
+            continue;
+        }
+        if let Some(offset) = input[position..comment.as_span().start()].find('}') {
+            return position + offset;
+        }
+        position = comment.as_span().end();
+    }
+    position + input[position..].find('}').unwrap_or_default()
+}
+
+fn find_target(pair: &Pair<Rule>) -> String {
+    pair.clone()
+        .into_inner()
+        .find(|p| p.as_rule() == Rule::attribute_target)
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default()
+}
+
+// Where the name of a subgraph ends, comments before it aren't part of the body
+fn name_end(pair: &Pair<Rule>) -> usize {
+    pair.clone()
+        .into_inner()
+        .find(|p| p.as_rule() == Rule::program_name)
+        .map(|p| p.as_span().end())
+        .unwrap_or_default()
+}
+
+// Canonical layout, see `format_body`
+pub fn format_script(script: &str) -> Result<String, pest::error::Error<Rule>> {
+    let program = FlowscriptParser::parse(Rule::program, script)?
+        .next()
+        .expect("Program always has a root pair");
+
+    let mut header = Vec::new();
+    let mut strict = false;
+    let mut started = false;
+    let mut name = None;
+    let mut body = Vec::new();
+    for pair in program.into_inner() {
+        match pair.as_rule() {
+            Rule::strict_keyword => strict = true,
+            Rule::digraph_keyword => started = true,
+            Rule::program_name => name = Some(format_id(&raw_id(pair))),
+            Rule::program_body => body.extend(pair.into_inner()),
+            // Comments before `digraph` stay above it, the ones at the start
+            // and end of the body are body comments like any other
+            Rule::COMMENT if !started => header.push(pair.as_str().trim().to_owned()),
+            Rule::COMMENT => body.push(pair),
+            _ => {}
+        }
+    }
+
+    let mut result = String::new();
+    for comment in header {
+        result.push_str(&comment);
+        result.push('\n');
+    }
+    if strict {
+        result.push_str("strict ");
+    }
+    result.push_str(&match name {
+        Some(name) => format!("digraph {} {{\n", name),
+        None => "digraph {\n".to_owned(),
+    });
+    result.push_str(&format_body(body, "  "));
+    result.push_str("}\n");
+    Ok(result)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Graphviz output for reviewing a script. Every node is listed, including the
+// ones only defined implicitly by a connection. Subgraphs become clusters with
+// their node names prefixed by the subgraph name.
+pub fn to_dot(program: &Program) -> String {
+    let mut result = "digraph flowscript {\n".to_owned();
+    write_graph(&mut result, program, program.main(), "", "  ");
+
+    let mut names: Vec<&String> = program.subgraphs.keys().collect();
+    names.sort();
+    for name in names {
+        result.push_str(&format!(
+            "  subgraph \"cluster_{}\" {{\n    label=\"{}\";\n",
+            escape(name),
+            escape(name)
+        ));
+        let defs = program.graph(program.subgraphs[name]);
+        write_graph(&mut result, program, defs, &format!("{}.", name), "    ");
+        result.push_str("  }\n");
+    }
+    result.push_str("}\n");
+    result
+}
+
+// The Graphviz shape for a node and what it does, when there is more to say
+// than its name
+pub fn describe_node(program: &Program, name: &str, def: &NodeDef) -> (&'static str, Option<String>) {
+    match def {
+        NodeDef::Input => ("oval", None),
+        NodeDef::Task(_) if program.subgraphs.contains_key(name) => ("tab", Some("call".to_owned())),
+        NodeDef::Task(_) => ("box", None),
+        NodeDef::IfStatement(condition) => ("rectangle", Some(format!("if {}", condition))),
+        NodeDef::Count => ("component", None),
+        NodeDef::Multi(_) => ("point", None),
+        NodeDef::Switch(field) => ("diamond", Some(format!("switch {}", field))),
+        NodeDef::Match(field) => ("Mdiamond", Some(format!("match {}", field))),
+        NodeDef::Setter(label) => ("cds", Some(format!("set {}", label))),
+        NodeDef::ForEach(def) => {
+            let detail = match def.condition {
+                Some(ref condition) => format!("filter {} where {}", def.over, condition),
+                None => format!("{:?} {} as {}", def.mode, def.over, def.item).to_lowercase(),
+            };
+            ("box3d", Some(detail))
+        }
+        NodeDef::Exec(def) => ("parallelogram", Some(format!("exec {}", def.command))),
+        NodeDef::Loop { condition, max } => {
+            ("hexagon", Some(format!("while {} (max {})", condition, max)))
+        }
+    }
+}
+
+// A plain text view of a script for reading in the terminal, each node with
+// where it goes next
+pub fn to_outline(program: &Program) -> String {
+    let mut result = String::new();
+    write_outline(&mut result, program, program.main(), "");
+
+    let mut names: Vec<&String> = program.subgraphs.keys().collect();
+    names.sort();
+    for name in names {
+        result.push_str(&format!("\nsubgraph {}\n", name));
+        write_outline(&mut result, program, program.graph(program.subgraphs[name]), "  ");
+    }
+    result
+}
+
+fn write_outline(result: &mut String, program: &Program, defs: &Defs, indent: &str) {
+    // Read from where the script starts
+    let mut names: Vec<&String> = defs.variables.keys().collect();
+    names.sort_by_key(|name| (*name != "input", defs.spans.get(*name).map(|s| s.start), name.to_owned()));
+
+    for name in names {
+        match describe_node(program, name, &defs.variables[name]) {
+            (_, Some(detail)) => result.push_str(&format!("{}{} ({})\n", indent, name, detail)),
+            (_, None) => result.push_str(&format!("{}{}\n", indent, name)),
+        }
+        for conn in defs.connections.iter().filter(|c| &c.from == name) {
+            let label = match &conn.c_type {
+                ConnectionType::Default => String::new(),
+                ConnectionType::IfResult(branch) => format!("{} ", branch),
+                ConnectionType::SwitchBranch(value) | ConnectionType::MatchBranch(value) => {
+                    format!("{} ", value)
+                }
+                ConnectionType::MultiOut => "branch ".to_owned(),
+                ConnectionType::Body => "body ".to_owned(),
+                ConnectionType::OnError => "on_error ".to_owned(),
+            };
+            result.push_str(&format!("{}  {}-> {}\n", indent, label, conn.to));
+        }
+    }
+}
+
+fn write_graph(result: &mut String, program: &Program, defs: &Defs, prefix: &str, indent: &str) {
+    let id = |name: &str| escape(&format!("{}{}", prefix, name));
+    let mut names: Vec<&String> = defs.variables.keys().collect();
+    names.sort_by_key(|name| (defs.spans.get(*name).map(|s| s.start), name.to_owned()));
+
+    let mut calls = Vec::new();
+    for name in names {
+        let (shape, detail) = describe_node(program, name, &defs.variables[name]);
+        if program.subgraphs.contains_key(name) && matches!(defs.variables[name], NodeDef::Task(_)) {
+            calls.push(name);
+        }
+        let label = match detail {
+            Some(detail) => format!("{}\\n{}", escape(name), escape(&detail)),
+            None => escape(name),
+        };
+        result.push_str(&format!(
+            "{}\"{}\" [shape=\"{}\", label=\"{}\"];\n",
+            indent,
+            id(name),
+            shape,
+            label
+        ));
+    }
+
+    for conn in &defs.connections {
+        let attributes = match &conn.c_type {
+            ConnectionType::Default => String::new(),
+            ConnectionType::IfResult(branch) => format!(" [label=\"{}\"]", branch),
+            ConnectionType::SwitchBranch(value) => format!(" [label=\"{}\"]", escape(value)),
+            ConnectionType::MatchBranch(value) => {
+                format!(" [label=\"{}\", style=\"dashed\"]", escape(value))
+            }
+            ConnectionType::MultiOut => " [style=\"dashed\"]".to_owned(),
+            ConnectionType::Body => " [label=\"body\"]".to_owned(),
+            ConnectionType::OnError => {
+                " [label=\"on_error\", style=\"dashed\", color=\"red\"]".to_owned()
+            }
+        };
+        result.push_str(&format!(
+            "{}\"{}\" -> \"{}\"{};\n",
+            indent,
+            id(&conn.from),
+            id(&conn.to),
+            attributes
+        ));
+    }
+
+    // Point each call at the subgraph it runs
+    for name in calls {
+        result.push_str(&format!(
+            "{}\"{}\" -> \"{}.input\" [style=\"dotted\"];\n",
+            indent,
+            id(name),
+            escape(name)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_at_the_start_of_the_body_stay_in_it() {
+        let script = "// Compiles\ndigraph {\n  // First the files\n  input -> Compile;\n  # Then the report\n  Compile -> Output;\n}\n";
+        let formatted = format_script(script).unwrap();
+        assert_eq!(
+            formatted,
+            "// Compiles\ndigraph {\n  // First the files\n  input -> Compile;\n  # Then the report\n  Compile -> Output;\n}\n"
+        );
+        assert_eq!(format_script(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn comments_in_an_empty_body_stay_in_it() {
+        let script = "digraph {\n  // Nothing yet\n}\n";
+        assert_eq!(format_script(script).unwrap(), script);
+    }
+}
@@ -0,0 +1,855 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, PrepareRenameRequest, Rename, Request as _},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PrepareRenameResponse, PublishDiagnosticsParams, RenameOptions, RenameParams,
+    ServerCapabilities, TextDocumentPositionParams, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Url, WorkspaceEdit,
+};
+use pest::{error::InputLocation, iterators::Pair, Parser};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::system::types::JobType;
+
+use super::{
+    find_errors, format, modules,
+    parser::{self, Defs, FlowscriptParser, NodeDef, Rule, EDGE_KINDS, NODE_KINDS},
+    typecheck,
+};
+
+// Serves editors over stdin and stdout until they shut the server down.
+// Nothing else may print while it runs, stdout belongs to the protocol.
+pub fn serve() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    run(&connection)?;
+    // The IO threads only finish once the connection is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+fn run(connection: &Connection) -> Result<()> {
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["\"".to_owned(), "=".to_owned()]),
+            ..Default::default()
+        }),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Right(RenameOptions {
+            prepare_provider: Some(true),
+            work_done_progress_options: Default::default(),
+        })),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut server = Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                connection.sender.send(Message::Response(server.handle(request)))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(diagnostics) = server.notify(notification)? {
+                    let notification = Notification::new(PublishDiagnostics::METHOD.to_owned(), diagnostics);
+                    connection.sender.send(Message::Notification(notification))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// Open documents, as the editor has them rather than as they are on disk
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Url, String>,
+}
+
+fn params<P: DeserializeOwned>(params: Value) -> Result<P> {
+    Ok(serde_json::from_value(params)?)
+}
+
+impl Server {
+    fn handle(&self, request: Request) -> Response {
+        let result = match request.method.as_str() {
+            Completion::METHOD => params(request.params).and_then(|p| self.completion(p)),
+            HoverRequest::METHOD => params(request.params).and_then(|p| self.hover(p)),
+            GotoDefinition::METHOD => params(request.params).and_then(|p| self.definition(p)),
+            PrepareRenameRequest::METHOD => params(request.params).and_then(|p| self.prepare_rename(p)),
+            Rename::METHOD => params(request.params).and_then(|p| self.rename(p)),
+            method => {
+                return Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unknown method {}", method),
+                )
+            }
+        };
+        match result {
+            Ok(value) => Response::new_ok(request.id, value),
+            Err(e) => Response::new_err(request.id, ErrorCode::RequestFailed as i32, e.to_string()),
+        }
+    }
+
+    // Keeps documents in step with the editor, giving back the diagnostics to
+    // publish when one changed
+    fn notify(&mut self, notification: Notification) -> Result<Option<PublishDiagnosticsParams>> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let opened: DidOpenTextDocumentParams = params(notification.params)?;
+                let uri = opened.text_document.uri;
+                self.documents.insert(uri.clone(), opened.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let changed: DidChangeTextDocumentParams = params(notification.params)?;
+                let uri = changed.text_document.uri;
+                // Changes always hold the whole text, see the sync kind above
+                if let Some(change) = changed.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let closed: DidCloseTextDocumentParams = params(notification.params)?;
+                self.documents.remove(&closed.text_document.uri);
+                return Ok(Some(PublishDiagnosticsParams::new(closed.text_document.uri, Vec::new(), None)));
+            }
+            _ => return Ok(None),
+        };
+
+        let text = &self.documents[&uri];
+        let diagnostics = diagnostics(text, file_path(&uri).as_deref());
+        Ok(Some(PublishDiagnosticsParams::new(uri, diagnostics, None)))
+    }
+
+    fn document<'a>(&'a self, position: &'a TextDocumentPositionParams) -> Result<(&'a Url, &'a str, usize)> {
+        let uri = &position.text_document.uri;
+        let text = self
+            .documents
+            .get(uri)
+            .ok_or(anyhow!("{} isn't open", uri))?;
+        Ok((uri, text, offset(text, position.position)))
+    }
+
+    fn completion(&self, completion: CompletionParams) -> Result<Value> {
+        let (_, text, at) = self.document(&completion.text_document_position)?;
+        Ok(serde_json::to_value(complete(text, at))?)
+    }
+
+    fn hover(&self, hover: HoverParams) -> Result<Value> {
+        let (uri, text, at) = self.document(&hover.text_document_position_params)?;
+        let Some(name) = name_at(text, at) else {
+            return Ok(Value::Null);
+        };
+        let Some(markdown) = describe(text, file_path(uri).as_deref(), &name) else {
+            return Ok(Value::Null);
+        };
+        Ok(serde_json::to_value(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: markdown,
+            }),
+            range: Some(range(text, &name.span)),
+        })?)
+    }
+
+    fn definition(&self, definition: GotoDefinitionParams) -> Result<Value> {
+        let (uri, text, at) = self.document(&definition.text_document_position_params)?;
+        let location = name_at(text, at).and_then(|name| define(uri, text, &name));
+        Ok(serde_json::to_value(location)?)
+    }
+
+    fn prepare_rename(&self, position: TextDocumentPositionParams) -> Result<Value> {
+        let (uri, text, at) = self.document(&position)?;
+        let (name, _) = rename_targets(text, file_path(uri).as_deref(), at)?;
+        Ok(serde_json::to_value(PrepareRenameResponse::Range(range(text, &name.span)))?)
+    }
+
+    fn rename(&self, rename: RenameParams) -> Result<Value> {
+        let (uri, text, at) = self.document(&rename.text_document_position)?;
+        let (name, spans) = rename_targets(text, file_path(uri).as_deref(), at)?;
+        let new_name = rename.new_name.trim();
+        check_new_name(text, &name, new_name)?;
+
+        let edits = spans
+            .iter()
+            .map(|span| TextEdit::new(range(text, span), quote_id(new_name)))
+            .collect();
+        let edit = WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)]));
+        Ok(serde_json::to_value(edit)?)
+    }
+}
+
+fn file_path(uri: &Url) -> Option<PathBuf> {
+    uri.to_file_path().ok()
+}
+
+// Positions ----------------------------
+
+// LSP counts columns in UTF-16 code units
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position::new(line as u32, before[start..].encode_utf16().count() as u32)
+}
+
+fn offset(text: &str, position: Position) -> usize {
+    let mut start = 0;
+    for _ in 0..position.line {
+        match text[start..].find('\n') {
+            Some(end) => start += end + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: &Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(position(text, span.start), position(text, span.end))
+}
+
+// Diagnostics ----------------------------
+
+// Every error validation and type checking finds. Errors in imported files go
+// at the top since they have no place in this one.
+fn diagnostics(text: &str, path: Option<&Path>) -> Vec<Diagnostic> {
+    let Err(errors) = find_errors(text, path) else {
+        return Vec::new();
+    };
+    let own = path.map(|p| p.canonicalize().unwrap_or(p.to_owned()).to_string_lossy().to_string());
+
+    errors
+        .iter()
+        .map(|e| {
+            let message = e.variant.message().to_string();
+            let (span, message) = match e.path() {
+                Some(other) if Some(other) != own.as_deref() => (0..0, format!("{}: {}", other, message)),
+                _ => match e.location {
+                    InputLocation::Pos(at) => (at..at, message),
+                    InputLocation::Span((start, end)) => (start..end, message),
+                },
+            };
+            Diagnostic {
+                range: range(text, &span),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("flowscript".to_owned()),
+                message,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+// Names ----------------------------
+
+// A node name as written in the script
+struct Name {
+    span: Range<usize>,
+    name: String,
+    scope: Option<String>, // The subgraph it's in
+    subgraph: bool,        // The name of a subgraph definition
+}
+
+// Every name in a script that parses, subgraph names included
+fn names(text: &str) -> Option<Vec<Name>> {
+    let program = FlowscriptParser::parse(Rule::program, text).ok()?.next()?;
+    let mut names = Vec::new();
+    collect_names(program, None, &mut names);
+    Some(names)
+}
+
+fn collect_names(pair: Pair<Rule>, scope: Option<&str>, names: &mut Vec<Name>) {
+    for inner in pair.into_inner() {
+        let span = inner.as_span().start()..inner.as_span().end();
+        match inner.as_rule() {
+            Rule::variable => names.push(Name {
+                span,
+                name: parser::id_value(inner),
+                scope: scope.map(|s| s.to_owned()),
+                subgraph: false,
+            }),
+            Rule::subgraph_def => {
+                let Some(id) = inner.clone().into_inner().find(|p| p.as_rule() == Rule::program_name) else {
+                    continue;
+                };
+                let name = parser::id_value(id.clone());
+                names.push(Name {
+                    span: id.as_span().start()..id.as_span().end(),
+                    name: name.clone(),
+                    scope: None,
+                    subgraph: true,
+                });
+                collect_names(inner, Some(&name), names);
+            }
+            // Attribute values can look like names but never are
+            Rule::attributes | Rule::graph_attribute | Rule::import_def => {}
+            _ => collect_names(inner, scope, names),
+        }
+    }
+}
+
+fn name_at(text: &str, at: usize) -> Option<Name> {
+    names(text)?
+        .into_iter()
+        .find(|n| n.span.start <= at && at <= n.span.end)
+}
+
+// The graph a name is in
+fn scope_defs<'a>(defs: &'a Defs, scope: &Option<String>) -> Option<&'a Defs> {
+    match scope {
+        None => Some(defs),
+        Some(scope) => defs.subgraphs.iter().find(|d| d.name.as_ref() == Some(scope)),
+    }
+}
+
+// Names that need quotes in DOT get them
+fn quote_id(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    match plain {
+        true => name.to_owned(),
+        false => format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+// Completion ----------------------------
+
+fn complete(text: &str, at: usize) -> Vec<CompletionItem> {
+    let line = &text[text[..at].rfind('\n').map_or(0, |i| i + 1)..at];
+
+    // Inside `kind="...`, edges and nodes have different kinds
+    if let Some(value) = kind_value(line) {
+        let (kinds, help): (&[&str], fn(&str) -> &'static str) = match line.contains("->") {
+            true => (EDGE_KINDS, parser::edge_kind_help),
+            false => (NODE_KINDS, parser::kind_help),
+        };
+        return kinds
+            .iter()
+            .filter(|kind| kind.starts_with(value))
+            .map(|kind| CompletionItem {
+                label: kind.to_string(),
+                kind: Some(CompletionItemKind::ENUM_MEMBER),
+                detail: Some(help(kind).to_owned()),
+                ..Default::default()
+            })
+            .collect();
+    }
+
+    let mut items: Vec<CompletionItem> = JobType::ALL
+        .iter()
+        .map(|job| CompletionItem {
+            label: job.name(),
+            kind: Some(CompletionItemKind::FUNCTION),
+            detail: Some(job.description().to_owned()),
+            ..Default::default()
+        })
+        .collect();
+
+    let mut seen: Vec<String> = items.iter().map(|item| item.label.clone()).collect();
+    for name in names(text).unwrap_or_default() {
+        if seen.contains(&name.name) {
+            continue;
+        }
+        seen.push(name.name.clone());
+        items.push(CompletionItem {
+            label: quote_id(&name.name),
+            kind: Some(match name.subgraph {
+                true => CompletionItemKind::MODULE,
+                false => CompletionItemKind::VARIABLE,
+            }),
+            ..Default::default()
+        });
+    }
+    items
+}
+
+// The part of a kind already typed when the cursor is in `kind="`
+fn kind_value(line: &str) -> Option<&str> {
+    let after = &line[line.rfind("kind")? + "kind".len()..];
+    let value = after.trim_start().strip_prefix('=')?.trim_start();
+    let value = value.strip_prefix('"').unwrap_or(value);
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        .then_some(value)
+}
+
+// Hover ----------------------------
+
+// What a node does, what it reads and gives back when it's a job, and the
+// payload it gets once the script checks out
+fn describe(text: &str, path: Option<&Path>, name: &Name) -> Option<String> {
+    let defs = parser::extract_definitions(text).ok()?;
+    if name.subgraph {
+        let graph = scope_defs(&defs, &Some(name.name.clone()))?;
+        let mut lines = vec![format!("**{}** subgraph", name.name)];
+        for key in ["inputs", "outputs"] {
+            if let Some(fields) = graph.attributes.get(key) {
+                lines.push(format!("{} `{}`", key, fields));
+            }
+        }
+        return Some(lines.join("\n\n"));
+    }
+
+    let scope = scope_defs(&defs, &name.scope)?;
+    let def = scope.variables.get(&name.name)?;
+    let program = modules::resolve(text, path).ok();
+    let calls = matches!(def, NodeDef::Task(_))
+        && program.as_ref().is_some_and(|p| p.subgraphs.contains_key(&name.name));
+
+    let mut lines = Vec::new();
+    match (def, JobType::from_name(&name.name)) {
+        _ if calls => lines.push(format!("**{}** calls the subgraph {}", name.name, name.name)),
+        (NodeDef::Task(_), Some(job)) => {
+            lines.push(format!("**{}** `task`", name.name));
+            lines.push(job.description().to_owned());
+            lines.push(format!(
+                "reads `{}`  \ngives back `{}`",
+                typecheck::signature(&typecheck::from_root(&job.input_schema())),
+                typecheck::signature(&typecheck::from_root(&job.output_schema()))
+            ));
+        }
+        (NodeDef::Input, _) => lines.push(format!("**{}** where the script starts", name.name)),
+        _ => {
+            lines.push(format!("**{}** `{}`", name.name, def.kind()));
+            if let Some(program) = program.as_ref() {
+                if let (_, Some(detail)) = format::describe_node(program, &name.name, def) {
+                    lines.push(format!("`{}`", detail));
+                }
+            }
+            lines.push(parser::kind_help(def.kind()).to_owned());
+        }
+    }
+
+    // Types are only known for scripts without errors
+    if let Ok(program) = find_errors(text, path) {
+        let payloads = typecheck::payloads(&program);
+        if let Some(payload) = payloads.get(&name.scope).and_then(|p| p.get(&name.name)) {
+            lines.push(format!("gets `{}`", typecheck::signature(payload)));
+        }
+    }
+    Some(lines.join("\n\n"))
+}
+
+// Definition ----------------------------
+
+// Calls go to the subgraph they run, which might be in an imported file, other
+// names go to where the node was defined or first used
+fn define(uri: &Url, text: &str, name: &Name) -> Option<Location> {
+    let defs = parser::extract_definitions(text).ok()?;
+    let scope = scope_defs(&defs, &name.scope)?;
+
+    if !name.subgraph && matches!(scope.variables.get(&name.name), Some(NodeDef::Task(_))) {
+        let program = modules::resolve(text, file_path(uri).as_deref()).ok();
+        if let Some(graph) = program.as_ref().and_then(|p| p.subgraphs.get(&name.name).map(|g| (p, *g))) {
+            let (program, graph) = graph;
+            let module = &program.modules[graph.module];
+            let target = match (graph.module, &module.path) {
+                (0, _) | (_, None) => uri.clone(),
+                (_, Some(path)) => Url::from_file_path(path).ok()?,
+            };
+            return Some(Location::new(target, range(&module.script, &program.graph(graph).span)));
+        }
+    }
+
+    let span = match name.subgraph {
+        true => name.span.clone(),
+        false => scope.spans.get(&name.name)?.clone(),
+    };
+    Some(Location::new(uri.clone(), range(text, &span)))
+}
+
+// Rename ----------------------------
+
+// Every place the name under the cursor has to change. Jobs and input are
+// picked by their names so they can't be renamed.
+fn rename_targets(text: &str, path: Option<&Path>, at: usize) -> Result<(Name, Vec<Range<usize>>)> {
+    let names = names(text).ok_or(anyhow!("The script has to parse before anything can be renamed"))?;
+    let Some(index) = names.iter().position(|n| n.span.start <= at && at <= n.span.end) else {
+        return Err(anyhow!("There is no node name here"));
+    };
+    let name = &names[index];
+    if name.name == "input" {
+        return Err(anyhow!("input is where the script starts and can't be renamed"));
+    }
+    if JobType::from_name(&name.name).is_some() {
+        return Err(anyhow!(
+            "{} runs the {} job, renaming it would change what it does",
+            name.name,
+            name.name
+        ));
+    }
+
+    // Subgraphs are called from anywhere, other names belong to their graph
+    let subgraph = names.iter().any(|n| n.subgraph && n.name == name.name);
+    let imported = modules::resolve(text, path).is_ok_and(|p| p.subgraphs.contains_key(&name.name));
+    if !subgraph && imported {
+        return Err(anyhow!("{} is a subgraph from another file, rename it there", name.name));
+    }
+    let spans = names
+        .iter()
+        .filter(|n| n.name == name.name && (subgraph || (n.scope == name.scope && !n.subgraph)))
+        .map(|n| n.span.clone())
+        .collect();
+    let mut names = names;
+    Ok((names.swap_remove(index), spans))
+}
+
+fn check_new_name(text: &str, name: &Name, new_name: &str) -> Result<()> {
+    if new_name.is_empty() {
+        return Err(anyhow!("Nodes need a name"));
+    }
+    if new_name == "input" || JobType::from_name(new_name).is_some() {
+        return Err(anyhow!("{} would change what the node does", new_name));
+    }
+    let taken = names(text)
+        .unwrap_or_default()
+        .iter()
+        .any(|n| n.name == new_name && (n.scope == name.scope || n.subgraph));
+    if taken {
+        return Err(anyhow!("{} is already a node", new_name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use lsp_server::RequestId;
+    use lsp_types::{
+        notification::{Exit, Initialized},
+        request::{Initialize, Shutdown},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    const SCRIPT: &str = "digraph {
+  import \"lib.fs\";
+  input -> Compile -> tidy -> report;
+  subgraph tidy {
+    input -> clean;
+    clean [kind=\"set\", label=\"remove fix_warnings\"];
+  }
+}
+";
+
+    const LIBRARY: &str = "digraph report {\n  input -> Output;\n}\n";
+
+    // The script next to the file it imports
+    fn project() -> (tempfile::TempDir, Url) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.fs"), LIBRARY).unwrap();
+        fs::write(dir.path().join("main.fs"), SCRIPT).unwrap();
+        let uri = Url::from_file_path(dir.path().join("main.fs")).unwrap();
+        (dir, uri)
+    }
+
+    fn at(text: &str, needle: &str) -> usize {
+        text.find(needle).unwrap()
+    }
+
+    fn name(text: &str, needle: &str) -> Name {
+        name_at(text, at(text, needle)).unwrap()
+    }
+
+    #[test]
+    fn columns_are_counted_in_utf16() {
+        let text = "a = \"é😀\";\nb";
+        let after_emoji = at(text, "\";");
+        assert_eq!(position(text, after_emoji), Position::new(0, 8));
+        assert_eq!(offset(text, Position::new(0, 8)), after_emoji);
+        assert_eq!(position(text, text.len()), Position::new(1, 1));
+        assert_eq!(offset(text, Position::new(1, 1)), text.len());
+
+        // Past the end of a line stops at the line break, past the end of the text at its end
+        assert_eq!(offset(text, Position::new(0, 99)), at(text, "\n"));
+        assert_eq!(offset(text, Position::new(9, 0)), text.len());
+    }
+
+    #[test]
+    fn kinds_are_completed_for_nodes_and_edges() {
+        let node = "digraph {\n  a [kind=\"lo";
+        let labels: Vec<String> = complete(node, node.len()).into_iter().map(|i| i.label).collect();
+        assert_eq!(labels, ["loop"]);
+
+        let edge = "digraph {\n  a -> b [kind=\"";
+        let labels: Vec<String> = complete(edge, edge.len()).into_iter().map(|i| i.label).collect();
+        assert_eq!(labels, EDGE_KINDS);
+    }
+
+    #[test]
+    fn jobs_and_nodes_are_completed_once() {
+        let labels: Vec<String> = complete(SCRIPT, at(SCRIPT, "input ->")).into_iter().map(|i| i.label).collect();
+        for job in JobType::ALL {
+            assert_eq!(labels.iter().filter(|l| **l == job.name()).count(), 1);
+        }
+        for name in ["input", "tidy", "report", "clean"] {
+            assert_eq!(labels.iter().filter(|l| *l == name).count(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn hover_describes_jobs_setters_and_subgraphs() {
+        let (dir, _) = project();
+        let path = dir.path().join("main.fs");
+
+        let compile = describe(SCRIPT, Some(&path), &name(SCRIPT, "Compile")).unwrap();
+        assert!(compile.starts_with("**Compile** `task`"));
+        assert!(compile.contains("reads `{files: [string], fix_warnings: bool}`"));
+        assert!(compile.contains("gets `{files: [string], fix_warnings: bool}`"));
+
+        let clean = describe(SCRIPT, Some(&path), &name(SCRIPT, "clean [")).unwrap();
+        assert!(clean.starts_with("**clean** `set`"));
+        assert!(clean.contains("`set remove fix_warnings`"));
+
+        let call = describe(SCRIPT, Some(&path), &name(SCRIPT, "tidy ->")).unwrap();
+        assert!(call.starts_with("**tidy** calls the subgraph tidy"));
+        let subgraph = describe(SCRIPT, Some(&path), &name(SCRIPT, "tidy {")).unwrap();
+        assert_eq!(subgraph, "**tidy** subgraph");
+    }
+
+    #[test]
+    fn definitions_follow_calls_into_subgraphs_and_imports() {
+        let (dir, uri) = project();
+
+        let node = define(&uri, SCRIPT, &name(SCRIPT, "clean [")).unwrap();
+        assert_eq!(node.uri, uri);
+        assert_eq!(node.range.start, position(SCRIPT, at(SCRIPT, "clean [")));
+
+        let local = define(&uri, SCRIPT, &name(SCRIPT, "tidy ->")).unwrap();
+        assert_eq!(local.uri, uri);
+        assert_eq!(local.range.start, position(SCRIPT, at(SCRIPT, "subgraph tidy")));
+
+        let imported = define(&uri, SCRIPT, &name(SCRIPT, "report")).unwrap();
+        let library = dir.path().join("lib.fs").canonicalize().unwrap();
+        assert_eq!(imported.uri.to_file_path().unwrap().canonicalize().unwrap(), library);
+        assert_eq!(imported.range.start, Position::new(0, 0));
+    }
+
+    #[test]
+    fn renames_change_every_use_in_the_graph() {
+        let (dir, _) = project();
+        let path = dir.path().join("main.fs");
+
+        let (_, spans) = rename_targets(SCRIPT, Some(&path), at(SCRIPT, "clean [")).unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| &SCRIPT[span.clone()] == "clean"));
+
+        // The call and the definition
+        let (tidy, spans) = rename_targets(SCRIPT, Some(&path), at(SCRIPT, "tidy ->")).unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(check_new_name(SCRIPT, &tidy, "tidy_up").is_ok());
+    }
+
+    #[test]
+    fn inputs_jobs_and_imports_are_not_renamed() {
+        let (dir, _) = project();
+        let path = dir.path().join("main.fs");
+        let refused = |needle: &str| {
+            rename_targets(SCRIPT, Some(&path), at(SCRIPT, needle))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(refused("input ->"), "input is where the script starts and can't be renamed");
+        assert_eq!(refused("Compile"), "Compile runs the Compile job, renaming it would change what it does");
+        assert_eq!(refused("report"), "report is a subgraph from another file, rename it there");
+    }
+
+    #[test]
+    fn new_names_cant_change_what_a_node_does_or_clash() {
+        let clean = name(SCRIPT, "clean [");
+        let refused = |new_name: &str| check_new_name(SCRIPT, &clean, new_name).err().unwrap().to_string();
+        assert_eq!(refused(""), "Nodes need a name");
+        assert_eq!(refused("Output"), "Output would change what the node does");
+        assert_eq!(refused("input"), "input would change what the node does");
+        // Subgraph names are taken everywhere
+        assert_eq!(refused("tidy"), "tidy is already a node");
+        assert!(check_new_name(SCRIPT, &clean, "strip").is_ok());
+    }
+
+    // Talks to `run` the way an editor talks to `serve`
+    struct Client {
+        connection: Connection,
+        server: Option<thread::JoinHandle<Result<()>>>,
+        next_id: i32,
+    }
+
+    impl Client {
+        fn start() -> Client {
+            let (server, connection) = Connection::memory();
+            let server = thread::spawn(move || run(&server));
+            let mut client = Client {
+                connection,
+                server: Some(server),
+                next_id: 0,
+            };
+            client.request(Initialize::METHOD, json!({ "capabilities": {} }));
+            client.notify(Initialized::METHOD, json!({}));
+            client
+        }
+
+        fn notify(&self, method: &str, params: Value) {
+            let notification = Notification::new(method.to_owned(), params);
+            self.connection.sender.send(Message::Notification(notification)).unwrap();
+        }
+
+        fn request(&mut self, method: &str, params: Value) -> Response {
+            self.next_id += 1;
+            let id = RequestId::from(self.next_id);
+            let request = Request::new(id.clone(), method.to_owned(), params);
+            self.connection.sender.send(Message::Request(request)).unwrap();
+            loop {
+                match self.connection.receiver.recv().unwrap() {
+                    Message::Response(response) if response.id == id => return response,
+                    _ => {}
+                }
+            }
+        }
+
+        fn result(&mut self, method: &str, params: Value) -> Value {
+            let response = self.request(method, params);
+            assert!(response.error.is_none(), "{:?}", response.error);
+            response.result.unwrap_or_default()
+        }
+
+        fn diagnostics(&self) -> PublishDiagnosticsParams {
+            loop {
+                if let Message::Notification(notification) = self.connection.receiver.recv().unwrap() {
+                    if notification.method == PublishDiagnostics::METHOD {
+                        return serde_json::from_value(notification.params).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    impl Drop for Client {
+        fn drop(&mut self) {
+            self.request(Shutdown::METHOD, Value::Null);
+            self.notify(Exit::METHOD, Value::Null);
+            if let Some(server) = self.server.take() {
+                server.join().unwrap().unwrap();
+            }
+        }
+    }
+
+    fn open(client: &Client, uri: &Url, text: &str) {
+        client.notify(
+            DidOpenTextDocument::METHOD,
+            json!({ "textDocument": { "uri": uri, "languageId": "flowscript", "version": 1, "text": text } }),
+        );
+    }
+
+    fn spot(uri: &Url, text: &str, needle: &str) -> Value {
+        json!({ "textDocument": { "uri": uri }, "position": position(text, at(text, needle)) })
+    }
+
+    #[test]
+    fn the_server_publishes_diagnostics_as_documents_change() {
+        let (_dir, uri) = project();
+        let mut client = Client::start();
+
+        let broken = "digraph {\n  input -> Output;\n}\n";
+        open(&client, &uri, broken);
+        let published = client.diagnostics();
+        assert_eq!(published.uri, uri);
+        assert_eq!(published.diagnostics.len(), 1);
+        assert_eq!(published.diagnostics[0].message, "Output needs .errors which isn't set coming from input");
+        assert_eq!(published.diagnostics[0].range.start, Position::new(1, 2));
+
+        client.notify(
+            DidChangeTextDocument::METHOD,
+            json!({ "textDocument": { "uri": uri, "version": 2 }, "contentChanges": [{ "text": SCRIPT }] }),
+        );
+        assert!(client.diagnostics().diagnostics.is_empty());
+
+        client.notify(DidCloseTextDocument::METHOD, json!({ "textDocument": { "uri": uri } }));
+        assert!(client.diagnostics().diagnostics.is_empty());
+        let closed = client.request(HoverRequest::METHOD, spot(&uri, SCRIPT, "Compile"));
+        assert!(closed.error.is_some());
+    }
+
+    #[test]
+    fn the_server_answers_completion_hover_definition_and_rename() {
+        let (_dir, uri) = project();
+        let mut client = Client::start();
+        open(&client, &uri, SCRIPT);
+        client.diagnostics();
+
+        let items = client.result(Completion::METHOD, spot(&uri, SCRIPT, "input ->"));
+        let items: Vec<CompletionItem> = serde_json::from_value(items).unwrap();
+        assert!(items.iter().any(|item| item.label == "FixCode"));
+
+        let hover = client.result(HoverRequest::METHOD, spot(&uri, SCRIPT, "Compile"));
+        let hover: Hover = serde_json::from_value(hover).unwrap();
+        let HoverContents::Markup(markup) = hover.contents else {
+            panic!("Hovers are markdown");
+        };
+        assert!(markup.value.starts_with("**Compile** `task`"));
+
+        let location: Location =
+            serde_json::from_value(client.result(GotoDefinition::METHOD, spot(&uri, SCRIPT, "tidy ->"))).unwrap();
+        assert_eq!(location.range.start, position(SCRIPT, at(SCRIPT, "subgraph tidy")));
+
+        let range = client.result(PrepareRenameRequest::METHOD, spot(&uri, SCRIPT, "clean;"));
+        let expected = range_of(SCRIPT, "clean;", "clean".len());
+        assert_eq!(serde_json::from_value::<lsp_types::Range>(range).unwrap(), expected);
+
+        let mut params = spot(&uri, SCRIPT, "clean;");
+        params["newName"] = json!("strip warnings");
+        let edit: WorkspaceEdit = serde_json::from_value(client.result(Rename::METHOD, params)).unwrap();
+        let edits = &edit.changes.unwrap()[&uri];
+        assert_eq!(edits.len(), 2);
+        assert!(edits.iter().all(|edit| edit.new_text == "\"strip warnings\""));
+
+        let mut params = spot(&uri, SCRIPT, "report");
+        params["newName"] = json!("summary");
+        let refused = client.request(Rename::METHOD, params);
+        assert_eq!(
+            refused.error.unwrap().message,
+            "report is a subgraph from another file, rename it there"
+        );
+
+        let unknown = client.request("flowscript/unknown", Value::Null);
+        assert_eq!(unknown.error.unwrap().code, ErrorCode::MethodNotFound as i32);
+    }
+
+    fn range_of(text: &str, needle: &str, len: usize) -> lsp_types::Range {
+        let start = at(text, needle);
+        range(text, &(start..start + len))
+    }
+}
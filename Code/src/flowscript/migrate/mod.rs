@@ -0,0 +1,209 @@
+use pest::{iterators::Pair, Parser};
+
+use super::parser::{
+    extract_definitions, get_attributes, legacy_kind, ConnectionType, Defs, FlowscriptParser, Rule,
+};
+
+// Text to put in at a position of the original script
+struct Edit {
+    at: usize,
+    text: String,
+}
+
+#[derive(Default)]
+pub struct Migration {
+    pub script: String,
+    pub nodes: usize,
+    pub edges: usize,
+}
+
+// Adds `kind="..."` wherever an old script picked a node type with a shape.
+// Shapes are left alone so the graph still draws the same. Once a node has a
+// kind its edge styles stop meaning anything, so dashed multi outputs get
+// `kind="branch"`.
+pub fn migrate_script(script: &str) -> Result<Migration, pest::error::Error<Rule>> {
+    let defs = extract_definitions(script)?;
+    let program = FlowscriptParser::parse(Rule::program, script)?
+        .next()
+        .expect("Program always has a root pair");
+
+    let mut migration = Migration::default();
+    let mut edits = Vec::new();
+    for pair in program.into_inner() {
+        if pair.as_rule() == Rule::program_body {
+            migrate_body(pair, &defs, &mut edits, &mut migration);
+        }
+    }
+
+    let mut result = script.to_owned();
+    edits.sort_by_key(|e| e.at);
+    for edit in edits.into_iter().rev() {
+        result.insert_str(edit.at, &edit.text);
+    }
+    migration.script = result;
+    Ok(migration)
+}
+
+fn migrate_body(body: Pair<Rule>, defs: &Defs, edits: &mut Vec<Edit>, migration: &mut Migration) {
+    let mut subgraphs = defs.subgraphs.iter();
+    for statement in body.into_inner() {
+        match statement.as_rule() {
+            // `node [shape=cds]` defaults get the kind too, the nodes they
+            // create pick it up the same way they picked up the shape
+            Rule::variable_def | Rule::attribute_statement => {
+                let target = statement
+                    .clone()
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::attribute_target)
+                    .map(|p| p.as_str().to_lowercase());
+                if target.as_ref().is_some_and(|t| t != "node") {
+                    continue;
+                }
+                let attrs = get_attributes(statement.clone());
+                let kind = attrs.get("shape").and_then(|s| legacy_kind(s));
+                if let (Some(kind), false) = (kind, attrs.contains_key("kind")) {
+                    edits.push(add_attribute(&statement, &format!("kind=\"{}\"", kind)));
+                    migration.nodes += 1;
+                }
+            }
+            Rule::connection_def => {
+                let start = statement.as_span().start();
+                let multi_out = defs
+                    .connections
+                    .iter()
+                    .any(|c| c.span.start == start && c.c_type == ConnectionType::MultiOut);
+                if multi_out && !get_attributes(statement.clone()).contains_key("kind") {
+                    edits.push(add_attribute(&statement, "kind=\"branch\""));
+                    migration.edges += 1;
+                }
+            }
+            Rule::subgraph_def => {
+                let sub = subgraphs.next().expect("Every subgraph was extracted");
+                let body = statement
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::subgraph_body)
+                    .expect("Subgraph has a body");
+                migrate_body(body, sub, edits, migration);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Goes at the start of the first attribute list, or in a new list after the
+// last name when there is none
+fn add_attribute(statement: &Pair<Rule>, attribute: &str) -> Edit {
+    let inner: Vec<Pair<Rule>> = statement.clone().into_inner().collect();
+    if let Some(list) = inner.iter().find(|p| p.as_rule() == Rule::attributes) {
+        let empty = !list.clone().into_inner().any(|p| p.as_rule() == Rule::attribute);
+        return Edit {
+            at: list.as_span().start() + 1,
+            text: match empty {
+                true => attribute.to_owned(),
+                false => format!("{}, ", attribute),
+            },
+        };
+    }
+
+    let end = inner
+        .iter()
+        .filter(|p| matches!(p.as_rule(), Rule::variable | Rule::node_group))
+        .map(|p| p.as_span().end())
+        .max()
+        .unwrap_or(statement.as_span().end());
+    Edit {
+        at: end,
+        text: format!(" [{}]", attribute),
+    }
+}
+
+// The migrated script has to build the same graph as the old one
+pub fn same_graph(before: &Defs, after: &Defs) -> bool {
+    let describe = |defs: &Defs| {
+        let mut nodes: Vec<String> = defs
+            .variables
+            .iter()
+            .map(|(name, def)| format!("{} {:?}", name, def))
+            .collect();
+        nodes.sort();
+        let mut conns: Vec<String> = defs
+            .connections
+            .iter()
+            .map(|c| format!("{} -> {} {:?}", c.from, c.to, c.c_type))
+            .collect();
+        conns.sort();
+        (nodes, conns)
+    };
+
+    describe(before) == describe(after)
+        && before.subgraphs.len() == after.subgraphs.len()
+        && before
+            .subgraphs
+            .iter()
+            .zip(&after.subgraphs)
+            .all(|(b, a)| same_graph(b, a))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"digraph {
+  input -> check;
+  check [shape="rectangle", label=".fix_warnings"];
+  check -> both [label="true"];
+  check -> Compile [label="false"];
+  both [shape=point];
+  both -> Compile [style="dashed"];
+  both -> mark [style=dashed];
+  both -> Output;
+  mark [shape=cds, label="marked = true"];
+  subgraph tidy {
+    node [shape=cds];
+    input -> clean;
+    clean [label="remove fix_warnings"];
+  }
+}"#;
+
+    const MIGRATED: &str = r#"digraph {
+  input -> check;
+  check [kind="if", shape="rectangle", label=".fix_warnings"];
+  check -> both [label="true"];
+  check -> Compile [label="false"];
+  both [kind="multi", shape=point];
+  both -> Compile [kind="branch", style="dashed"];
+  both -> mark [kind="branch", style=dashed];
+  both -> Output;
+  mark [kind="set", shape=cds, label="marked = true"];
+  subgraph tidy {
+    node [kind="set", shape=cds];
+    input -> clean;
+    clean [label="remove fix_warnings"];
+  }
+}"#;
+
+    #[test]
+    fn shapes_and_dashed_edges_become_kinds() {
+        let migration = migrate_script(OLD).unwrap();
+        assert_eq!(migration.script, MIGRATED);
+        assert_eq!((migration.nodes, migration.edges), (4, 2));
+
+        let before = extract_definitions(OLD).unwrap();
+        let after = extract_definitions(&migration.script).unwrap();
+        assert!(same_graph(&before, &after));
+    }
+
+    #[test]
+    fn migrated_scripts_are_left_alone() {
+        let migration = migrate_script(MIGRATED).unwrap();
+        assert_eq!(migration.script, MIGRATED);
+        assert_eq!((migration.nodes, migration.edges), (0, 0));
+    }
+
+    #[test]
+    fn different_graphs_are_told_apart() {
+        let before = extract_definitions(OLD).unwrap();
+        let after = extract_definitions(&MIGRATED.replace("both -> Output", "both -> Compile")).unwrap();
+        assert!(!same_graph(&before, &after));
+    }
+}
@@ -1,41 +1,225 @@
+use std::{collections::HashSet, path::Path};
+
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
+use pest::error::Error;
+use schemars::schema::RootSchema;
 use serde_json::Value;
 
-use crate::{flowscript::{parser::extract_definitions, transform::TransformError}, system::job_core};
+use crate::system::job_core;
+
+use self::parser::Rule;
+pub use self::parser::{edge_kind_help, kind_help, EDGE_KINDS, NODE_KINDS};
+pub use self::scheduler::Host;
 
+mod debug;
+mod exec;
+mod format;
+pub mod lsp;
+pub mod migrate;
+mod modules;
 mod nodes;
 mod parser;
+mod scheduler;
+pub mod trace;
 mod transform;
+mod typecheck;
+mod validate;
+
+// Parses and validates a script and its imports. `path` is where the script
+// was read from, imports are relative to it.
+fn find_errors(script: &str, path: Option<&Path>) -> Result<modules::Program, Vec<Error<Rule>>> {
+    let program = modules::resolve(script, path)?;
+
+    let subgraphs: HashSet<&String> = program.subgraphs.keys().collect();
+    let mut errors = Vec::new();
+    for (module, defs) in program.graphs() {
+        for e in validate::validate(&module.script, defs, &subgraphs) {
+            errors.push(modules::with_module(e, module));
+        }
+    }
+    // Types are only worth checking once the graph itself makes sense
+    if errors.is_empty() {
+        errors = typecheck::check(&program);
+    }
+    match errors.is_empty() {
+        true => Ok(program),
+        false => Err(errors),
+    }
+}
+
+// Like find_errors, printing every error that was found
+fn load(script: &str, path: Option<&Path>) -> Result<modules::Program> {
+    find_errors(script, path).map_err(|errors| {
+        for e in &errors {
+            println!("{}", e);
+        }
+        anyhow::anyhow!("Flowscript has {} error(s)", errors.len())
+    })
+}
+
+// Every error in a script, for callers that want to show them somewhere else
+pub fn flowscript_errors(script: &str) -> Vec<String> {
+    match find_errors(script, None) {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+pub fn check_flowscript(script: &str, path: Option<&Path>) -> Result<()> {
+    load(script, path).map(|_| ())
+}
 
 pub fn execute_flowscript<'a, T: job_core::Job + Serialize + Deserialize<'a>>(
-    script: &String,
+    script: &str,
+    path: Option<&Path>,
     input: T,
 ) -> Result<Value> {
-    let defs = match extract_definitions(script) {
-        Ok(defs) => defs,
-        Err(e) => {
-            println!("{}", e);
-            return Err(anyhow::anyhow!("Error parsing file"));
-        }
-    };
-    let graph = match transform::defs_to_graph(defs) {
+    execute_flowscript_json(script, path, serde_json::to_value(input)?)
+}
+
+pub fn execute_flowscript_json(script: &str, path: Option<&Path>, input: Value) -> Result<Value> {
+    execute_with(script, path, input, &|_, node| node)
+}
+
+// Like execute_flowscript_json, with the jobs that prompt or write files
+// handed to `host` on this thread
+pub fn execute_flowscript_hosted(script: &str, path: Option<&Path>, input: Value, host: Host) -> Result<Value> {
+    let (graph, context) = prepare(script, path, &|_, node| node)?;
+    context.run_hosted(&graph, input, host)
+}
+
+// Like execute_flowscript_hosted with every job handed to `host`, so a script
+// can be tried out with stand-ins for jobs that would ask a model or prompt
+pub fn execute_flowscript_stubbed(script: &str, path: Option<&Path>, input: Value, host: Host) -> Result<Value> {
+    let (graph, mut context) = prepare(script, path, &|_, node| node)?;
+    context.host_every_job();
+    context.run_hosted(&graph, input, host)
+}
+
+// Runs a script recording every step, the trace is written even when the run fails
+pub fn trace_flowscript(
+    script: &str,
+    path: Option<&Path>,
+    input: Value,
+    trace_path: &Path,
+    format: trace::TraceFormat,
+) -> Result<Value> {
+    let tracer = trace::Tracer::new();
+    let result = execute_with(script, path, input, &|info, inner| {
+        Box::new(trace::TracedNode {
+            name: info.name,
+            kind: info.kind,
+            inner,
+            tracer: tracer.clone(),
+        })
+    });
+    tracer.write(trace_path, format)?;
+    result
+}
+
+// Runs a script that pauses before breakpoints, see debug::Debugger
+pub fn debug_flowscript(
+    script: &str,
+    path: Option<&Path>,
+    input: Value,
+    breakpoints: Vec<String>,
+) -> Result<Value> {
+    let debugger = debug::Debugger::new(breakpoints);
+    let (graph, context) = prepare(script, path, &|info, inner| debugger.wrap(info, inner))?;
+    debugger.check_breakpoints()?;
+    context.run(&graph, input)
+}
+
+// Runs a script and prints how often each node ran and for how long, even
+// when the run fails
+pub fn profile_flowscript(script: &str, path: Option<&Path>, input: Value) -> Result<Value> {
+    let (graph, context) = prepare(script, path, &|_, node| node)?;
+    let result = context.run(&graph, input);
+
+    println!("{} steps", context.steps());
+    println!("{:<32} {:>6} {:>6} {:>10}", "node", "runs", "errors", "ms");
+    for (node, metrics) in context.metrics() {
+        println!(
+            "{:<32} {:>6} {:>6} {:>10.1}",
+            node,
+            metrics.runs,
+            metrics.errors,
+            metrics.time.as_secs_f64() * 1000.0
+        );
+    }
+    result
+}
+
+fn execute_with(script: &str, path: Option<&Path>, input: Value, wrap: transform::Wrap) -> Result<Value> {
+    let (graph, context) = prepare(script, path, wrap)?;
+    context.run(&graph, input)
+}
+
+// Builds the nodes of a script and the context for one run of them
+fn prepare(
+    script: &str,
+    path: Option<&Path>,
+    wrap: transform::Wrap,
+) -> Result<(nodes::NodeMap, scheduler::Context)> {
+    let program = load(script, path)?;
+
+    let graph = match transform::build_program(&program, wrap) {
         Ok(graph) => graph,
         Err(e) => {
-            match &e {
-                TransformError::NoConnection(f) => {
-                    println!("Error: No connection for {}", f)
-                }
-            };
+            println!("Error: {}", e);
             return Err(anyhow::anyhow!("Error rectifying connections"));
         }
     };
 
-    let Some(input_node) = graph.get("input") else {
+    if !graph.contains_key("input") {
         println!("Error: No input node");
         return Err(anyhow::anyhow!("No input node"));
+    }
+
+    let max_steps = program
+        .main()
+        .attributes
+        .get("max_steps")
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(scheduler::DEFAULT_MAX_STEPS);
+    Ok((graph, scheduler::Context::new(max_steps)))
+}
+
+pub fn format_flowscript(script: &str) -> Result<String> {
+    format::format_script(script).map_err(|e| {
+        println!("{}", e);
+        anyhow::anyhow!("Error parsing file")
+    })
+}
+
+// Refuses to hand back a script that would build a different graph
+pub fn migrate_flowscript(script: &str) -> Result<migrate::Migration> {
+    let before = parser::extract_definitions(script);
+    let migration = migrate::migrate_script(script);
+    let (before, migration) = match (before, migration) {
+        (Ok(before), Ok(migration)) => (before, migration),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("{}", e);
+            return Err(anyhow::anyhow!("Error parsing file"));
+        }
     };
 
-    let input_json = serde_json::to_value(input)?;
-    input_node.execute(input_json, &graph)
+    match parser::extract_definitions(&migration.script) {
+        Ok(after) if migrate::same_graph(&before, &after) => Ok(migration),
+        _ => Err(anyhow::anyhow!("The migrated script doesn't build the same graph, left unchanged")),
+    }
+}
+
+pub fn flowscript_outline(script: &str) -> Result<String> {
+    Ok(format::to_outline(&load(script, None)?))
+}
+
+// A short spelling of what a job reads or gives back, like `{errors: [...]}`
+pub fn schema_signature(schema: &RootSchema) -> String {
+    typecheck::signature(&typecheck::from_root(schema))
+}
+
+pub fn flowscript_to_dot(script: &str, path: Option<&Path>) -> Result<String> {
+    Ok(format::to_dot(&load(script, path)?))
 }
@@ -0,0 +1,383 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use pest::error::Error;
+
+use crate::system::types::JobType;
+
+use super::{
+    parser::{extract_definitions, Defs, NodeDef, Rule},
+    validate::build_error,
+};
+
+// One parsed file, the script that was run comes first
+pub struct Module {
+    pub path: Option<PathBuf>,
+    pub script: String,
+    pub defs: Defs,
+}
+
+impl Module {
+    pub fn display_name(&self) -> String {
+        match self.path {
+            Some(ref path) => path.to_string_lossy().to_string(),
+            None => "<script>".to_owned(),
+        }
+    }
+
+    fn error(&self, span: &Range<usize>, msg: &str) -> Error<Rule> {
+        with_module(build_error(&self.script, span, msg), self)
+    }
+}
+
+pub fn with_module(error: Error<Rule>, module: &Module) -> Error<Rule> {
+    match module.path {
+        Some(ref path) => error.with_path(&path.to_string_lossy()),
+        None => error,
+    }
+}
+
+// Where a callable graph is defined. `subgraph` is None for the named digraph
+// of an imported file.
+#[derive(Debug, Clone, Copy)]
+pub struct GraphRef {
+    pub module: usize,
+    pub subgraph: Option<usize>,
+}
+
+pub struct Program {
+    pub modules: Vec<Module>,
+    pub subgraphs: HashMap<String, GraphRef>,
+}
+
+impl Program {
+    pub fn main(&self) -> &Defs {
+        &self.modules[0].defs
+    }
+
+    pub fn graph(&self, graph: GraphRef) -> &Defs {
+        let defs = &self.modules[graph.module].defs;
+        match graph.subgraph {
+            Some(index) => &defs.subgraphs[index],
+            None => defs,
+        }
+    }
+
+    // Every graph that can run with the module it was written in
+    pub fn graphs(&self) -> Vec<(&Module, &Defs)> {
+        let mut graphs = vec![(&self.modules[0], self.main())];
+        let mut refs: Vec<&GraphRef> = self.subgraphs.values().collect();
+        refs.sort_by_key(|r| (r.module, r.subgraph));
+        for graph in refs {
+            graphs.push((&self.modules[graph.module], self.graph(*graph)));
+        }
+        graphs
+    }
+
+    // Subgraphs called by task nodes in a graph
+    pub fn callees<'a>(&self, defs: &'a Defs) -> Vec<&'a String> {
+        let mut names: Vec<&String> = defs
+            .variables
+            .iter()
+            .filter(|(name, def)| matches!(def, NodeDef::Task(_)) && self.subgraphs.contains_key(*name))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+// Parses a script and everything it imports, then registers every subgraph.
+// Import paths are relative to the file doing the import.
+pub fn resolve(script: &str, path: Option<&Path>) -> Result<Program, Vec<Error<Rule>>> {
+    let mut modules = Vec::new();
+    let mut errors = Vec::new();
+    let root = path.map(|p| p.canonicalize().unwrap_or(p.to_owned()));
+    let mut stack: Vec<PathBuf> = root.iter().cloned().collect();
+    load_module(script.to_owned(), root, &mut modules, &mut stack, &mut errors);
+    // Names are still checked when an import failed, as long as the script parsed
+    if modules.is_empty() {
+        return Err(errors);
+    }
+
+    let mut program = Program {
+        modules,
+        subgraphs: HashMap::new(),
+    };
+    register_subgraphs(&mut program, &mut errors);
+    check_shadowed_names(&program, &mut errors);
+    check_call_cycles(&program, &mut errors);
+
+    match errors.is_empty() {
+        true => Ok(program),
+        false => Err(errors),
+    }
+}
+
+fn load_module(
+    script: String,
+    path: Option<PathBuf>,
+    modules: &mut Vec<Module>,
+    stack: &mut Vec<PathBuf>,
+    errors: &mut Vec<Error<Rule>>,
+) {
+    let defs = match extract_definitions(&script) {
+        Ok(defs) => defs,
+        Err(e) => {
+            errors.push(match path {
+                Some(ref path) => e.with_path(&path.to_string_lossy()),
+                None => e,
+            });
+            return;
+        }
+    };
+
+    let imports = defs.imports.clone();
+    let base = path
+        .as_ref()
+        .and_then(|p| p.parent())
+        .map(|p| p.to_owned())
+        .unwrap_or(PathBuf::from("."));
+    modules.push(Module { path, script, defs });
+    let index = modules.len() - 1;
+
+    for (import, span) in imports {
+        let target = match base.join(&import).canonicalize() {
+            Ok(target) => target,
+            Err(e) => {
+                errors.push(modules[index].error(&span, &format!("Could not import {}: {}", import, e)));
+                continue;
+            }
+        };
+
+        if let Some(start) = stack.iter().position(|p| *p == target) {
+            let mut cycle: Vec<String> = stack[start..]
+                .iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect();
+            cycle.push(target.to_string_lossy().to_string());
+            errors.push(modules[index].error(&span, &format!("Import cycle: {}", cycle.join(" -> "))));
+            continue;
+        }
+
+        // Importing the same file from two places is fine, it's only loaded once
+        if modules.iter().any(|m| m.path.as_ref() == Some(&target)) {
+            continue;
+        }
+
+        let script = match fs::read_to_string(&target) {
+            Ok(script) => script,
+            Err(e) => {
+                errors.push(modules[index].error(&span, &format!("Could not import {}: {}", import, e)));
+                continue;
+            }
+        };
+        stack.push(target.clone());
+        load_module(script, Some(target), modules, stack, errors);
+        stack.pop();
+    }
+}
+
+fn register_subgraphs(program: &mut Program, errors: &mut Vec<Error<Rule>>) {
+    for (m, module) in program.modules.iter().enumerate() {
+        let mut found = Vec::new();
+        // The digraph of an imported file can be called by its name
+        if m > 0 {
+            if let Some(ref name) = module.defs.name {
+                found.push((name, module.defs.span.clone(), None));
+            }
+        }
+        for (i, sub) in module.defs.subgraphs.iter().enumerate() {
+            let name = sub.name.as_ref().expect("Subgraphs are always named");
+            found.push((name, sub.span.clone(), Some(i)));
+        }
+
+        for (name, span, subgraph) in found {
+            if JobType::from_name(name).is_some() {
+                errors.push(module.error(&span, &format!("Subgraph {} has the same name as a job type", name)));
+                continue;
+            }
+            if let Some(existing) = program.subgraphs.get(name) {
+                let other = &program.modules[existing.module];
+                errors.push(module.error(
+                    &span,
+                    &format!("Subgraph {} is already defined in {}", name, other.display_name()),
+                ));
+                continue;
+            }
+            program.subgraphs.insert(name.clone(), GraphRef { module: m, subgraph });
+        }
+    }
+}
+
+// A node with a kind can't share a name with a subgraph, it would be unclear
+// which one a connection means
+fn check_shadowed_names(program: &Program, errors: &mut Vec<Error<Rule>>) {
+    for (module, defs) in program.graphs() {
+        let mut names: Vec<&String> = defs.variables.keys().collect();
+        names.sort();
+        for name in names {
+            let is_task = matches!(defs.variables[name], NodeDef::Task(_) | NodeDef::Input);
+            if !is_task && program.subgraphs.contains_key(name) {
+                let span = defs.spans.get(name).cloned().unwrap_or(0..0);
+                errors.push(module.error(&span, &format!("Node {} has the same name as a subgraph", name)));
+            }
+        }
+    }
+}
+
+fn check_call_cycles(program: &Program, errors: &mut Vec<Error<Rule>>) {
+    fn visit<'a>(
+        name: &'a String,
+        program: &'a Program,
+        path: &mut Vec<&'a String>,
+        done: &mut HashSet<&'a String>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.clone());
+            return Some(cycle);
+        }
+        if !done.insert(name) {
+            return None;
+        }
+
+        path.push(name);
+        for callee in program.callees(program.graph(program.subgraphs[name])) {
+            if let Some(cycle) = visit(callee, program, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    let mut names: Vec<&String> = program.subgraphs.keys().collect();
+    names.sort();
+    let mut done = HashSet::new();
+    for name in names {
+        if let Some(cycle) = visit(name, program, &mut Vec::new(), &mut done) {
+            let graph = program.subgraphs[&cycle[0]];
+            let module = &program.modules[graph.module];
+            errors.push(module.error(
+                &program.graph(graph).span,
+                &format!("Subgraph {} calls itself: {}", cycle[0], cycle.join(" -> ")),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the files of a project and resolves `main.fs`
+    fn resolve_files(files: &[(&str, &str)]) -> (tempfile::TempDir, Result<Program, Vec<String>>) {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, script) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, script).unwrap();
+        }
+        let main = dir.path().join("main.fs");
+        let script = fs::read_to_string(&main).unwrap();
+        let program = resolve(&script, Some(&main))
+            .map_err(|errors| errors.iter().map(|e| e.variant.message().to_string()).collect());
+        (dir, program)
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_file_and_loaded_once() {
+        let (_dir, program) = resolve_files(&[
+            (
+                "main.fs",
+                "digraph {
+  import \"flows/build.fs\";
+  import \"flows/report.fs\";
+  input -> build;
+  build -> report;
+}
+",
+            ),
+            ("flows/build.fs", "digraph build {\n  import \"report.fs\";\n  input -> Compile;\n}\n"),
+            ("flows/report.fs", "digraph report {\n  input -> Output;\n}\n"),
+        ]);
+        let program = program.unwrap();
+        assert_eq!(program.modules.len(), 3);
+        let mut names: Vec<&String> = program.subgraphs.keys().collect();
+        names.sort();
+        assert_eq!(names, ["build", "report"]);
+        assert_eq!(program.callees(program.main()), ["build", "report"]);
+    }
+
+    #[test]
+    fn missing_imports_and_import_cycles_are_errors() {
+        let missing = "digraph {\n  import \"gone.fs\";\n  input -> Compile;\n}\n";
+        let (_dir, program) = resolve_files(&[("main.fs", missing)]);
+        let errors = program.err().unwrap();
+        assert!(errors[0].starts_with("Could not import gone.fs"), "{:?}", errors);
+
+        let (_dir, program) = resolve_files(&[
+            ("main.fs", "digraph {\n  import \"a.fs\";\n  input -> a;\n}\n"),
+            ("a.fs", "digraph a {\n  import \"main.fs\";\n  input -> Compile;\n}\n"),
+        ]);
+        let errors = program.err().unwrap();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("Import cycle: "), "{:?}", errors);
+        assert!(errors[0].ends_with("main.fs"), "{:?}", errors);
+    }
+
+    #[test]
+    fn names_can_only_mean_one_thing() {
+        let (_dir, program) = resolve_files(&[
+            (
+                "main.fs",
+                "digraph {\n  import \"lib.fs\";\n  input -> tidy;\n  subgraph tidy {\n    input -> Output;\n  }\n}\n",
+            ),
+            ("lib.fs", "digraph tidy {\n  input -> Compile;\n}\n"),
+        ]);
+        let errors = program.err().unwrap();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("Subgraph tidy is already defined in "), "{:?}", errors);
+
+        let (_dir, program) = resolve_files(&[(
+            "main.fs",
+            "digraph {\n  input -> Compile;\n  subgraph Compile {\n    input -> Output;\n  }\n}\n",
+        )]);
+        assert_eq!(program.err().unwrap(), ["Subgraph Compile has the same name as a job type"]);
+
+        let (_dir, program) = resolve_files(&[(
+            "main.fs",
+            "digraph {\n  input -> tidy;\n  tidy [shape=\"component\"];\n  subgraph tidy {\n    input -> Output;\n  }\n}",
+        )]);
+        assert_eq!(program.err().unwrap(), ["Node tidy has the same name as a subgraph"]);
+    }
+
+    #[test]
+    fn subgraphs_can_not_call_each_other_in_a_circle() {
+        let (_dir, program) = resolve_files(&[(
+            "main.fs",
+            "digraph {
+  input -> first;
+  subgraph first {
+    input -> second;
+  }
+  subgraph second {
+    input -> third;
+  }
+  subgraph third {
+    input -> first;
+  }
+}
+",
+        )]);
+        assert_eq!(
+            program.err().unwrap(),
+            ["Subgraph first calls itself: first -> second -> third -> first"]
+        );
+    }
+}
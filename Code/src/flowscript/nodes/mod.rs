@@ -1,14 +1,38 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
-use serde_json::{Map, Value};
+use serde_json::{json, Map, Value};
 
-use crate::system;
+use crate::system::{self, types::JobType};
 
-use super::{parser, transform::safe_parse_to_value};
+use super::{
+    exec,
+    parser::{self, conditional::SetterOp, ExecDef, ForEachDef, ForEachMode, MergePolicy, TaskOptions},
+    scheduler::{Run, Stop},
+    trace,
+};
 
-pub trait Node {
-    fn execute(&self, input: serde_json::Value, node_map: &NodeMap) -> Result<Value>;
+// Where the payload goes once a node has run
+pub enum Next {
+    To(String, Value),
+    End(Value), // The path stops here with this result
+}
+
+fn next(points_to: &Option<String>, payload: Value) -> Next {
+    match points_to {
+        Some(points_to) => Next::To(points_to.clone(), payload),
+        None => Next::End(payload),
+    }
+}
+
+// Nodes don't run what comes after them, the scheduler does. They are shared
+// between the threads running multi node branches.
+pub trait Node: Send + Sync {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next>;
 }
 
 pub type NodeMap = HashMap<String, Box<dyn Node>>;
@@ -21,11 +45,8 @@ pub struct InputNode {
 }
 
 impl Node for InputNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
-        let node = node_map
-            .get(&self.points_to)
-            .ok_or(anyhow!("Could not find input in table"))?;
-        node.execute(input, node_map)
+    fn execute(&self, input: Value, _run: &Run) -> Result<Next> {
+        Ok(Next::To(self.points_to.clone(), input))
     }
 }
 
@@ -34,23 +55,142 @@ impl Node for InputNode {
 #[derive(Debug)]
 pub struct TaskNode {
     pub command: String,
+    pub options: TaskOptions,
+    pub on_error: Option<String>,
     pub points_to: Option<String>,
 }
 
-impl Node for TaskNode {
-    fn execute(&self, input: Value, _node_map: &NodeMap) -> Result<Value> {
-        // Run the task in the job system
-        let result = system::run_job_fs(self.command.clone(), input);
-        match self.points_to {
-            Some(ref points_to) => {
-                let node = _node_map.get(points_to).unwrap();
-                node.execute(result, _node_map)
+// A failed node goes to its on_error node with the error added to the input,
+// without one the whole script stops. So does a run that was stopped.
+fn handle_failure(name: &str, error: anyhow::Error, input: Value, on_error: &Option<String>) -> Result<Next> {
+    if error.is::<Stop>() {
+        return Err(error);
+    }
+    let Some(ref handler) = on_error else {
+        return Err(anyhow!("Node {} failed: {}", name, error));
+    };
+    let error = json!({ "node": name, "message": error.to_string() });
+    let input = match input {
+        Value::Object(mut map) => {
+            map.insert("__error".to_owned(), error);
+            Value::Object(map)
+        }
+        _ => json!({ "__error": error }),
+    };
+    Ok(Next::To(handler.clone(), input))
+}
+
+// Doubling stops here, unless the backoff itself is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Retries wait `backoff` and double the wait every time
+fn run_with_retries(name: &str, options: &TaskOptions, run: impl Fn() -> Result<Value>) -> Result<Value> {
+    let max_delay = MAX_BACKOFF.max(options.backoff);
+    let mut delay = options.backoff;
+    let mut attempt = 0;
+    loop {
+        match run() {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt >= options.retries => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                println!("{} failed ({}), retrying {}/{}", name, e, attempt, options.retries);
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2).min(max_delay);
             }
-            None => Ok(result),
         }
     }
 }
 
+// Object results are merged into the input so state like `files` is still
+// there for later jobs, anything else replaces it
+fn merge_result(input: Value, result: Value) -> Value {
+    match (input, result) {
+        (Value::Object(mut input), Value::Object(result)) => {
+            input.extend(result);
+            Value::Object(input)
+        }
+        (_, result) => result,
+    }
+}
+
+impl Node for TaskNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        // Run the task in the job system, or on the host for jobs that prompt or write files
+        let hosted = JobType::from_name(&self.command).filter(|job| run.hosts(job));
+        let job = || match hosted {
+            Some(job_type) => run.host(job_type, input.clone()),
+            None => system::run_job_fs(self.command.clone(), input.clone(), self.options.timeout),
+        };
+        let result = match run_with_retries(&self.command, &self.options, job) {
+            Ok(result) => result,
+            Err(e) => return handle_failure(&self.command, e, input, &self.on_error),
+        };
+        Ok(next(&self.points_to, merge_result(input, result)))
+    }
+}
+
+// Exec Node -------------------
+
+#[derive(Debug)]
+pub struct ExecNode {
+    pub name: String,
+    pub def: ExecDef,
+    pub on_error: Option<String>,
+    pub points_to: Option<String>,
+}
+
+impl Node for ExecNode {
+    fn execute(&self, input: Value, _run: &Run) -> Result<Next> {
+        let run = || exec::run(&self.def, &input);
+        let result = match run_with_retries(&self.name, &self.def.options, run) {
+            Ok(result) => result,
+            Err(e) => return handle_failure(&self.name, e, input, &self.on_error),
+        };
+        Ok(next(&self.points_to, merge_result(input, result)))
+    }
+}
+
+// Subgraph Node -----------------
+
+// A graph that can be called like a task. When inputs or outputs are named only
+// those fields go in or come back out.
+pub struct Subgraph {
+    pub graph: NodeMap,
+    pub inputs: Option<Vec<String>>,
+    pub outputs: Option<Vec<String>>,
+}
+
+fn pick_fields(value: Value, fields: &Option<Vec<String>>) -> Value {
+    match (fields, value) {
+        (Some(fields), Value::Object(map)) => Value::Object(
+            map.into_iter()
+                .filter(|(key, _)| fields.contains(key))
+                .collect(),
+        ),
+        (_, value) => value,
+    }
+}
+
+pub struct SubgraphNode {
+    pub name: String,
+    pub subgraph: Arc<Subgraph>,
+    pub on_error: Option<String>,
+    pub points_to: Option<String>,
+}
+
+impl Node for SubgraphNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        let inner = run.subgraph(&self.name, &self.subgraph.graph);
+        let result = match inner.path("input", pick_fields(input.clone(), &self.subgraph.inputs)) {
+            Ok(result) => result,
+            Err(e) => return handle_failure(&self.name, e, input, &self.on_error),
+        };
+        let result = merge_result(input, pick_fields(result, &self.subgraph.outputs));
+        Ok(next(&self.points_to, result))
+    }
+}
+
 // If Node -----------------------
 
 #[derive(Debug)]
@@ -61,38 +201,31 @@ pub struct IfNode {
 }
 
 impl Node for IfNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
-        // TODO: Show errors
+    fn execute(&self, input: Value, _run: &Run) -> Result<Next> {
         let bool_result =
             parser::conditional::evaluate_if_statement(self.condition.clone(), &input)
                 .map_err(|e| anyhow!("Could not evaluate conditional {}", e))?;
 
         if bool_result {
-            let node = node_map
-                .get(self.true_branch.as_str())
-                .ok_or(anyhow!("Could not get node in table"))?;
-            node.execute(input, node_map)
+            Ok(Next::To(self.true_branch.clone(), input))
         } else {
-            let node = node_map
-                .get(self.false_branch.as_str())
-                .ok_or(anyhow!("Could not find node in table"))?;
-            node.execute(input, node_map)
+            Ok(Next::To(self.false_branch.clone(), input))
         }
     }
 }
 
 // Count Node --------------------
 
+// The count is kept by the run, see Run::count
 #[derive(Debug)]
 pub struct CountNode {
-    pub count: RefCell<usize>,
+    pub name: String,
     pub points_to: String,
 }
 
 impl Node for CountNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
-        let next = node_map.get(&self.points_to).unwrap();
-        self.count.replace_with(|&mut x| x + 1);
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        let count = run.count(&self.name);
         // Merge the count into the json
         let mut binding = input.clone();
         let new_input = match binding.as_object_mut() {
@@ -101,19 +234,198 @@ impl Node for CountNode {
         };
         new_input.insert(
             "__count".to_owned(),
-            serde_json::Value::from(*self.count.borrow()),
+            serde_json::Value::from(count),
         );
 
-        next.execute(new_input.clone().into(), node_map)
+        Ok(Next::To(self.points_to.clone(), new_input.clone().into()))
+    }
+}
+
+// Loop Node ---------------------
+
+#[derive(Debug)]
+pub struct LoopNode {
+    pub name: String,
+    pub condition: String,
+    pub max: usize,
+    pub body: String,
+    pub points_to: Option<String>,
+}
+
+impl Node for LoopNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        // The body runs until it reaches a node with nowhere to go, and that
+        // result is fed into the next iteration
+        let keep_going = |value: &Value| {
+            parser::conditional::evaluate_if_statement(self.condition.clone(), value)
+                .map_err(|e| anyhow!("Could not evaluate conditional {}", e))
+        };
+        let mut value = input;
+        for iteration in 1..=self.max {
+            if !keep_going(&value)? {
+                return Ok(next(&self.points_to, value));
+            }
+
+            if let Some(map) = value.as_object_mut() {
+                map.insert("__iteration".to_owned(), Value::from(iteration));
+            }
+            value = run.path(&self.body, value)?;
+        }
+
+        // Running out of iterations isn't an error but it is rarely what was meant
+        if keep_going(&value)? {
+            println!(
+                "Warning: loop {} stopped after {} iterations with its condition still true, raise max to allow more",
+                self.name, self.max
+            );
+        }
+        Ok(next(&self.points_to, value))
     }
 }
 
-impl CountNode {
-    pub fn new(points_to: String) -> CountNode {
-        CountNode {
-            count: RefCell::new(0),
-            points_to,
+// For Each Node ------------------
+
+#[derive(Debug)]
+pub struct ForEachNode {
+    pub def: ForEachDef,
+    pub init: Value,
+    pub body: Option<String>,
+    pub points_to: Option<String>,
+}
+
+impl ForEachNode {
+    fn element_input(&self, payload: &Map<String, Value>, item: &Value, index: usize) -> Map<String, Value> {
+        let mut input = payload.clone();
+        input.insert(self.def.item.clone(), item.clone());
+        input.insert("index".to_owned(), Value::from(index));
+        input
+    }
+
+    // The body's whole result is collected, less the item and index it was handed
+    // when it left them as they were
+    fn run_body(
+        &self,
+        run: &Run,
+        body: &str,
+        payload: &Map<String, Value>,
+        item: &Value,
+        index: usize,
+    ) -> Result<Value> {
+        let input = self.element_input(payload, item, index);
+        Ok(match run.path(body, input.into())? {
+            Value::Object(mut result) => {
+                if result.get(&self.def.item) == Some(item) {
+                    result.remove(&self.def.item);
+                }
+                if result.get("index") == Some(&Value::from(index)) {
+                    result.remove("index");
+                }
+                Value::Object(result)
+            }
+            other => other,
+        })
+    }
+
+    fn map(&self, run: &Run, body: &str, payload: &Map<String, Value>, items: &[Value]) -> Result<Vec<Value>> {
+        if !self.def.parallel {
+            return items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| self.run_body(run, body, payload, item, index))
+                .collect();
+        }
+
+        // One thread per core at a time, the jobs themselves run on the job system
+        let width = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(2);
+        let mut results = Vec::new();
+        let step = trace::current_step();
+        for (chunk_index, chunk) in items.chunks(width).enumerate() {
+            let chunk_results: Vec<Result<Value>> = std::thread::scope(|scope| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(offset, item)| {
+                        let index = chunk_index * width + offset;
+                        scope.spawn(move || {
+                            trace::set_current_step(step);
+                            self.run_body(run, body, payload, item, index)
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow!("For each body panicked")))
+                    })
+                    .collect()
+            });
+            for result in chunk_results {
+                results.push(result?);
+            }
         }
+        Ok(results)
+    }
+}
+
+fn write_into(into: &str, payload: &mut Value, value: Value) -> Result<()> {
+    parser::conditional::set_path(into, payload, value)
+        .map_err(|e| anyhow!("Could not write to {} {}", into, e))
+}
+
+impl Node for ForEachNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        let Value::Object(payload) = input else {
+            return Err(anyhow!("For each input is not an object"));
+        };
+
+        let items = parser::conditional::evaluate_path(&self.def.over, &Value::Object(payload.clone()))
+            .map_err(|e| anyhow!("Could not read the array to go over {}", e))?;
+        let items = match items {
+            Value::Array(items) => items,
+            Value::Null => Vec::new(),
+            other => return Err(anyhow!("{} is not an array: {}", self.def.over, other)),
+        };
+
+        let result = match (self.def.mode, self.body.as_deref()) {
+            (ForEachMode::Map, Some(body)) => Value::Array(self.map(run, body, &payload, &items)?),
+
+            (ForEachMode::Filter, _) => {
+                let condition = self.def.condition.clone().unwrap_or_default();
+                let mut kept = Vec::new();
+                for (index, item) in items.into_iter().enumerate() {
+                    let input = self.element_input(&payload, &item, index).into();
+                    let keep = parser::conditional::evaluate_if_statement(condition.clone(), &input)
+                        .map_err(|e| anyhow!("Could not evaluate conditional {}", e))?;
+                    if keep {
+                        kept.push(item);
+                    }
+                }
+                Value::Array(kept)
+            }
+
+            // The accumulator lives at `into` while the body runs
+            (ForEachMode::Reduce, Some(body)) => {
+                let mut accumulator = self.init.clone();
+                for (index, item) in items.iter().enumerate() {
+                    let mut input = self.element_input(&payload, item, index).into();
+                    write_into(&self.def.into, &mut input, accumulator)?;
+                    let result = run.path(body, input)?;
+                    accumulator = parser::conditional::evaluate_path(&self.def.into, &result)
+                        .map_err(|e| anyhow!("Could not read {} {}", self.def.into, e))?;
+                }
+                accumulator
+            }
+
+            (_, None) => return Err(anyhow!("For each over {} has no body", self.def.over)),
+        };
+        let mut payload = Value::Object(payload);
+        write_into(&self.def.into, &mut payload, result)?;
+        Ok(next(&self.points_to, payload))
     }
 }
 
@@ -122,36 +434,77 @@ impl CountNode {
 #[derive(Debug)]
 pub struct MultiNode {
     pub run_before: Vec<String>,
+    pub merge: MergePolicy,
     pub points_to: String,
 }
 
 impl Node for MultiNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
-        let mut results = Vec::new();
-        for node_name in &self.run_before {
-            let node = node_map.get(node_name).unwrap();
-            let result = node.execute(input.clone(), node_map)?;
-            results.push(result);
-        }
-
-        // Merge all results into one json value
-        let mut merged = match input.as_object() {
-            Some(map) => map.clone(),
-            None => serde_json::Map::new(),
-        };
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        // Every branch runs on its own thread, so their jobs are queued together
+        let step = trace::current_step();
+        let results: Vec<Result<Value>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .run_before
+                .iter()
+                .map(|branch| {
+                    let input = input.clone();
+                    scope.spawn(move || {
+                        trace::set_current_step(step);
+                        run.path(branch, input)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("Multi node branch panicked")))
+                })
+                .collect()
+        });
+
+        // Merge what each branch changed into the input, in the order the branches were declared. A key
+        // only counts as set by a branch when its value differs from the input, so branches that pass
+        // the payload through don't clash
+        let empty = serde_json::Map::new();
+        let original = input.as_object().unwrap_or(&empty);
+        let mut merged = original.clone();
+
+        let mut set_by_branch = HashSet::new();
+        for (branch, result) in self.run_before.iter().zip(results) {
+            let result = result?;
+
+            if self.merge == MergePolicy::NestByBranch {
+                merged.insert(branch.clone(), result);
+                continue;
+            }
 
-        for result in results {
             let result = match result.as_object() {
                 Some(map) => map,
-                None => return Err(anyhow!("Result is not an object")),
+                None => return Err(anyhow!("Result of {} is not an object", branch)),
             };
-            for (key, value) in result {
-                merged.insert(key.clone(), value.clone());
+            let changed = result
+                .iter()
+                .filter(|(key, value)| original.get(*key) != Some(*value))
+                .map(|(key, value)| (key, Some(value)));
+            let removed = original
+                .keys()
+                .filter(|key| !result.contains_key(*key))
+                .map(|key| (key, None));
+            for (key, value) in changed.chain(removed) {
+                if !set_by_branch.insert(key.clone()) && self.merge == MergePolicy::Error {
+                    return Err(anyhow!("Multi node branches both set the key {}", key));
+                }
+                match value {
+                    Some(value) => merged.insert(key.clone(), value.clone()),
+                    None => merged.remove(key),
+                };
             }
         }
 
-        let node = node_map.get(&self.points_to).unwrap();
-        node.execute(merged.into(), node_map)
+        Ok(Next::To(self.points_to.clone(), merged.into()))
     }
 }
 
@@ -164,29 +517,22 @@ pub struct SwitchNode {
 }
 
 impl Node for SwitchNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
+    fn execute(&self, input: Value, _run: &Run) -> Result<Next> {
         let to_compare = input
             .get(self.field.clone())
             .ok_or(anyhow!("Could not read field for match statement"))?;
         for case in self.cases_to.as_slice() {
             let (test, points_to) = case;
             if *to_compare == *test {
-                let node = node_map.get(&points_to.clone()).unwrap();
-                return node.execute(input, node_map);
+                return Ok(Next::To(points_to.clone(), input));
             }
         }
 
-        match self.default_to {
-            Some(ref points_to) => {
-                let node = node_map.get(points_to).unwrap();
-                node.execute(input, node_map)
-            }
-            None => Ok(input),
-        }
+        Ok(next(&self.default_to, input))
     }
 }
 
-// Switch Node --------------------
+// Match Node ---------------------
 #[derive(Debug)]
 pub struct MatchNode {
     pub field: String,
@@ -195,7 +541,7 @@ pub struct MatchNode {
 }
 
 impl Node for MatchNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
         let mut result = input.clone();
         let to_compare = result
             .get(self.field.clone())
@@ -203,52 +549,119 @@ impl Node for MatchNode {
         for case in self.cases_to.as_slice() {
             let (test, points_to) = case;
             if *to_compare == *test {
-                let node = node_map.get(&points_to.clone()).unwrap();
-                result = node.execute(input.clone(), node_map)?;
+                result = run.path(points_to, input.clone())?;
                 break;
             }
         }
 
-        match self.default_to {
-            Some(ref points_to) => {
-                let node = node_map.get(points_to).unwrap();
-                node.execute(result, node_map)
-            }
-            None => Ok(result),
-        }
+        Ok(next(&self.default_to, result))
     }
 }
 
 pub struct AddFieldNode {
-    pub label: String,
+    pub ops: Vec<SetterOp>,
     pub points_to: Option<String>,
 }
 
 impl Node for AddFieldNode {
-    fn execute(&self, input: Value, node_map: &NodeMap) -> Result<Value> {
-        let mut new_input = input.clone();
-        let mut default = Map::new();
-        let new_input = match new_input.as_object_mut() {
-            Some(map) => map,
-            None => &mut default,
+    fn execute(&self, input: Value, _run: &Run) -> Result<Next> {
+        let payload = match input {
+            Value::Object(map) => map,
+            _ => Map::new(),
         };
+        let payload = parser::conditional::apply_setter(&self.ops, payload)
+            .map_err(|e| anyhow!("Could not set fields {}", e))?;
 
-        // Split label on ":" and set the values
-        let mut split = self.label.split(':');
-        let key = split
-            .next()
-            .ok_or_else(|| anyhow!("Could not get key from field node"))?;
-        let value = split.next().unwrap_or("null");
+        Ok(next(&self.points_to, payload.into()))
+    }
+}
 
-        let value = safe_parse_to_value(value.trim());
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::flowscript::{execute_flowscript_json, scheduler::tests::one_run_at_a_time};
+
+    #[test]
+    fn map_collects_the_body_result_without_the_item_and_index() {
+        let _run = one_run_at_a_time();
+        // The second element sets fix_warnings to the value it already had
+        let script = r#"digraph {
+  input -> each;
+  each [kind="for_each", over=".files", as="file", mode="map", into="results"];
+  each -> describe [kind="body"];
+  describe [kind="set", label="name = .file; position = .index + 1; fix_warnings = .index == 1"];
+}"#;
+        let input = json!({ "files": ["a.cpp", "b.cpp"], "fix_warnings": true });
+        let result = execute_flowscript_json(script, None, input).unwrap();
+        let files = json!(["a.cpp", "b.cpp"]);
+        assert_eq!(
+            result["results"],
+            json!([
+                { "files": files, "fix_warnings": false, "name": "a.cpp", "position": 1 },
+                { "files": files, "fix_warnings": true, "name": "b.cpp", "position": 2 },
+            ])
+        );
+        assert_eq!(result.get("file"), None);
+        assert_eq!(result.get("index"), None);
+    }
 
-        new_input.insert(key.to_owned().trim().to_owned(), value);
+    // Two branches that each set their own key, the left one also overwrites side
+    fn multi(merge: &str) -> String {
+        format!(
+            r#"digraph {{
+  input -> both;
+  both [kind="multi", merge="{merge}"];
+  both -> done;
+  done [kind="set", label="done = true"];
+  both -> left [kind="branch"];
+  both -> right [kind="branch"];
+  left [kind="set", label="side = 'left'; left = true"];
+  right [kind="set", label="right = true"];
+}}"#
+        )
+    }
 
-        if let Some(ref points_to) = self.points_to {
-            let node = node_map.get(points_to).unwrap();
-            node.execute(new_input.clone().into(), node_map)
-        } else {
-            Ok(new_input.clone().into())
-        }
+    #[test]
+    fn last_wins_merges_what_each_branch_changed() {
+        let _run = one_run_at_a_time();
+        let input = json!({ "files": [], "fix_warnings": false, "side": "none" });
+        let result = execute_flowscript_json(&multi("last_wins"), None, input).unwrap();
+        assert_eq!(result["side"], json!("left"));
+        assert_eq!(result["left"], json!(true));
+        assert_eq!(result["right"], json!(true));
+
+        let script = multi("last_wins").replace("label=\"right = true\"", "label=\"side = 'right'\"");
+        let input = json!({ "files": [], "fix_warnings": false });
+        let result = execute_flowscript_json(&script, None, input).unwrap();
+        assert_eq!(result["side"], json!("right"));
+    }
+
+    #[test]
+    fn error_ignores_keys_a_branch_passed_through() {
+        let _run = one_run_at_a_time();
+        let input = json!({ "files": [], "fix_warnings": false, "side": "none" });
+        let result = execute_flowscript_json(&multi("error"), None, input).unwrap();
+        assert_eq!(result["side"], json!("left"));
+        assert_eq!(result["files"], json!([]));
+    }
+
+    #[test]
+    fn error_reports_two_branches_changing_a_key() {
+        let _run = one_run_at_a_time();
+        let script = multi("error").replace("label=\"right = true\"", "label=\"side = 'right'\"");
+        let input = json!({ "files": [], "fix_warnings": false });
+        let error = execute_flowscript_json(&script, None, input).unwrap_err();
+        assert!(format!("{error:#}").contains("both set the key side"), "{error:#}");
+    }
+
+    #[test]
+    fn nest_keeps_each_branch_under_its_name() {
+        let _run = one_run_at_a_time();
+        let input = json!({ "files": [], "fix_warnings": false });
+        let result = execute_flowscript_json(&multi("nest"), None, input).unwrap();
+        assert_eq!(result["left"]["side"], json!("left"));
+        assert_eq!(result["right"]["right"], json!(true));
+        assert_eq!(result["right"].get("side"), None);
     }
 }
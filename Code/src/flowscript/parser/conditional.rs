@@ -1,10 +1,14 @@
+use std::{borrow::Cow, cmp::Ordering};
+
 use pest::{
     error::{self, Error},
     iterators::Pair,
     Parser,
 };
 use pest_derive::Parser;
-use serde_json::Value;
+use serde_json::{Map, Value};
+
+use crate::flowscript::transform::safe_parse_to_value;
 
 #[derive(Parser)]
 #[grammar = "./flowscript/grammar/condition.pest"]
@@ -19,123 +23,526 @@ fn build_pest_error(pair: Pair<Rule>, msg: &str) -> error::Error<Rule> {
     )
 }
 
-fn parse_side(pair: Pair<Rule>, result: &serde_json::Value) -> Result<Value, Error<Rule>> {
-    let Some(inner) = pair.clone().into_inner().next() else {
-        return Err(build_pest_error(pair, "Could not get inner"));
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+// Follows a path like `.errors[0].kind`. `.length` gives the size of arrays,
+// strings and objects unless the object has its own `length` key. Anything
+// missing along the way is null so `.field == null` can test for it.
+fn lookup_path(pair: Pair<Rule>, result: &Value) -> Value {
+    let mut current = Cow::Borrowed(result);
+    for part in pair.into_inner() {
+        let next = match part.as_rule() {
+            Rule::field => match (current.get(part.as_str()), part.as_str()) {
+                (Some(value), _) => Some(value.clone()),
+                (None, "length") => match current.as_ref() {
+                    Value::Array(items) => Some(Value::from(items.len())),
+                    Value::String(text) => Some(Value::from(text.chars().count())),
+                    Value::Object(map) => Some(Value::from(map.len())),
+                    _ => None,
+                },
+                (None, _) => None,
+            },
+            Rule::index => part
+                .as_str()
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| current.get(index).cloned()),
+            _ => None,
+        };
+
+        let Some(next) = next else {
+            return Value::Null;
+        };
+        current = Cow::Owned(next);
+    }
+    current.into_owned()
+}
+
+fn parse_literal(pair: Pair<Rule>) -> Result<Value, Error<Rule>> {
+    match pair.as_rule() {
+        Rule::number_value => serde_json::from_str::<Value>(pair.as_str())
+            .map_err(|_| build_pest_error(pair, "Could not convert number")),
+        Rule::bool_value => Ok(Value::Bool(pair.as_str() == "true")),
+        Rule::null_value => Ok(Value::Null),
+        Rule::string_value => Ok(Value::String(pair.as_str().to_owned())),
+        Rule::quoted_string => {
+            let inner = pair.into_inner().next().map(|p| p.as_str()).unwrap_or("");
+            Ok(Value::String(unescape(inner)))
+        }
+        _ => Err(build_pest_error(pair, "Could not match pair type")),
+    }
+}
+
+fn evaluate_operand(pair: Pair<Rule>, result: &Value) -> Result<Value, Error<Rule>> {
+    match pair.as_rule() {
+        Rule::or_expr => evaluate_value(pair, result),
+        Rule::json_path => Ok(lookup_path(pair, result)),
+        _ => parse_literal(pair),
+    }
+}
+
+// The sum an `or_expr` is made of when it has no comparisons or logic
+fn lone_sum(pair: Pair<Rule>) -> Option<Pair<Rule>> {
+    let mut pair = pair;
+    for rule in [Rule::and_expr, Rule::unary, Rule::comparison, Rule::sum] {
+        let mut inner = pair.into_inner();
+        let only = inner.next()?;
+        if only.as_rule() != rule || inner.next().is_some() {
+            return None;
+        }
+        pair = only;
+    }
+    Some(pair)
+}
+
+// Arithmetic gives a value, anything with a comparison or logic gives a bool
+fn evaluate_value(pair: Pair<Rule>, result: &Value) -> Result<Value, Error<Rule>> {
+    match lone_sum(pair.clone()) {
+        Some(sum) => evaluate_sum(sum, result),
+        None => Ok(Value::Bool(evaluate_or(pair, result)?)),
+    }
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn number(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map(Value::Number).unwrap_or(Value::Null)
+}
+
+// Whole numbers stay whole unless they're divided
+fn arithmetic(pair: Pair<Rule>, op: Rule, left: Value, right: Value) -> Result<Value, Error<Rule>> {
+    match (op, &left, &right) {
+        (Rule::plus, Value::String(_), _) | (Rule::plus, _, Value::String(_)) => {
+            return Ok(Value::String(as_text(&left) + &as_text(&right)))
+        }
+        (Rule::plus, Value::Array(l), Value::Array(r)) => {
+            return Ok(Value::Array(l.iter().chain(r).cloned().collect()))
+        }
+        _ => {}
+    }
+
+    let (Value::Number(l), Value::Number(r)) = (&left, &right) else {
+        return Err(build_pest_error(pair, &format!("Can not do arithmetic on {} and {}", left, right)));
     };
-    match inner.as_rule() {
-        Rule::number_value => {
-            let val = serde_json::from_str::<Value>(inner.as_str())
-                .map_err(|_| build_pest_error(pair, "Could not convert number"))?;
-            Ok(val)
+    let zero = r.as_f64() == Some(0.0);
+    if zero && matches!(op, Rule::divide | Rule::modulo) {
+        return Err(build_pest_error(pair, "Division by zero"));
+    }
+    if let (Some(l), Some(r), false) = (l.as_i64(), r.as_i64(), op == Rule::divide) {
+        let whole = match op {
+            Rule::plus => l.checked_add(r),
+            Rule::minus => l.checked_sub(r),
+            Rule::times => l.checked_mul(r),
+            _ => l.checked_rem(r),
+        };
+        if let Some(whole) = whole {
+            return Ok(Value::from(whole));
         }
+    }
+
+    let (l, r) = (l.as_f64().unwrap_or_default(), r.as_f64().unwrap_or_default());
+    Ok(number(match op {
+        Rule::plus => l + r,
+        Rule::minus => l - r,
+        Rule::times => l * r,
+        Rule::divide => l / r,
+        _ => l % r,
+    }))
+}
+
+fn evaluate_product(pair: Pair<Rule>, result: &Value) -> Result<Value, Error<Rule>> {
+    let mut inner = pair.clone().into_inner();
+    let mut value = evaluate_operand(inner.next().expect("Product has an operand"), result)?;
+    while let (Some(op), Some(operand)) = (inner.next(), inner.next()) {
+        let right = evaluate_operand(operand, result)?;
+        value = arithmetic(pair.clone(), op.as_rule(), value, right)?;
+    }
+    Ok(value)
+}
+
+fn evaluate_sum(pair: Pair<Rule>, result: &Value) -> Result<Value, Error<Rule>> {
+    let mut inner = pair.clone().into_inner();
+    let mut value = evaluate_product(inner.next().expect("Sum has a product"), result)?;
+    while let (Some(op), Some(product)) = (inner.next(), inner.next()) {
+        let right = evaluate_product(product, result)?;
+        value = arithmetic(pair.clone(), op.as_rule(), value, right)?;
+    }
+    Ok(value)
+}
 
-        Rule::string_value => {
-            let input = format!("\"{}\"", inner.as_str());
-            let val = serde_json::from_str::<Value>(input.as_str())
-                .map_err(|_| build_pest_error(pair, "Could not convert string"))?;
-            Ok(val)
+fn as_bool(pair: Pair<Rule>, value: &Value) -> Result<bool, Error<Rule>> {
+    value.as_bool().ok_or_else(|| {
+        build_pest_error(pair, &format!("Expected a boolean but found {}", value))
+    })
+}
+
+fn compare(pair: Pair<Rule>, left: &Value, right: &Value) -> Result<Ordering, Error<Rule>> {
+    let ordering = match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64().partial_cmp(&r.as_f64()),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => None,
+    };
+    ordering.ok_or_else(|| build_pest_error(pair, &format!("Can not compare {} and {}", left, right)))
+}
+
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        // So 1 == 1.0
+        (Value::Number(l), Value::Number(r)) => l.as_f64() == r.as_f64(),
+        _ => left == right,
+    }
+}
+
+fn evaluate_comparison(pair: Pair<Rule>, result: &Value) -> Result<bool, Error<Rule>> {
+    let mut inner = pair.clone().into_inner();
+    let left = evaluate_sum(inner.next().expect("Comparison has an operand"), result)?;
+
+    let Some(condition) = inner.next() else {
+        return as_bool(pair, &left);
+    };
+    let right = evaluate_sum(inner.next().expect("Condition has a right side"), result)?;
+
+    let op = condition.into_inner().next().expect("Has inner");
+    Ok(match op.as_rule() {
+        Rule::equal => values_equal(&left, &right),
+        Rule::not_equal => !values_equal(&left, &right),
+        Rule::less_than => compare(pair, &left, &right)? == Ordering::Less,
+        Rule::less_than_eq_to => compare(pair, &left, &right)? != Ordering::Greater,
+        Rule::greater_than => compare(pair, &left, &right)? == Ordering::Greater,
+        Rule::greater_than_eq_to => compare(pair, &left, &right)? != Ordering::Less,
+        _ => return Err(build_pest_error(pair, "Could not match condition")),
+    })
+}
+
+fn evaluate_unary(pair: Pair<Rule>, result: &Value) -> Result<bool, Error<Rule>> {
+    let mut negate = false;
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::not => negate = !negate,
+            _ => return Ok(evaluate_comparison(part, result)? != negate),
         }
+    }
+    unreachable!("Unary always ends in a comparison")
+}
 
-        Rule::json_value => {
-            let Some(val) = result.get(inner.as_str()) else {
-                return Err(build_pest_error(pair, "Could not get value from result"));
-            };
-            Ok(val.clone())
+// `&&` and `||` short circuit
+fn evaluate_and(pair: Pair<Rule>, result: &Value) -> Result<bool, Error<Rule>> {
+    for part in pair.into_inner().filter(|p| p.as_rule() == Rule::unary) {
+        if !evaluate_unary(part, result)? {
+            return Ok(false);
         }
-        _ => Err(build_pest_error(pair, "Could not match pair type")),
     }
+    Ok(true)
 }
 
-fn parse_value_pair(pair: Pair<Rule>, result: Value) -> Result<(Value, Value), Error<Rule>> {
-    let mut left: Value = Value::Null;
-    let mut right: Value = Value::Null;
+fn evaluate_or(pair: Pair<Rule>, result: &Value) -> Result<bool, Error<Rule>> {
+    for part in pair.into_inner().filter(|p| p.as_rule() == Rule::and_expr) {
+        if evaluate_and(part, result)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
 
-    for pair in pair.into_inner().flatten() {
-        match pair.as_rule() {
-            Rule::first => {
-                left = parse_side(pair, &result)?;
-            }
+fn parse_condition(condition: &str) -> Result<Pair<'_, Rule>, Error<Rule>> {
+    let expression = ConditionParser::parse(Rule::expression, condition)?
+        .next()
+        .expect("Expression always has a root pair");
+    Ok(expression
+        .into_inner()
+        .next()
+        .expect("Expression always has a body"))
+}
+
+pub fn evaluate_path(path: &str, value: &Value) -> Result<Value, Error<Rule>> {
+    let pair = ConditionParser::parse(Rule::path, path)?
+        .next()
+        .and_then(|path| path.into_inner().next())
+        .expect("Path always has a body");
+    Ok(lookup_path(pair, value))
+}
+
+// Writes `new` at a path like `.result.items`, making objects for fields that
+// are missing or null along the way
+pub fn set_path(path: &str, value: &mut Value, new: Value) -> Result<(), Error<Rule>> {
+    let pair = ConditionParser::parse(Rule::path, path)?
+        .next()
+        .and_then(|path| path.into_inner().next())
+        .expect("Path always has a body");
 
-            Rule::second => {
-                right = parse_side(pair, &result)?;
+    let mut current = value;
+    for part in pair.into_inner() {
+        if part.as_rule() == Rule::field && current.is_null() {
+            *current = Value::Object(Map::new());
+        }
+        current = match (part.as_rule(), current) {
+            (Rule::field, Value::Object(map)) => map.entry(part.as_str()).or_insert(Value::Null),
+            (Rule::index, Value::Array(items)) => {
+                let index = part.as_str().parse::<usize>().unwrap_or(usize::MAX);
+                match items.get_mut(index) {
+                    Some(item) => item,
+                    None => return Err(build_pest_error(part, "No element at this index")),
+                }
             }
-            _ => {}
+            (Rule::field, _) => return Err(build_pest_error(part, "Not an object")),
+            _ => return Err(build_pest_error(part, "Not an array")),
         };
     }
-    Ok((left, right))
+    *current = new;
+    Ok(())
+}
+
+pub fn check_path(path: &str) -> Result<(), Error<Rule>> {
+    ConditionParser::parse(Rule::path, path).map(|_| ())
+}
+
+// Only checks the syntax, used to report bad conditions before running
+pub fn check_condition(condition: &str) -> Result<(), Error<Rule>> {
+    parse_condition(condition).map(|_| ())
 }
 
 pub fn evaluate_if_statement(
     condition: String,
     job_result: &serde_json::Value,
 ) -> Result<bool, Error<Rule>> {
-    let mut parsed = ConditionParser::parse(Rule::expression, &condition)?;
-
-    let Some(condition) = parsed
-        .clone()
-        .flatten()
-        .find(|pair| pair.as_rule() == Rule::condition)
-    else {
-        return Err(build_pest_error(
-            parsed.next().expect("Has inside"),
-            "Could not match condition",
-        ));
-    };
+    evaluate_or(parse_condition(&condition)?, job_result)
+}
 
-    let whole_thing = parsed.next().expect("Has inside");
+// Setters ------------------------------
 
-    let possible_num_error = build_pest_error(whole_thing.clone(), "Could not parse a number");
+#[derive(Debug, Clone)]
+pub enum SetterOp {
+    Set(String, String), // The field and the expression giving its value
+    Literal(String, Value),
+    Remove(String),
+    Rename(String, String),
+}
 
-    match condition
-        .clone()
-        .into_inner()
-        .next()
-        .expect("Has inner")
-        .as_rule()
-    {
-        Rule::equal => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            return Ok(left == right);
-        }
+fn target_name(pair: Pair<Rule>) -> String {
+    pair.as_str().trim_start_matches('.').to_owned()
+}
 
-        Rule::not_equal => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            return Ok(left != right);
+// Labels written as `key: value` before setters had expressions are still a
+// single literal
+pub fn parse_setter(label: &str) -> Result<Vec<SetterOp>, Error<Rule>> {
+    let setter = match ConditionParser::parse(Rule::setter, label) {
+        Ok(mut setter) => setter.next().expect("Setter always has a root pair"),
+        Err(e) => {
+            let Some((key, value)) = label.split_once(':') else {
+                return Err(e);
+            };
+            let value = safe_parse_to_value(value.trim());
+            return Ok(vec![SetterOp::Literal(key.trim().to_owned(), value)]);
         }
+    };
 
-        Rule::less_than => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            let left = left.as_f64().ok_or(possible_num_error.clone())?;
-            let right = right.as_f64().ok_or(possible_num_error.clone())?;
-            return Ok(left < right);
-        }
+    let mut ops = Vec::new();
+    for statement in setter.into_inner() {
+        let mut targets = statement
+            .clone()
+            .into_inner()
+            .filter(|p| p.as_rule() == Rule::set_target)
+            .map(target_name);
+        let op = match statement.as_rule() {
+            Rule::assignment => {
+                let expression = statement
+                    .clone()
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::or_expr)
+                    .map(|p| p.as_str().to_owned())
+                    .unwrap_or_default();
+                SetterOp::Set(targets.next().unwrap_or_default(), expression)
+            }
+            Rule::remove_field => SetterOp::Remove(targets.next().unwrap_or_default()),
+            Rule::rename_field => {
+                let from = targets.next().unwrap_or_default();
+                SetterOp::Rename(from, targets.next().unwrap_or_default())
+            }
+            _ => continue,
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
 
-        Rule::less_than_eq_to => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            let left = left.as_f64().ok_or(possible_num_error.clone())?;
-            let right = right.as_f64().ok_or(possible_num_error.clone())?;
-            return Ok(left <= right);
+// Runs a setter's statements in order, each one sees what the ones before did
+pub fn apply_setter(ops: &[SetterOp], mut payload: Map<String, Value>) -> Result<Map<String, Value>, Error<Rule>> {
+    for op in ops {
+        match op {
+            SetterOp::Set(field, expression) => {
+                let value = evaluate_expression(expression, &Value::Object(payload.clone()))?;
+                payload.insert(field.clone(), value);
+            }
+            SetterOp::Literal(field, value) => {
+                payload.insert(field.clone(), value.clone());
+            }
+            SetterOp::Remove(field) => {
+                payload.remove(field);
+            }
+            SetterOp::Rename(from, to) => {
+                if let Some(value) = payload.remove(from) {
+                    payload.insert(to.clone(), value);
+                }
+            }
         }
-        Rule::greater_than => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            let left = left.as_f64().ok_or(possible_num_error.clone())?;
-            let right = right.as_f64().ok_or(possible_num_error.clone())?;
-            return Ok(left > right);
+    }
+    Ok(payload)
+}
+
+// What an expression like `.errors.length - 1` or `.name + ".cpp"` comes to
+pub fn evaluate_expression(expression: &str, value: &Value) -> Result<Value, Error<Rule>> {
+    evaluate_value(parse_condition(expression)?, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn holds(condition: &str, payload: &Value) -> bool {
+        evaluate_if_statement(condition.to_owned(), payload).unwrap()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_applies_to_one_comparison() {
+        let payload = json!({ "a": true, "b": false, "c": true });
+        assert!(holds(".a || .b && .b", &payload));
+        assert!(!holds("(.a || .b) && .b", &payload));
+        assert!(holds("!.b && .c", &payload));
+        assert!(!holds("!(.b || .c)", &payload));
+        assert!(holds("!!.a", &payload));
+    }
+
+    #[test]
+    fn arithmetic_binds_tighter_than_comparisons() {
+        let payload = json!({ "x": 2, "y": 3 });
+        assert!(holds(".x + .y * 2 == 8", &payload));
+        assert!(holds("(.x + .y) * 2 == 10", &payload));
+        assert!(holds(".y - .x - 1 == 0", &payload));
+        assert!(holds(".y % .x == 1 && .y / .x == 1.5", &payload));
+        assert_eq!(evaluate_expression(".x * .y", &payload).unwrap(), json!(6));
+        assert_eq!(evaluate_expression(".y / .x", &payload).unwrap(), json!(1.5));
+    }
+
+    #[test]
+    fn conditions_have_to_give_a_boolean() {
+        let payload = json!({ "count": 3, "name": "main" });
+        for condition in [".count", ".name", ".count + 1", ".missing"] {
+            let error = evaluate_if_statement(condition.to_owned(), &payload).unwrap_err();
+            assert!(error.to_string().contains("Expected a boolean"), "{}", condition);
         }
+        assert!(evaluate_if_statement(".count > .name".to_owned(), &payload).is_err());
+    }
 
-        Rule::greater_than_eq_to => {
-            let (left, right) = parse_value_pair(whole_thing, job_result.clone())?;
-            let left = left.as_f64().ok_or(possible_num_error.clone())?;
-            let right = right.as_f64().ok_or(possible_num_error.clone())?;
-            return Ok(left >= right);
+    #[test]
+    fn paths_index_arrays_and_read_lengths() {
+        let payload = json!({
+            "errors": [{ "kind": "error", "notes": ["a", "b"] }, { "kind": "warning" }],
+            "sized": { "length": 7 },
+            "file": "main.cpp",
+        });
+        assert!(holds(".errors.length == 2", &payload));
+        assert!(holds(".errors[0].kind == error && .errors[1].kind == 'warning'", &payload));
+        assert!(holds(".errors[0].notes[1] == \"b\"", &payload));
+        assert!(holds(".errors[5] == null && .errors[1].notes == null", &payload));
+        assert!(holds(".file.length == 8", &payload));
+        // An object's own length field wins
+        assert!(holds(".sized.length == 7", &payload));
+    }
+
+    #[test]
+    fn literals_keep_their_type() {
+        let payload = json!({ "text": "say \"hi\"", "flag": false, "nothing": null, "n": 1 });
+        assert!(holds(r#".text == "say \"hi\"""#, &payload));
+        assert!(holds(".flag == false && .nothing == null", &payload));
+        assert!(holds(".n == 1.0 && .n != \"1\"", &payload));
+        assert_eq!(evaluate_expression("'a' + .n", &payload).unwrap(), json!("a1"));
+    }
+
+    #[test]
+    fn broken_conditions_are_refused_before_running() {
+        for condition in [".a &&", "(.a", ".a == == 1", ".a[x]", "&& .a", ".a ! .b"] {
+            assert!(check_condition(condition).is_err(), "{}", condition);
         }
+        assert!(check_condition("!(.a.length > 0) || .b[0] == 'x'").is_ok());
+    }
 
-        _ => {}
-    };
+    fn run_setter(label: &str, payload: Value) -> Value {
+        let Value::Object(payload) = payload else { panic!("Payload has to be an object") };
+        Value::Object(apply_setter(&parse_setter(label).unwrap(), payload).unwrap())
+    }
+
+    #[test]
+    fn setters_remove_and_rename_in_order() {
+        let payload = json!({ "errors": [1, 2], "code": "x", "keep": true });
+        let result = run_setter("left = .errors.length; remove errors; rename .code -> fix", payload);
+        assert_eq!(result, json!({ "left": 2, "fix": "x", "keep": true }));
+
+        // A later statement sees the renamed field, and missing fields are left alone
+        let label = "rename a -> b; total = .b + 1; remove missing; rename gone -> here";
+        let result = run_setter(label, json!({ "a": 1 }));
+        assert_eq!(result, json!({ "b": 1, "total": 2 }));
+
+        let ops = parse_setter("remove errors rename code -> fix").unwrap();
+        assert!(matches!(
+            &ops[..],
+            [SetterOp::Remove(a), SetterOp::Rename(b, c)] if a == "errors" && b == "code" && c == "fix"
+        ));
+    }
+
+    #[test]
+    fn old_key_value_labels_set_one_literal() {
+        let ops = parse_setter("attempts: 3").unwrap();
+        assert!(matches!(&ops[..], [SetterOp::Literal(key, value)] if key == "attempts" && *value == json!(3)));
 
-    Err(build_pest_error(
-        parsed.next().expect("Has inside"),
-        "Could not match condition",
-    ))
+        let result = run_setter("mode: fast lane", json!({ "mode": "slow" }));
+        assert_eq!(result, json!({ "mode": "fast lane" }));
+
+        // A label that is neither still fails
+        assert!(parse_setter("just words").is_err());
+    }
+
+    #[test]
+    fn set_path_writes_along_the_path() {
+        let mut value = json!({ "result": { "items": [1, 2, 3], "keep": true } });
+        set_path(".result.items", &mut value, json!([2, 3])).unwrap();
+        assert_eq!(value, json!({ "result": { "items": [2, 3], "keep": true } }));
+
+        set_path(".stats.big", &mut value, json!(1)).unwrap();
+        assert_eq!(value["stats"], json!({ "big": 1 }));
+
+        set_path(".result.items[0]", &mut value, json!(9)).unwrap();
+        assert_eq!(value["result"]["items"], json!([9, 3]));
+    }
+
+    #[test]
+    fn set_path_refuses_what_it_cant_write() {
+        let mut value = json!({ "list": [1], "name": "x" });
+        assert!(set_path(".list[3]", &mut value, json!(0)).is_err());
+        assert!(set_path(".name.first", &mut value, json!(0)).is_err());
+        assert_eq!(value, json!({ "list": [1], "name": "x" }));
+    }
 }
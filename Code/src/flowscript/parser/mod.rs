@@ -1,6 +1,6 @@
 pub mod conditional;
 mod create_error;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, time::Duration};
 
 use pest::{iterators::Pair, Parser};
 use pest_derive::Parser;
@@ -9,16 +9,226 @@ use pest_derive::Parser;
 #[grammar = "./flowscript/grammar/grammar.pest"]
 pub struct FlowscriptParser;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum NodeDef {
     Input,               // Done
-    Task,                // Done
+    Task(TaskOptions),   // Done
     IfStatement(String), // Done
     Count,               // Done
-    Multi,               // Done
+    Multi(MergePolicy),  // Done
     Switch(String),      // Done
     Match(String),
     Setter(String),
+    Loop { condition: String, max: usize },
+    ForEach(ForEachDef),
+    Exec(ExecDef),
+}
+
+impl NodeDef {
+    // The `kind` attribute that makes this node, also shown in traces and the debugger
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NodeDef::Input => "input",
+            NodeDef::Task(_) => "task",
+            NodeDef::IfStatement(_) => "if",
+            NodeDef::Count => "count",
+            NodeDef::Multi(_) => "multi",
+            NodeDef::Switch(_) => "switch",
+            NodeDef::Match(_) => "match",
+            NodeDef::Setter(_) => "set",
+            NodeDef::Loop { .. } => "loop",
+            NodeDef::ForEach(_) => "for_each",
+            NodeDef::Exec(_) => "exec",
+        }
+    }
+}
+
+// Every value the `kind` attribute can have
+pub const NODE_KINDS: &[&str] = &[
+    "task", "if", "count", "multi", "switch", "match", "set", "loop", "for_each", "exec",
+];
+
+// What each kind does and the attributes it reads, for prompts and editors
+pub fn kind_help(kind: &str) -> &'static str {
+    match kind {
+        "task" => "Runs the job the node is named after and merges its result into the payload. retries, backoff and timeout are optional. A job that times out keeps running in the background and its result is dropped, Approve and ApplyFix can't time out",
+        "if" => "label is a condition like `.errors.length > 0`, continues on the edge labeled true or the one labeled false",
+        "count" => "Sets __count to the number of times the node was reached",
+        "multi" => "Runs every kind=\"branch\" edge on the payload and merges the results, merge is last_wins, error or nest",
+        "switch" => "label is a field, continues on the edge labeled with its value or else the unlabeled edge",
+        "match" => "label is a field, runs the edge labeled with its value and then continues on the unlabeled edge",
+        "set" => "label changes the payload, like `left = .errors.length - 1; remove errors; rename code -> fix`",
+        "loop" => "Runs the kind=\"body\" edge while the label condition holds, at most max times (default 10)",
+        "for_each" => "Runs the kind=\"body\" edge for every element of the array in over, named by as (default item). mode is map, filter (label is the condition) or reduce, the result goes in into, a field or path that a filter defaults to over",
+        "exec" => "Runs command with args, `{.field}` in args is filled in from the payload. Adds stdout, stderr and exit_code (null if killed by a signal), or puts them at into, a field or path",
+        _ => "",
+    }
+}
+
+// The kind each Graphviz shape stood for before nodes had a `kind`
+pub fn legacy_kind(shape: &str) -> Option<&'static str> {
+    match shape {
+        "rectangle" => Some("if"),
+        "component" => Some("count"),
+        "diamond" => Some("switch"),
+        "Mdiamond" => Some("match"),
+        "point" => Some("multi"),
+        "cds" => Some("set"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ForEachMode {
+    Map,    // Collects what the body produced for every element
+    Filter, // Keeps the elements the condition holds for
+    Reduce, // Threads an accumulator through the body
+}
+
+impl ForEachMode {
+    pub fn from_name(name: &str) -> Option<ForEachMode> {
+        match name {
+            "map" => Some(ForEachMode::Map),
+            "filter" => Some(ForEachMode::Filter),
+            "reduce" => Some(ForEachMode::Reduce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ForEachDef {
+    pub mode: ForEachMode,
+    pub over: String,              // Path to the array, like `.errors`
+    pub item: String,              // Field each element is put in
+    pub into: String,              // Path the result is written to, like `.errors`
+    pub condition: Option<String>, // Only for filter
+    pub init: String,              // Starting accumulator for reduce
+    pub parallel: bool,
+}
+
+// A process run by an exec node, `{...}` in the args is filled in from the payload
+#[derive(Debug, Clone)]
+pub struct ExecDef {
+    pub command: String,
+    pub args: Vec<String>,
+    pub into: Option<String>, // Path the output is written to, merged into the payload without one
+    pub options: TaskOptions,
+}
+
+// Splits `args` like a shell would, quotes keep spaces in one argument and so
+// do `{...}` placeholders
+fn split_args(text: &str) -> Result<Vec<String>, &'static str> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    let mut in_placeholder = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (_, '{' | '}') if chars.peek() == Some(&c) => {
+                chars.next();
+                current.get_or_insert_with(String::new).extend([c, c]);
+            }
+            (_, '{' | '}') => {
+                in_placeholder = c == '{';
+                current.get_or_insert_with(String::new).push(c);
+            }
+            (_, c) if in_placeholder => current.get_or_insert_with(String::new).push(c),
+            (None, c) if c.is_whitespace() => {
+                args.extend(current.take());
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                let escaped = chars.next().ok_or("Args can't end with a backslash")?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            (_, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("Args have a quote that is never closed");
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskOptions {
+    pub retries: usize,
+    pub backoff: Duration, // Wait before the first retry, doubled for every retry after
+    pub timeout: Option<Duration>,
+}
+
+// Accepts "500ms", "30s", "2m" or a bare number of seconds
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let number: f64 = number.trim().parse().ok().filter(|n: &f64| *n >= 0.0)?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+// What the other kinds read, a node without a kind or shape that sets one most likely forgot its kind.
+// Anything else is only for drawing
+const OTHER_KIND_ATTRIBUTES: &[&str] = &[
+    "label", "merge", "max", "over", "as", "mode", "into", "init", "parallel", "command", "args",
+];
+
+fn parse_task_options(attrs: &HashMap<String, String>) -> Result<TaskOptions, &'static str> {
+    let retries = match attrs.get("retries") {
+        Some(retries) => retries.parse().map_err(|_| "Retries must be a number")?,
+        None => 0,
+    };
+    let backoff = match attrs.get("backoff") {
+        Some(backoff) => parse_duration(backoff).ok_or("Backoff must be a duration like 500ms or 2s")?,
+        None => Duration::ZERO,
+    };
+    let timeout = match attrs.get("timeout") {
+        Some(timeout) => {
+            Some(parse_duration(timeout).ok_or("Timeout must be a duration like 500ms or 30s")?)
+        }
+        None => None,
+    };
+    Ok(TaskOptions {
+        retries,
+        backoff,
+        timeout,
+    })
+}
+
+// Loops without a max attribute stop after this many iterations
+pub const DEFAULT_LOOP_MAX: usize = 10;
+
+// How a multi node combines results that set the same key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergePolicy {
+    LastWins,
+    Error,
+    NestByBranch,
+}
+
+impl MergePolicy {
+    pub fn from_name(name: &str) -> Option<MergePolicy> {
+        match name {
+            "last_wins" => Some(MergePolicy::LastWins),
+            "error" => Some(MergePolicy::Error),
+            "nest" => Some(MergePolicy::NestByBranch),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -28,6 +238,8 @@ pub enum ConnectionType {
     SwitchBranch(String),
     MatchBranch(String),
     MultiOut,
+    Body, // A "body" label coming out of a loop or for each node
+    OnError, // Taken when a task fails
 }
 
 #[derive(Debug, Clone)]
@@ -35,12 +247,59 @@ pub struct ConnectionDef {
     pub from: String,
     pub to: String,
     pub c_type: ConnectionType,
+    pub span: Range<usize>,
+    pub attributes: HashMap<String, String>, // Including the edge defaults it picked up
 }
 
 #[derive(Debug)]
 pub struct Defs {
+    pub name: Option<String>,
+    pub span: Range<usize>,
     pub variables: HashMap<String, NodeDef>,
     pub connections: Vec<ConnectionDef>,
+    // Where each node was defined, or first mentioned if it was defined implicitly
+    pub spans: HashMap<String, Range<usize>>,
+    pub attributes: HashMap<String, String>,
+    // Attributes of each node, with the `node [..]` defaults it picked up
+    pub node_attributes: HashMap<String, HashMap<String, String>>,
+    pub subgraphs: Vec<Defs>,
+    pub imports: Vec<(String, Range<usize>)>, // Paths as written, with their spans
+}
+
+// Only quotes and backslashes are unescaped, the rest is left for the node to read
+fn unescape_attribute(text: &str) -> String {
+    text.replace("\\\n", "")
+        .replace("\\\"", "\"")
+        .replace("\\\\", "\\")
+}
+
+// The text of an ID as written, without quotes or html brackets. Escapes are
+// kept so `fmt` can write it back unchanged.
+pub fn raw_id(pair: Pair<Rule>) -> String {
+    let Some(id) = pair.into_inner().flatten().find(|p| {
+        matches!(
+            p.as_rule(),
+            Rule::quoted_id | Rule::html_id | Rule::numeral_id | Rule::plain_id
+        )
+    }) else {
+        return String::new();
+    };
+    match id.as_rule() {
+        // "a" + "b" is one string
+        Rule::quoted_id => id
+            .into_inner()
+            .flatten()
+            .filter(|p| p.as_rule() == Rule::string_content)
+            .map(|p| p.as_str())
+            .collect(),
+        Rule::html_id => id.into_inner().next().map(|p| p.as_str()).unwrap_or_default().to_owned(),
+        _ => id.as_str().to_owned(),
+    }
+}
+
+// The value of an ID, what nodes and attributes are looked up by
+pub fn id_value(pair: Pair<Rule>) -> String {
+    unescape_attribute(&raw_id(pair))
 }
 
 fn process_attribute_pair(pair: Pair<'_, Rule>) -> (String, String) {
@@ -53,276 +312,616 @@ fn process_attribute_pair(pair: Pair<'_, Rule>) -> (String, String) {
 
     pair.into_inner().for_each(|pair| match pair.as_rule() {
         Rule::attribute_name => {
-            key = pair.as_str().to_owned();
+            key = id_value(pair);
         }
         Rule::attribute_value => {
-            value = pair.as_str().to_owned();
+            value = id_value(pair);
         }
         _ => {}
     });
     (key, value)
 }
 
-fn get_attributes(pair: Pair<'_, Rule>) -> HashMap<String, String> {
-    // TODO: Fix typesafety?
-    if pair.as_rule() != Rule::attributes {
-        panic!("Error: Expected attributes");
-    };
-
+// Every attribute list of a statement, `a [x=1][y=2]` is the same as `a [x=1, y=2]`
+pub fn get_attributes(pair: Pair<'_, Rule>) -> HashMap<String, String> {
     let mut attributes_map = HashMap::new();
 
-    pair.into_inner().for_each(|pair| match pair.as_rule() {
-        Rule::attribute => {
-            let attr = process_attribute_pair(pair);
-            attributes_map.insert(attr.0, attr.1);
-        }
-        _ => {}
-    });
+    pair.into_inner()
+        .filter(|p| p.as_rule() == Rule::attributes)
+        .flat_map(|p| p.into_inner())
+        .for_each(|pair| {
+            if pair.as_rule() == Rule::attribute {
+                let attr = process_attribute_pair(pair);
+                attributes_map.insert(attr.0, attr.1);
+            }
+        });
     attributes_map
 }
 
-fn process_var_def(pair: Pair<Rule>) -> Result<(String, NodeDef), pest::error::Error<Rule>> {
-    if pair.as_rule() != Rule::variable_def {
-        panic!("Error: Expected variable_def"); // Should never happen
-    };
-
-    let mut name = String::new();
-
-    let mut attributes: Option<HashMap<String, String>> = None;
+// Each attribute a statement sets, by name
+fn attribute_pairs<'a>(pair: &Pair<'a, Rule>) -> impl Iterator<Item = (String, Pair<'a, Rule>)> {
+    pair.clone()
+        .into_inner()
+        .filter(|p| p.as_rule() == Rule::attributes)
+        .flat_map(|p| p.into_inner())
+        .filter(|p| p.as_rule() == Rule::attribute)
+        .map(|p| (find_id(&p, Rule::attribute_name), p))
+}
 
-    for single in pair.clone().into_inner() {
-        match single.as_rule() {
-            Rule::variable => {
-                name = single.as_str().to_owned();
-            }
-            Rule::attributes => {
-                attributes = Some(get_attributes(single));
-            }
-            _ => {}
-        }
-    }
+fn has_attributes(pair: &Pair<Rule>) -> bool {
+    pair.clone().into_inner().any(|p| p.as_rule() == Rule::attributes)
+}
 
+// `pair` is the statement that defined the node, errors point at it. `set_at` has the attribute
+// each of the node's own attributes came from
+fn process_var_def<'a>(
+    pair: Pair<'a, Rule>,
+    name: String,
+    attributes: Option<HashMap<String, String>>,
+    set_at: HashMap<String, Pair<'a, Rule>>,
+) -> Result<(String, NodeDef), pest::error::Error<Rule>> {
     if name == "input" {
         return Ok((name, NodeDef::Input));
     }
 
-    match attributes {
-        Some(attrs) => {
-            // Other node types must have a shape
-            if !attrs.contains_key("shape") {
+    let attrs = attributes.unwrap_or_default();
+    let kind = match attrs.get("kind") {
+        Some(kind) => kind.as_str(),
+        // Scripts written before `kind` picked the type with a Graphviz shape,
+        // any other shape is only for drawing
+        None => match attrs.get("shape") {
+            Some(shape) => legacy_kind(shape).unwrap_or("task"),
+            None => match OTHER_KIND_ATTRIBUTES.iter().find(|key| attrs.contains_key(**key)) {
+                None => "task",
+                Some(key) => {
+                    return Err(create_error::build_pest_error(
+                        set_at.get(*key).cloned().unwrap_or(pair),
+                        &format!("{} is for a kind other than task, set the node's kind", key),
+                    ))
+                }
+            },
+        },
+    };
+
+    match kind {
+        "task" => {
+            return match parse_task_options(&attrs) {
+                Ok(options) => Ok((name, NodeDef::Task(options))),
+                Err(msg) => Err(create_error::build_pest_error(pair, msg)),
+            };
+        }
+        "if" => {
+            let Some(condition) = attrs.get("label") else {
                 return Err(create_error::build_pest_error(
                     pair,
-                    "Non-task node requires a shape definition",
+                    "If statement requires a condition",
                 ));
-            }
-            let shape = attrs.get("shape").unwrap();
+            };
+            return Ok((name, NodeDef::IfStatement(condition.to_owned())));
+        }
+        "count" => return Ok((name, NodeDef::Count)),
 
-            match shape.as_str() {
-                "rectangle" => {
-                    let Some(condition) = attrs.get("label") else {
-                        return Err(create_error::build_pest_error(
-                            pair,
-                            "If statement requires a condition",
-                        ));
-                    };
-                    return Ok((name, NodeDef::IfStatement(condition.to_owned())));
-                }
-                "component" => return Ok((name, NodeDef::Count)),
+        "switch" => {
+            let Some(condition) = attrs.get("label") else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Switch statement requires a field to switch on",
+                ));
+            };
+            return Ok((name, NodeDef::Switch(condition.to_owned())));
+        }
 
-                "diamond" => {
-                    let Some(condition) = attrs.get("label") else {
-                        return Err(create_error::build_pest_error(
-                            pair,
-                            "Switch statement requires a field to switch on",
-                        ));
-                    };
-                    return Ok((name, NodeDef::Switch(condition.to_owned())));
-                }
+        "match" => {
+            let Some(label) = attrs.get("label") else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Match statement requires a field to match with",
+                ));
+            };
+            return Ok((name, NodeDef::Match(label.to_owned())));
+        }
 
-                "Mdiamond" => {
-                    let Some(label) = attrs.get("label") else {
+        "multi" => {
+            let merge = attrs.get("merge").map(|m| m.as_str()).unwrap_or("last_wins");
+            let Some(policy) = MergePolicy::from_name(merge) else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Merge must be one of last_wins, error or nest",
+                ));
+            };
+            return Ok((name, NodeDef::Multi(policy)));
+        }
+
+        "loop" => {
+            let Some(condition) = attrs.get("label") else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Loop requires a condition to keep going",
+                ));
+            };
+            let max = match attrs.get("max") {
+                Some(max) => match max.parse::<usize>() {
+                    Ok(max) if max > 0 => max,
+                    _ => {
                         return Err(create_error::build_pest_error(
                             pair,
-                            "Match statement requires a field to match with",
-                        ));
-                    };
-                    return Ok((name, NodeDef::Match(label.to_owned())));
-                }
+                            "Loop max must be a positive number",
+                        ))
+                    }
+                },
+                None => DEFAULT_LOOP_MAX,
+            };
+            return Ok((
+                name,
+                NodeDef::Loop {
+                    condition: condition.to_owned(),
+                    max,
+                },
+            ));
+        }
 
-                "point" => return Ok((name, NodeDef::Multi)),
+        "for_each" => {
+            let mode = attrs.get("mode").map(|m| m.as_str()).unwrap_or("map");
+            let Some(mode) = ForEachMode::from_name(mode) else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Mode must be one of map, filter or reduce",
+                ));
+            };
+            let Some(over) = attrs.get("over") else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "For each requires an array to go over",
+                ));
+            };
+            let condition = attrs.get("label").cloned();
+            if mode == ForEachMode::Filter && condition.is_none() {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Filter requires a condition",
+                ));
+            }
 
-                "cds" => {
-                    let Some(label) = attrs.get("label") else {
-                        return Err(create_error::build_pest_error(
-                            pair,
-                            "Setter requires a field to set",
-                        ));
-                    };
-                    return Ok((name, NodeDef::Setter(label.to_owned())));
-                }
-                _ => {}
+            let over = match over.starts_with('.') {
+                true => over.to_owned(),
+                false => format!(".{}", over),
+            };
+            let field = |key: &str, default: &str| {
+                attrs.get(key).cloned().unwrap_or(default.to_owned())
+            };
+            // A filter narrows the array in place by default
+            let into = match mode {
+                ForEachMode::Filter => over.as_str(),
+                ForEachMode::Map => ".results",
+                ForEachMode::Reduce => ".result",
+            };
+            let into = match field("into", into) {
+                into if into.starts_with('.') => into,
+                into => format!(".{}", into),
+            };
+            if let Err(e) = conditional::check_path(&into) {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    &format!("into must be a field or a path like .result.items: {}", e.variant.message()),
+                ));
+            }
+            let def = ForEachDef {
+                mode,
+                into,
+                over,
+                item: field("as", "item"),
+                condition,
+                init: field("init", "null"),
+                parallel: field("parallel", "false") == "true",
+            };
+            return Ok((name, NodeDef::ForEach(def)));
+        }
+
+        "exec" => {
+            let Some(command) = attrs.get("command").filter(|c| !c.trim().is_empty()) else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Exec node requires a command to run",
+                ));
+            };
+            let args = match split_args(attrs.get("args").map(|a| a.as_str()).unwrap_or_default()) {
+                Ok(args) => args,
+                Err(msg) => return Err(create_error::build_pest_error(pair, msg)),
+            };
+            let options = match parse_task_options(&attrs) {
+                Ok(options) => options,
+                Err(msg) => return Err(create_error::build_pest_error(pair, msg)),
+            };
+            let into = match attrs.get("into") {
+                Some(into) if into.starts_with('.') => Some(into.to_owned()),
+                Some(into) => Some(format!(".{}", into)),
+                None => None,
+            };
+            if let Some(Err(e)) = into.as_deref().map(conditional::check_path) {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    &format!("into must be a field or a path like .build.output: {}", e.variant.message()),
+                ));
             }
+            let def = ExecDef {
+                command: command.trim().to_owned(),
+                args,
+                into,
+                options,
+            };
+            return Ok((name, NodeDef::Exec(def)));
         }
-        None => {
-            return Ok((name, NodeDef::Task));
+
+        "set" => {
+            let Some(label) = attrs.get("label") else {
+                return Err(create_error::build_pest_error(
+                    pair,
+                    "Setter requires a field to set",
+                ));
+            };
+            return Ok((name, NodeDef::Setter(label.to_owned())));
         }
+        _ => {}
+    }
+    Err(create_error::build_pest_error(
+        pair,
+        &format!("Unknown node kind {}, expected one of {}", kind, NODE_KINDS.join(", ")),
+    ))
+}
+
+// An edge `kind` says what the edge is without relying on its style
+fn edge_kind(kind: &str) -> Option<ConnectionType> {
+    match kind {
+        "next" => Some(ConnectionType::Default),
+        "branch" => Some(ConnectionType::MultiOut),
+        "body" => Some(ConnectionType::Body),
+        "on_error" => Some(ConnectionType::OnError),
+        _ => None,
+    }
+}
+
+pub const EDGE_KINDS: &[&str] = &["next", "branch", "body", "on_error"];
+
+pub fn edge_kind_help(kind: &str) -> &'static str {
+    match kind {
+        "next" => "Where the node continues, edges without a kind or label are next",
+        "branch" => "One of the branches a multi node runs",
+        "body" => "What a loop or for_each node runs every time round",
+        "on_error" => "Where a task or exec node goes when it fails, with __error set to {node, message}",
+        _ => "",
+    }
+}
+
+// How edges out of a shaped node are read, the style tells match branches and
+// multi outputs apart
+fn connection_type(attr_map: &HashMap<String, String>) -> ConnectionType {
+    if let Some(c_type) = attr_map.get("kind").and_then(|k| edge_kind(k)) {
+        return c_type;
+    }
+    let style = attr_map.get("style").map(|s| s.as_str());
+    if style == Some("on_error") {
+        return ConnectionType::OnError;
+    }
+
+    match attr_map.get("label") {
+        // TODO: Make sure not dashed
+        Some(label) if label == "true" => ConnectionType::IfResult(true),
+        Some(label) if label == "false" => ConnectionType::IfResult(false),
+        // If it is dashed, its a match branch
+        Some(label) if style == Some("dashed") => ConnectionType::MatchBranch(label.to_owned()),
+        Some(label) => ConnectionType::SwitchBranch(label.to_owned()),
+        None if style == Some("dashed") => ConnectionType::MultiOut,
+        // Other styles like bold only change how Graphviz draws the edge
+        None => ConnectionType::Default,
+    }
+}
+
+// How edges out of a node with a `kind` are read. The node already says what
+// its labels mean so styles are only for drawing.
+fn branch_type(from: &NodeDef, attr_map: &HashMap<String, String>) -> ConnectionType {
+    if let Some(c_type) = attr_map.get("kind").and_then(|k| edge_kind(k)) {
+        return c_type;
+    }
+    if attr_map.get("style").is_some_and(|s| s == "on_error") {
+        return ConnectionType::OnError;
+    }
+
+    let Some(label) = attr_map.get("label") else {
+        return ConnectionType::Default;
     };
-    Err(create_error::build_pest_error(pair, "Unknown node type"))
+    match from {
+        NodeDef::IfStatement(_) if label == "true" => ConnectionType::IfResult(true),
+        NodeDef::IfStatement(_) if label == "false" => ConnectionType::IfResult(false),
+        NodeDef::Switch(_) => ConnectionType::SwitchBranch(label.to_owned()),
+        NodeDef::Match(_) => ConnectionType::MatchBranch(label.to_owned()),
+        NodeDef::Loop { .. } | NodeDef::ForEach(_) if label == "body" => ConnectionType::Body,
+        _ => ConnectionType::Default,
+    }
 }
 
-fn process_connection_def(pair: Pair<Rule>) -> Result<ConnectionDef, pest::error::Error<Rule>> {
+// The nodes on each side of every `->`, a group like `{ b c }` is one operand
+fn edge_operands(pair: Pair<Rule>) -> Vec<Vec<Pair<Rule>>> {
+    pair.into_inner()
+        .filter_map(|operand| match operand.as_rule() {
+            Rule::variable => Some(vec![operand]),
+            Rule::node_group => Some(
+                operand
+                    .into_inner()
+                    .filter(|p| p.as_rule() == Rule::variable)
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+// Chains are expanded into a connection for every pair, all with the same type
+fn process_connection_def(
+    pair: Pair<Rule>,
+    edge_defaults: &HashMap<String, String>,
+) -> Result<Vec<ConnectionDef>, pest::error::Error<Rule>> {
     if pair.as_rule() != Rule::connection_def {
         panic!("Expected connection definition") // Should never happen
     }
 
-    let variable_mentions: Vec<Pair<Rule>> = pair
-        .clone()
-        .into_inner()
-        .flatten()
-        .filter(|p| p.as_rule() == Rule::variable)
-        .collect();
-
-    if variable_mentions.len() != 2 {
+    let mut attr_map = edge_defaults.clone();
+    attr_map.extend(get_attributes(pair.clone()));
+    if let Some(kind) = attr_map.get("kind").filter(|k| edge_kind(k).is_none()) {
         return Err(create_error::build_pest_error(
             pair,
-            "Connection definition must have exactly two variables",
+            &format!("Unknown edge kind {}, expected one of {}", kind, EDGE_KINDS.join(", ")),
         ));
     }
+    let c_type = connection_type(&attr_map);
+    let span = span_of(&pair);
 
-    let from = variable_mentions[0].as_str().to_owned();
-    let to = variable_mentions[1].as_str().to_owned();
-
-    let has_attributes = pair
-        .clone()
-        .into_inner()
-        .flatten()
-        .find(|p| p.as_rule() == Rule::attributes);
-
-    match has_attributes {
-        Some(attrs) => {
-            let attr_map = get_attributes(attrs);
-            let maybe_label = attr_map.get("label");
-
-            match maybe_label {
-                Some(label) => {
-                    if label == "true" {
-                        // TODO: Make sure not dashed
-                        return Ok(ConnectionDef {
-                            from,
-                            to,
-                            c_type: ConnectionType::IfResult(true),
-                        });
-                    } else if label == "false" {
-                        // TODO: Make sure not dashed
-                        return Ok(ConnectionDef {
-                            from,
-                            to,
-                            c_type: ConnectionType::IfResult(false),
-                        });
-                    }
-
-                    let is_dashed = attr_map.get("style").unwrap_or(&"".to_owned()) == "dashed";
-                    // If it is dashed, its a match branch
-                    if is_dashed {
-                        return Ok(ConnectionDef {
-                            from,
-                            to,
-                            c_type: ConnectionType::MatchBranch(label.to_owned()),
-                        });
-                    } else {
-                        return Ok(ConnectionDef {
-                            from,
-                            to,
-                            c_type: ConnectionType::SwitchBranch(label.to_owned()),
-                        });
-                    }
-                }
-
-                // Must be a multi or a default
-                None => {
-                    let style = attr_map.get("style");
-                    // If style is "dashed" return multi
-                    match style {
-                        Some(s) => {
-                            if s == "dashed" {
-                                return Ok(ConnectionDef {
-                                    from,
-                                    to,
-                                    c_type: ConnectionType::MultiOut,
-                                });
-                            }
-                        }
-                        None => {
-                            return Ok(ConnectionDef {
-                                from,
-                                to,
-                                c_type: ConnectionType::Default,
-                            });
-                        }
-                    }
-                }
+    let operands = edge_operands(pair);
+    let mut conns = Vec::new();
+    for window in operands.windows(2) {
+        for from in &window[0] {
+            for to in &window[1] {
+                conns.push(ConnectionDef {
+                    from: id_value(from.clone()),
+                    to: id_value(to.clone()),
+                    span: span.clone(),
+                    c_type: c_type.clone(),
+                    attributes: attr_map.clone(),
+                });
             }
         }
-        None => {
-            return Ok(ConnectionDef {
-                from,
-                to,
-                c_type: ConnectionType::Default,
-            })
-        }
-    };
+    }
+    Ok(conns)
+}
 
-    Err(create_error::build_pest_error(
-        pair,
-        "Unknown connection type",
-    ))
+fn span_of(pair: &Pair<Rule>) -> Range<usize> {
+    pair.as_span().start()..pair.as_span().end()
 }
 
-pub fn extract_definitions(script: &str) -> Result<Defs, pest::error::Error<Rule>> {
-    let mut vars = HashMap::new();
-    let mut conns = Vec::new();
+fn find_id(pair: &Pair<Rule>, rule: Rule) -> String {
+    pair.clone()
+        .into_inner()
+        .find(|p| p.as_rule() == rule)
+        .map(id_value)
+        .unwrap_or_default()
+}
 
-    let parse = FlowscriptParser::parse(Rule::program, script)?;
+// Defaults set by `node [..]` and `edge [..]`, subgraphs start with the ones
+// their parent had when they were defined
+#[derive(Clone, Default)]
+struct Defaults {
+    node: HashMap<String, String>,
+    edge: HashMap<String, String>,
+}
 
-    let defs: Vec<Pair<Rule>> = parse
-        .flatten()
-        .filter(|pair| {
-            pair.as_rule() == Rule::variable_def || pair.as_rule() == Rule::connection_def
-        })
-        .collect();
+// Builds the definitions of one graph, either the digraph or a subgraph
+fn extract_graph<'a>(
+    name: Option<String>,
+    span: Range<usize>,
+    body: Pair<'a, Rule>,
+    mut defaults: Defaults,
+) -> Result<Defs, pest::error::Error<Rule>> {
+    let mut conns = Vec::new();
+    let mut spans = HashMap::new();
+    let mut attributes = HashMap::new();
+    let mut node_attributes: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut attribute_at: HashMap<String, HashMap<String, Pair<'a, Rule>>> = HashMap::new();
+    let mut subgraphs = Vec::new();
+    let mut imports = Vec::new();
+    // The statement that defined each node, in the order they were first seen
+    let mut defined_by: Vec<(String, Pair<'a, Rule>)> = Vec::new();
+
+    // Like Graphviz, node defaults only apply to nodes that don't exist yet
+    let mut create = |name: &String, pair: &Pair<'a, Rule>, defaults: &Defaults| {
+        if defined_by.iter().any(|(n, _)| n == name) {
+            return None;
+        }
+        defined_by.push((name.clone(), pair.clone()));
+        Some(defaults.node.clone()).filter(|attrs| !attrs.is_empty())
+    };
 
-    for def in defs {
+    for def in body.into_inner() {
         match def.as_rule() {
             Rule::variable_def => {
-                let var_def = process_var_def(def)?;
-                vars.insert(var_def.0, var_def.1);
+                let name = find_id(&def, Rule::variable);
+                if let Some(attrs) = create(&name, &def, &defaults) {
+                    node_attributes.insert(name.clone(), attrs);
+                }
+                // A node can be defined again to add attributes
+                if has_attributes(&def) {
+                    node_attributes
+                        .entry(name.clone())
+                        .or_default()
+                        .extend(get_attributes(def.clone()));
+                    attribute_at.entry(name.clone()).or_default().extend(attribute_pairs(&def));
+                }
+                spans.insert(name, span_of(&def));
             }
             Rule::connection_def => {
-                let con_def = process_connection_def(def)?;
-                conns.push(con_def);
+                for operand in edge_operands(def.clone()).into_iter().flatten() {
+                    let name = id_value(operand);
+                    if let Some(attrs) = create(&name, &def, &defaults) {
+                        node_attributes.insert(name.clone(), attrs);
+                    }
+                    spans.entry(name).or_insert(span_of(&def));
+                }
+                conns.extend(process_connection_def(def, &defaults.edge)?);
+            }
+            Rule::attribute_statement => {
+                let target = def
+                    .clone()
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::attribute_target)
+                    .map(|p| p.as_str().to_lowercase())
+                    .unwrap_or_default();
+                let attrs = get_attributes(def);
+                match target.as_str() {
+                    "node" => defaults.node.extend(attrs),
+                    "edge" => defaults.edge.extend(attrs),
+                    _ => attributes.extend(attrs),
+                }
+            }
+            Rule::graph_attribute => {
+                let key = find_id(&def, Rule::attribute_name);
+                let value = find_id(&def, Rule::attribute_value);
+                attributes.insert(key, value);
+            }
+            Rule::import_def => {
+                let span = span_of(&def);
+                let path = find_id(&def, Rule::import_path);
+                imports.push((path, span));
+            }
+            Rule::subgraph_def => {
+                let span = span_of(&def);
+                let name = find_id(&def, Rule::program_name);
+                let body = def
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::subgraph_body)
+                    .expect("Subgraph has a body");
+                subgraphs.push(extract_graph(Some(name), span, body, defaults.clone())?);
             }
             _ => {}
         }
     }
 
+    let mut vars = HashMap::new();
+    for (name, pair) in defined_by {
+        let attrs = node_attributes.get(&name).cloned();
+        let set_at = attribute_at.remove(&name).unwrap_or_default();
+        let (name, var) = process_var_def(pair, name, attrs, set_at)?;
+        vars.insert(name, var);
+    }
+
     // If input isn't included in vars, add it
     if !vars.contains_key("input") {
         vars.insert("input".to_owned(), NodeDef::Input);
     }
 
-    for conn in conns.iter() {
-        let from = conn.from.clone();
-        if !vars.contains_key(&from) {
-            vars.insert(from.clone(), NodeDef::Task);
-        }
-        let to = conn.to.clone();
-        if !vars.contains_key(&to) {
-            vars.insert(to.clone(), NodeDef::Task);
+    // Connection types are decided before all nodes are known, so loop bodies
+    // start out as switch branches
+    for conn in conns.iter_mut() {
+        let has_kind = node_attributes.get(&conn.from).is_some_and(|a| a.contains_key("kind"));
+        match vars.get(&conn.from) {
+            Some(from) if has_kind => conn.c_type = branch_type(from, &conn.attributes),
+            Some(NodeDef::Loop { .. } | NodeDef::ForEach(_))
+                if conn.c_type == ConnectionType::SwitchBranch("body".to_owned()) =>
+            {
+                conn.c_type = ConnectionType::Body
+            }
+            _ => {}
         }
     }
 
     Ok(Defs {
+        name,
+        span,
         variables: vars,
         connections: conns,
+        spans,
+        attributes,
+        node_attributes,
+        subgraphs,
+        imports,
     })
 }
+
+pub fn extract_definitions(script: &str) -> Result<Defs, pest::error::Error<Rule>> {
+    let program = FlowscriptParser::parse(Rule::program, script)?
+        .next()
+        .expect("Program always has a root pair");
+
+    let span = span_of(&program);
+    let mut name = None;
+    let mut body = None;
+    for pair in program.into_inner() {
+        match pair.as_rule() {
+            Rule::program_name => name = Some(id_value(pair)),
+            Rule::program_body => body = Some(pair),
+            _ => {}
+        }
+    }
+
+    extract_graph(name, span, body.expect("Program always has a body"), Defaults::default())
+}
+
+impl Defs {
+    // A graph attribute holding a comma separated list of field names
+    pub fn field_list(&self, key: &str) -> Option<Vec<String>> {
+        self.attributes.get(key).map(|value| {
+            value
+                .split(',')
+                .map(|field| field.trim().to_owned())
+                .filter(|field| !field.is_empty())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_too_long_to_hold_are_refused() {
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration("1000000000000000000000m"), None);
+        // The same number fits once it's in milliseconds
+        assert!(parse_duration("1000000000000000000000ms").is_some());
+    }
+
+    #[test]
+    fn attributes_without_a_kind_make_a_task_unless_another_kind_reads_them() {
+        let task = "digraph {\n  input -> Compile;\n  Compile [retries=\"2\", timeout=\"1s\"];\n}";
+        assert!(matches!(
+            extract_definitions(task).unwrap().variables["Compile"],
+            NodeDef::Task(_)
+        ));
+
+        let drawn = "digraph {\n  input -> Compile;\n  Compile [color=\"red\", fontsize=12];\n}";
+        assert!(matches!(
+            extract_definitions(drawn).unwrap().variables["Compile"],
+            NodeDef::Task(_)
+        ));
+
+        let forgot_kind = "digraph {\n  input -> check;\n  check [color=\"red\", label=\".x > 1\"];\n}";
+        let error = extract_definitions(forgot_kind).unwrap_err();
+        assert_eq!(error.line_col, pest::error::LineColLocation::Span((3, 23), (3, 37)));
+        assert!(error.variant.message().contains("label is for a kind other than task"));
+    }
+
+    #[test]
+    fn only_kinds_from_before_kind_existed_have_a_shape() {
+        let script = |shape: &str| format!("digraph {{\n  input -> step;\n  step [shape=\"{}\"];\n}}", shape);
+        assert!(matches!(
+            extract_definitions(&script("component")).unwrap().variables["step"],
+            NodeDef::Count
+        ));
+        // Loops and for each came with kind, their shapes are only for drawing
+        for shape in ["hexagon", "box3d"] {
+            assert!(matches!(
+                extract_definitions(&script(shape)).unwrap().variables["step"],
+                NodeDef::Task(_)
+            ));
+        }
+    }
+}
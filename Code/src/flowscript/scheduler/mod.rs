@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::system::{self, types::JobType};
+
+use super::{
+    nodes::{Next, NodeMap},
+    trace,
+};
+
+// Runs stop after this many nodes unless the script sets `max_steps`
+pub const DEFAULT_MAX_STEPS: usize = 100_000;
+
+// Why a run stopped early. These are never handed to on_error nodes.
+#[derive(Debug)]
+pub enum Stop {
+    Interrupted(String),
+    StepLimit(String, usize),
+    Quit(String), // From the debugger
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Interrupted(node) => write!(f, "Interrupted before {}", node),
+            Stop::StepLimit(node, max) => write!(
+                f,
+                "Stopped before {} after {} steps, set max_steps on the graph to allow more",
+                node, max
+            ),
+            Stop::Quit(node) => write!(f, "Stopped in the debugger before {}", node),
+        }
+    }
+}
+
+impl std::error::Error for Stop {}
+
+// Time includes whatever the node ran inside it, like the body of a loop
+#[derive(Default, Clone, Debug)]
+pub struct NodeMetrics {
+    pub runs: usize,
+    pub errors: usize,
+    pub time: Duration,
+}
+
+// Ctrl-C stops the run before the next node instead of killing it halfway
+// through a job, a second one quits as usual
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// Runs the jobs that prompt or write files for a script, see Context::run_hosted
+pub type Host<'h> = &'h mut dyn FnMut(JobType, Value) -> Result<Value>;
+
+// A job sent back to the thread that started the script
+struct HostCall {
+    job: JobType,
+    input: Value,
+    reply: mpsc::Sender<Result<Value>>,
+}
+
+// Scripts get as much stack as the main thread would have given them
+const SCRIPT_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+// Everything a run changes lives here rather than in the nodes, so a built
+// graph can be run again
+pub struct Context {
+    max_steps: usize,
+    hosted: fn(&JobType) -> bool, // Jobs handed to the host instead of the job system
+    steps: AtomicUsize,
+    counts: Mutex<HashMap<String, usize>>,
+    metrics: Mutex<HashMap<String, NodeMetrics>>,
+}
+
+impl Context {
+    pub fn new(max_steps: usize) -> Context {
+        Context {
+            max_steps,
+            hosted: JobType::runs_on_host,
+            steps: AtomicUsize::new(0),
+            counts: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs a graph from its input node, jobs that run on the host run as they are
+    pub fn run(&self, graph: &NodeMap, input: Value) -> Result<Value> {
+        self.run_hosted(graph, input, &mut system::run_job_here)
+    }
+
+    // Runs a graph from its input node on a thread of its own. Jobs that
+    // prompt or write files are handed to `host` on this thread one at a
+    // time, however many branches are running.
+    pub fn run_hosted(&self, graph: &NodeMap, input: Value, host: Host) -> Result<Value> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let previous = unsafe { libc::signal(libc::SIGINT, interrupt as *const () as libc::sighandler_t) };
+
+        let (calls, received) = mpsc::channel::<HostCall>();
+        let result = std::thread::scope(|scope| {
+            let script = std::thread::Builder::new()
+                .stack_size(SCRIPT_STACK_SIZE)
+                .spawn_scoped(scope, move || {
+                    Run {
+                        graph,
+                        scope: None,
+                        context: self,
+                        host: &calls,
+                    }
+                    .path("input", input)
+                })?;
+
+            // Ends once the script is done and its sender is dropped
+            for call in received {
+                let _ = call.reply.send(host(call.job, call.input));
+            }
+            script
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("The script panicked")))
+        });
+
+        unsafe {
+            libc::signal(libc::SIGINT, previous);
+        }
+        result
+    }
+
+    // Hands every job to the host, for runs that stand in for the jobs
+    pub fn host_every_job(&mut self) {
+        self.hosted = |_| true;
+    }
+
+    // The step that hit the limit didn't run
+    pub fn steps(&self) -> usize {
+        self.steps.load(Ordering::SeqCst).min(self.max_steps)
+    }
+
+    // Slowest first
+    pub fn metrics(&self) -> Vec<(String, NodeMetrics)> {
+        let mut metrics: Vec<_> = self.metrics.lock().unwrap().clone().into_iter().collect();
+        metrics.sort_by(|(a, x), (b, y)| y.time.cmp(&x.time).then(a.cmp(b)));
+        metrics
+    }
+
+    fn record(&self, node: String, time: Duration, failed: bool) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let metric = metrics.entry(node).or_default();
+        metric.runs += 1;
+        metric.time += time;
+        if failed {
+            metric.errors += 1;
+        }
+    }
+}
+
+// A run inside one graph, subgraphs get their own
+pub struct Run<'a> {
+    graph: &'a NodeMap,
+    scope: Option<&'a str>, // The subgraph being run
+    context: &'a Context,
+    host: &'a mpsc::Sender<HostCall>,
+}
+
+impl<'a> Run<'a> {
+    // Node names as the trace and debugger show them
+    fn name(&self, node: &str) -> String {
+        match self.scope {
+            Some(scope) => format!("{}.{}", scope, node),
+            None => node.to_owned(),
+        }
+    }
+
+    pub fn subgraph(&self, name: &'a str, graph: &'a NodeMap) -> Run<'a> {
+        Run {
+            graph,
+            scope: Some(name),
+            context: self.context,
+            host: self.host,
+        }
+    }
+
+    pub fn hosts(&self, job: &JobType) -> bool {
+        (self.context.hosted)(job)
+    }
+
+    // Runs a job on the thread that started the script and waits for it
+    pub fn host(&self, job: JobType, input: Value) -> Result<Value> {
+        let (reply, answer) = mpsc::channel();
+        self.host
+            .send(HostCall { job, input, reply })
+            .map_err(|_| anyhow!("Nothing is left to run {}", job.name()))?;
+        answer
+            .recv()
+            .map_err(|_| anyhow!("{} was never answered", job.name()))?
+    }
+
+    // How many times a count node has been reached in this run, this one included
+    pub fn count(&self, node: &str) -> usize {
+        let mut counts = self.context.counts.lock().unwrap();
+        let count = counts.entry(self.name(node)).or_default();
+        *count += 1;
+        *count
+    }
+
+    // Runs nodes one after the other from `start` until one has nowhere to
+    // go, giving back its result. Nodes with a body run it through here, so
+    // the stack only grows with how deeply graphs are nested.
+    pub fn path(&self, start: &str, input: Value) -> Result<Value> {
+        let parent = trace::current_step();
+        let result = self.drive(start, input);
+        trace::set_current_step(parent);
+        result
+    }
+
+    fn drive(&self, start: &str, input: Value) -> Result<Value> {
+        let mut at = start.to_owned();
+        let mut payload = input;
+        loop {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                return Err(Stop::Interrupted(self.name(&at)).into());
+            }
+            if self.context.steps.fetch_add(1, Ordering::SeqCst) >= self.context.max_steps {
+                return Err(Stop::StepLimit(self.name(&at), self.context.max_steps).into());
+            }
+
+            let node = self
+                .graph
+                .get(&at)
+                .ok_or(anyhow!("Could not find node {} in table", at))?;
+            let started = Instant::now();
+            let result = node.execute(payload, self);
+            self.context.record(self.name(&at), started.elapsed(), result.is_err());
+
+            match result? {
+                Next::To(next, value) => {
+                    at = next;
+                    payload = value;
+                }
+                Next::End(value) => return Ok(value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::MutexGuard;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::flowscript::{
+        execute_flowscript_hosted, execute_flowscript_json, execute_flowscript_stubbed, prepare,
+    };
+
+    // Interrupts reach every run in the process, so tests run scripts one at a time
+    pub fn one_run_at_a_time() -> MutexGuard<'static, ()> {
+        static RUNS: Mutex<()> = Mutex::new(());
+        RUNS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn input() -> Value {
+        json!({ "files": [], "fix_warnings": false })
+    }
+
+    // Proposes a fix for Approve without asking ChatGPT
+    const PROPOSE: &str = r#"propose [kind="set", label="code = 'int x;'; explanation = 'declares x'"];"#;
+
+    #[test]
+    fn loops_run_their_body_until_the_condition_fails() {
+        let _run = one_run_at_a_time();
+        let script = r#"digraph {
+  input -> start -> repeat;
+  start [kind="set", label="n = 0"];
+  repeat [kind="loop", label=".n < 3"];
+  repeat -> step [kind="body"];
+  step [kind="set", label="n = .n + 1"];
+}"#;
+        let result = execute_flowscript_json(script, None, input()).unwrap();
+        assert_eq!(result["n"], json!(3));
+    }
+
+    #[test]
+    fn for_each_bodies_run_for_every_element() {
+        let _run = one_run_at_a_time();
+        let script = r#"digraph {
+  input -> start -> total;
+  start [kind="set", label="sizes: [1, 2, 3]"];
+  total [kind="for_each", over=".sizes", as="size", mode="reduce", into="sum", init="0"];
+  total -> add [kind="body"];
+  add [kind="set", label="sum = .sum + .size"];
+}"#;
+        let result = execute_flowscript_json(script, None, input()).unwrap();
+        assert_eq!(result["sum"], json!(6));
+    }
+
+    #[test]
+    fn runs_stop_at_the_step_limit() {
+        let _run = one_run_at_a_time();
+        let script = r#"digraph {
+  max_steps="6";
+  input -> start -> repeat;
+  start [kind="set", label="n = 0"];
+  repeat [kind="loop", label=".n < 100", max="1000"];
+  repeat -> step [kind="body"];
+  step [kind="set", label="n = .n + 1"];
+}"#;
+        let (graph, context) = prepare(script, None, &|_, node| node).unwrap();
+        let error = context.run(&graph, input()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Stop>(), Some(Stop::StepLimit(_, 6))), "{}", error);
+        assert_eq!(context.steps(), 6);
+    }
+
+    #[test]
+    fn host_jobs_run_on_the_calling_thread() {
+        let _run = one_run_at_a_time();
+        let script = format!(
+            "digraph {{\n  input -> propose -> Approve;\n  {}\n}}",
+            PROPOSE
+        );
+        let caller = std::thread::current().id();
+        let mut calls = Vec::new();
+        let mut host = |job: JobType, input: Value| {
+            assert_eq!(std::thread::current().id(), caller);
+            calls.push((job, input["code"].clone()));
+            Ok(json!({ "approved": true }))
+        };
+        let result = execute_flowscript_hosted(&script, None, input(), &mut host).unwrap();
+        assert_eq!(result["approved"], json!(true));
+        assert_eq!(result["explanation"], json!("declares x"));
+        assert!(matches!(calls.as_slice(), [(JobType::Approve, code)] if code == "int x;"));
+    }
+
+    #[test]
+    fn stubbed_runs_hand_every_job_to_the_host() {
+        let _run = one_run_at_a_time();
+        let script = "digraph {\n  input -> Compile;\n}";
+        let mut calls = Vec::new();
+        let mut host = |job: JobType, _: Value| {
+            calls.push(job);
+            Ok(json!({ "stubbed": true }))
+        };
+        let result = execute_flowscript_stubbed(script, None, input(), &mut host).unwrap();
+        assert_eq!(result["stubbed"], json!(true));
+        assert_eq!(calls, [JobType::Compile]);
+    }
+
+    #[test]
+    fn failed_jobs_go_to_their_on_error_node() {
+        let _run = one_run_at_a_time();
+        let script = format!(
+            r#"digraph {{
+  input -> propose -> Approve -> done;
+  {}
+  Approve -> failed [kind="on_error"];
+  done [kind="set", label="outcome = 'approved'"];
+  failed [kind="set", label="outcome = 'failed'"];
+}}"#,
+            PROPOSE
+        );
+        let mut host = |_: JobType, _: Value| Err(anyhow!("No terminal"));
+        let result = execute_flowscript_hosted(&script, None, input(), &mut host).unwrap();
+        assert_eq!(result["outcome"], json!("failed"));
+        assert_eq!(result["__error"], json!({ "node": "Approve", "message": "No terminal" }));
+
+        // Without a handler the run fails
+        let script = format!("digraph {{\n  input -> propose -> Approve;\n  {}\n}}", PROPOSE);
+        let mut host = |_: JobType, _: Value| Err(anyhow!("No terminal"));
+        let error = execute_flowscript_hosted(&script, None, input(), &mut host).unwrap_err();
+        assert_eq!(error.to_string(), "Node Approve failed: No terminal");
+    }
+
+    #[test]
+    fn interrupts_stop_before_the_next_node_and_skip_on_error() {
+        let _run = one_run_at_a_time();
+        let script = format!(
+            r#"digraph {{
+  input -> propose -> Approve -> done;
+  {}
+  Approve -> failed [kind="on_error"];
+  done [kind="set", label="outcome = 'approved'"];
+  failed [kind="set", label="outcome = 'failed'"];
+}}"#,
+            PROPOSE
+        );
+        // Like pressing Ctrl-C while the fix is shown
+        let mut host = |_: JobType, _: Value| {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            Ok(json!({ "approved": true }))
+        };
+        let error = execute_flowscript_hosted(&script, None, input(), &mut host).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Stop>(), Some(Stop::Interrupted(node)) if node == "done"));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    nodes::{Next, Node},
+    scheduler::Run,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Jsonl,  // One step per line
+    Chrome, // Trace event format, opens in chrome://tracing or Perfetto
+}
+
+// One node running. `output` is what it handed on, or returned when it was the
+// last node of a path, and `parent` is the node that handed to it or ran it as
+// a body. `duration_us` includes any bodies it ran.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Step {
+    pub step: usize,
+    pub parent: Option<usize>,
+    pub node: String,
+    pub kind: String,
+    pub thread: usize,
+    pub start_us: u64,
+    pub duration_us: u64,
+    pub input: Value,
+    pub output: Value,
+    pub next: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+thread_local! {
+    // The step running on this thread, the parent of whatever starts next
+    static CURRENT: Cell<Option<usize>> = const { Cell::new(None) };
+    static THREAD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+fn thread_index() -> usize {
+    THREAD.with(|thread| match thread.get() {
+        Some(index) => index,
+        None => {
+            let index = THREADS.fetch_add(1, Ordering::Relaxed);
+            thread.set(Some(index));
+            index
+        }
+    })
+}
+
+// Nodes that spawn threads pass the current step on so the branches keep their parent
+pub fn current_step() -> Option<usize> {
+    CURRENT.with(|current| current.get())
+}
+
+pub fn set_current_step(step: Option<usize>) {
+    CURRENT.with(|current| current.set(step));
+}
+
+pub struct Tracer {
+    start: Instant,
+    steps: Mutex<Vec<Step>>,
+}
+
+impl Tracer {
+    pub fn new() -> Arc<Tracer> {
+        Arc::new(Tracer {
+            start: Instant::now(),
+            steps: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn now_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn begin(&self, node: &str, kind: &str, input: &Value) -> usize {
+        let now = self.now_us();
+        let parent = current_step();
+        let mut steps = self.steps.lock().unwrap();
+
+        if let Some(parent) = parent.and_then(|p| steps.get_mut(p)) {
+            if !parent.next.iter().any(|n| n == node) {
+                parent.next.push(node.to_owned());
+            }
+        }
+
+        let step = steps.len();
+        steps.push(Step {
+            step,
+            parent,
+            node: node.to_owned(),
+            kind: kind.to_owned(),
+            thread: thread_index(),
+            start_us: now,
+            duration_us: 0,
+            input: input.clone(),
+            output: Value::Null,
+            next: Vec::new(),
+            error: None,
+        });
+        set_current_step(Some(step));
+        step
+    }
+
+    // The node a step hands on to becomes its child
+    fn end(&self, step: usize, result: &Result<Next>) {
+        let now = self.now_us();
+        let mut steps = self.steps.lock().unwrap();
+        let record = &mut steps[step];
+        record.duration_us = now - record.start_us;
+        match result {
+            Ok(Next::To(_, value)) => {
+                record.output = value.clone();
+                set_current_step(Some(step));
+            }
+            Ok(Next::End(value)) => {
+                record.output = value.clone();
+                set_current_step(record.parent);
+            }
+            Err(e) => {
+                record.error = Some(e.to_string());
+                set_current_step(record.parent);
+            }
+        }
+    }
+
+    pub fn steps(&self) -> Vec<Step> {
+        self.steps.lock().unwrap().clone()
+    }
+
+    pub fn write(&self, path: &Path, format: TraceFormat) -> Result<()> {
+        let steps = self.steps();
+        let text = match format {
+            TraceFormat::Jsonl => {
+                let mut text = String::new();
+                for step in &steps {
+                    text.push_str(&serde_json::to_string(step)?);
+                    text.push('\n');
+                }
+                text
+            }
+            TraceFormat::Chrome => {
+                let events: Vec<Value> = steps.iter().map(chrome_event).collect();
+                serde_json::to_string_pretty(&json!({ "traceEvents": events }))?
+            }
+        };
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+fn chrome_event(step: &Step) -> Value {
+    json!({
+        "name": step.node,
+        "cat": step.kind,
+        "ph": "X",
+        "ts": step.start_us,
+        "dur": step.duration_us,
+        "pid": 1,
+        "tid": step.thread,
+        "args": {
+            "step": step.step,
+            "parent": step.parent,
+            "input": step.input,
+            "output": step.output,
+            "next": step.next,
+            "error": step.error,
+        },
+    })
+}
+
+// Wraps a node so every time it runs a step is recorded
+pub struct TracedNode {
+    pub name: String,
+    pub kind: &'static str,
+    pub inner: Box<dyn Node>,
+    pub tracer: Arc<Tracer>,
+}
+
+impl Node for TracedNode {
+    fn execute(&self, input: Value, run: &Run) -> Result<Next> {
+        let step = self.tracer.begin(&self.name, self.kind, &input);
+        let result = self.inner.execute(input, run);
+        self.tracer.end(step, &result);
+        result
+    }
+}
+
+// Replay --------------------------
+
+// Reads a trace in either format
+pub fn read_trace(path: &Path) -> Result<Vec<Step>> {
+    let text = fs::read_to_string(path)?;
+    if let Ok(trace) = serde_json::from_str::<Value>(&text) {
+        if let Some(events) = trace.get("traceEvents").and_then(|e| e.as_array()) {
+            return events.iter().map(step_from_event).collect();
+        }
+    }
+
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| anyhow!("Line {} of the trace: {}", i + 1, e))
+        })
+        .collect()
+}
+
+fn step_from_event(event: &Value) -> Result<Step> {
+    let args = &event["args"];
+    Ok(Step {
+        step: serde_json::from_value(args["step"].clone())?,
+        parent: serde_json::from_value(args["parent"].clone())?,
+        node: serde_json::from_value(event["name"].clone())?,
+        kind: serde_json::from_value(event["cat"].clone())?,
+        thread: serde_json::from_value(event["tid"].clone())?,
+        start_us: serde_json::from_value(event["ts"].clone())?,
+        duration_us: serde_json::from_value(event["dur"].clone())?,
+        input: args["input"].clone(),
+        output: args["output"].clone(),
+        next: serde_json::from_value(args["next"].clone())?,
+        error: serde_json::from_value(args["error"].clone())?,
+    })
+}
+
+fn short(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(80) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+// Lists what changed between two payloads as `.path` lines
+pub fn diff(path: &str, before: &Value, after: &Value, changes: &mut Vec<String>) {
+    match (before, after) {
+        (Value::Object(b), Value::Object(a)) => {
+            for (key, value) in b {
+                let field = format!("{}.{}", path, key);
+                match a.get(key) {
+                    Some(new) => diff(&field, value, new, changes),
+                    None => changes.push(format!("  - {}", field)),
+                }
+            }
+            for (key, value) in a {
+                if !b.contains_key(key) {
+                    changes.push(format!("  + {}.{}: {}", path, key, short(value)));
+                }
+            }
+        }
+        (Value::Array(b), Value::Array(a)) if b.len() == a.len() => {
+            for (i, (old, new)) in b.iter().zip(a).enumerate() {
+                diff(&format!("{}[{}]", path, i), old, new, changes);
+            }
+        }
+        _ if before != after => {
+            let path = if path.is_empty() { "." } else { path };
+            changes.push(format!("  ~ {}: {} -> {}", path, short(before), short(after)));
+        }
+        _ => {}
+    }
+}
+
+// Prints every step in the order it started with what it changed. With
+// `step` set it waits for enter between steps.
+pub fn replay(steps: &[Step], step: bool, full: bool) -> Result<()> {
+    replay_to(steps, step, full, io::stdin().lock(), io::stdout().lock())
+}
+
+fn replay_to(steps: &[Step], step: bool, full: bool, mut keys: impl BufRead, mut out: impl Write) -> Result<()> {
+    for record in steps {
+        writeln!(
+            out,
+            "#{} {} ({}) {:.1}ms{}",
+            record.step,
+            record.node,
+            record.kind,
+            record.duration_us as f64 / 1000.0,
+            match record.next.is_empty() {
+                true => String::new(),
+                false => format!(" -> {}", record.next.join(", ")),
+            }
+        )?;
+
+        if full {
+            writeln!(out, "  input:  {}", record.input)?;
+            writeln!(out, "  output: {}", record.output)?;
+        } else if record.error.is_none() {
+            let mut changes = Vec::new();
+            diff("", &record.input, &record.output, &mut changes);
+            for change in changes {
+                writeln!(out, "{}", change)?;
+            }
+        }
+        if let Some(ref e) = record.error {
+            writeln!(out, "  error: {}", e)?;
+        }
+
+        if step {
+            out.flush()?;
+            let mut line = String::new();
+            if keys.read_line(&mut line)? == 0 || line.trim() == "q" {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flowscript::{scheduler::tests::one_run_at_a_time, trace_flowscript};
+
+    const SCRIPT: &str = r#"digraph {
+  input -> start;
+  start -> bump;
+  start [shape="cds", label="n: 1"];
+  bump [shape="cds", label="n: 2"];
+}"#;
+
+    fn input() -> Value {
+        json!({ "files": [], "fix_warnings": false })
+    }
+
+    fn changes(before: Value, after: Value) -> Vec<String> {
+        let mut changes = Vec::new();
+        diff("", &before, &after, &mut changes);
+        changes
+    }
+
+    #[test]
+    fn diffs_list_what_changed_by_path() {
+        assert_eq!(
+            changes(
+                json!({ "a": { "b": 1, "gone": true }, "list": [1, 2] }),
+                json!({ "a": { "b": 2 }, "list": [1, 3], "new": "x" })
+            ),
+            ["  ~ .a.b: 1 -> 2", "  - .a.gone", "  ~ .list[1]: 2 -> 3", "  + .new: \"x\""]
+        );
+        // Arrays that changed length and payloads that aren't objects change as a whole
+        assert_eq!(changes(json!({ "l": [1] }), json!({ "l": [1, 2] })), ["  ~ .l: [1] -> [1,2]"]);
+        assert_eq!(changes(json!(1), json!("one")), ["  ~ .: 1 -> \"one\""]);
+        assert!(changes(json!({ "a": [1] }), json!({ "a": [1] })).is_empty());
+    }
+
+    #[test]
+    fn traces_read_back_the_same_in_both_formats() {
+        let _run = one_run_at_a_time();
+        let dir = tempfile::tempdir().unwrap();
+        let mut read = Vec::new();
+        for (name, format) in [("trace.jsonl", TraceFormat::Jsonl), ("trace.json", TraceFormat::Chrome)] {
+            let path = dir.path().join(name);
+            trace_flowscript(SCRIPT, None, input(), &path, format).unwrap();
+            read.push(read_trace(&path).unwrap());
+        }
+
+        for steps in &read {
+            let nodes: Vec<&str> = steps.iter().map(|s| s.node.as_str()).collect();
+            assert_eq!(nodes, ["input", "start", "bump"]);
+            assert_eq!(steps[1].parent, Some(steps[0].step));
+            assert_eq!(steps[1].next, ["bump"]);
+            assert_eq!(steps[2].output, json!({ "files": [], "fix_warnings": false, "n": 2 }));
+        }
+        let outputs = |steps: &Vec<Step>| steps.iter().map(|s| s.output.clone()).collect::<Vec<_>>();
+        assert_eq!(outputs(&read[0]), outputs(&read[1]));
+    }
+
+    #[test]
+    fn replays_show_each_step_and_what_it_changed() {
+        let _run = one_run_at_a_time();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        trace_flowscript(SCRIPT, None, input(), &path, TraceFormat::Jsonl).unwrap();
+        let mut steps = read_trace(&path).unwrap();
+        // Durations differ from run to run
+        for step in &mut steps {
+            step.duration_us = 0;
+        }
+
+        let mut out = Vec::new();
+        replay_to(&steps, false, false, io::empty(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().collect::<Vec<_>>(),
+            [
+                "#0 input (input) 0.0ms -> start",
+                "#1 start (set) 0.0ms -> bump",
+                "  + .n: 1",
+                "#2 bump (set) 0.0ms",
+                "  ~ .n: 1 -> 2",
+            ]
+        );
+
+        // Stepping stops when asked to
+        let mut out = Vec::new();
+        replay_to(&steps, true, true, "\nq\n".as_bytes(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("#1 start"));
+        assert!(!text.contains("#2 bump"));
+        assert!(text.contains("  output: {\"files\":[],\"fix_warnings\":false,\"n\":1}"), "{}", text);
+    }
+}
@@ -1,15 +1,31 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use serde_json::Value;
 
-use super::{parser::{ConnectionDef, ConnectionType, Defs, NodeDef}, nodes::{NodeMap, self, TaskNode, IfNode, CountNode, MultiNode}};
+use super::{parser::{conditional, ConnectionDef, ConnectionType, Defs, ForEachMode, NodeDef}, nodes::{Node, NodeMap, self, TaskNode, IfNode, CountNode, MultiNode, LoopNode, ForEachNode, Subgraph, SubgraphNode, ExecNode}, modules::Program};
 
 #[derive(Debug)]
 pub enum TransformError {
     NoConnection(String),
+    MissingBranch(String, bool),
+    MissingBody(String),
+    InvalidSetter(String, String),
 }
 
-pub fn get_point_to(name: &str, conns: &Vec<ConnectionDef>) -> Option<String> {
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::NoConnection(name) => write!(f, "No connection for {}", name),
+            TransformError::MissingBranch(name, branch) => {
+                write!(f, "If node {} has no {} connection", name, branch)
+            }
+            TransformError::MissingBody(name) => write!(f, "Node {} has no body", name),
+            TransformError::InvalidSetter(name, e) => write!(f, "Setter {} is invalid:\n{}", name, e),
+        }
+    }
+}
+
+pub fn get_point_to(name: &str, conns: &[ConnectionDef]) -> Option<String> {
     let conn = conns
         .iter()
         .find(|conn| conn.from == name && conn.c_type == ConnectionType::Default);
@@ -17,61 +33,164 @@ pub fn get_point_to(name: &str, conns: &Vec<ConnectionDef>) -> Option<String> {
     conn.map(|conn| conn.to.clone())
 }
 
+fn get_on_error(name: &str, conns: &[ConnectionDef]) -> Option<String> {
+    conns
+        .iter()
+        .find(|conn| conn.from == name && conn.c_type == ConnectionType::OnError)
+        .map(|conn| conn.to.clone())
+}
+
 pub fn safe_parse_to_value(text: &str) -> Value {
     // If length is zero
     if text.is_empty() {
         return Value::Null;
     }
     // attempt to parse as a number, bool, undefined, null
-    match serde_json::from_str(text) {
-        Ok(v) => return v,
-        Err(_) => {}
-    };
+    if let Ok(v) = serde_json::from_str(text) {
+        return v;
+    }
 
     // parse as a string
     Value::String(text.to_owned())
 }
 
-pub fn defs_to_graph(defs: Defs) -> Result<NodeMap, TransformError> {
+// What a wrapper gets to know about the node it wraps
+pub struct NodeInfo {
+    pub name: String, // Prefixed with the subgraph it's in
+    pub kind: &'static str,
+    pub next: Option<String>, // The default connection, if it has one
+    pub attributes: HashMap<String, String>,
+}
+
+// Lets the caller wrap every node after it's built, used for tracing and debugging
+pub type Wrap<'a> = &'a dyn Fn(NodeInfo, Box<dyn Node>) -> Box<dyn Node>;
+
+// Subgraphs are built before the graphs that call them
+pub fn build_program(program: &Program, wrap: Wrap) -> Result<NodeMap, TransformError> {
+    fn build(
+        name: &String,
+        program: &Program,
+        built: &mut HashMap<String, Arc<Subgraph>>,
+        wrap: Wrap,
+    ) -> Result<(), TransformError> {
+        if built.contains_key(name) {
+            return Ok(());
+        }
+        let defs = program.graph(program.subgraphs[name]);
+        for callee in program.callees(defs) {
+            build(callee, program, built, wrap)?;
+        }
+        let graph = defs_to_graph(defs, built)?;
+        let subgraph = Subgraph {
+            graph: wrap_nodes(graph, defs, Some(name), built, wrap),
+            inputs: defs.field_list("inputs"),
+            outputs: defs.field_list("outputs"),
+        };
+        built.insert(name.clone(), Arc::new(subgraph));
+        Ok(())
+    }
+
+    let mut built = HashMap::new();
+    for name in program.callees(program.main()) {
+        build(name, program, &mut built, wrap)?;
+    }
+    let graph = defs_to_graph(program.main(), &built)?;
+    Ok(wrap_nodes(graph, program.main(), None, &built, wrap))
+}
+
+fn wrap_nodes(
+    graph: NodeMap,
+    defs: &Defs,
+    scope: Option<&String>,
+    subgraphs: &HashMap<String, Arc<Subgraph>>,
+    wrap: Wrap,
+) -> NodeMap {
+    graph
+        .into_iter()
+        .map(|(name, node)| {
+            let kind = match defs.variables.get(&name) {
+                Some(NodeDef::Task(_)) if subgraphs.contains_key(&name) => "subgraph",
+                Some(def) => def.kind(),
+                None => "input",
+            };
+            let info = NodeInfo {
+                name: match scope {
+                    Some(scope) => format!("{}.{}", scope, name),
+                    None => name.clone(),
+                },
+                kind,
+                next: get_point_to(&name, &defs.connections),
+                attributes: defs.node_attributes.get(&name).cloned().unwrap_or_default(),
+            };
+            (name, wrap(info, node))
+        })
+        .collect()
+}
+
+pub fn defs_to_graph(
+    defs: &Defs,
+    subgraphs: &HashMap<String, Arc<Subgraph>>,
+) -> Result<NodeMap, TransformError> {
     let mut node_map: nodes::NodeMap = HashMap::new();
-    let conns = defs.connections;
+    let conns = &defs.connections;
 
-    for var_def in defs.variables {
-        let name = var_def.0;
-        let var = var_def.1;
+    for (name, var) in &defs.variables {
+        let name = name.clone();
 
-        match var {
+        match var.clone() {
             NodeDef::Input => {
-                let Some(points_to) = get_point_to(&name, &conns) else {
+                let Some(points_to) = get_point_to(&name, conns) else {
                     return Err(TransformError::NoConnection(name));
                 };
                 let node = nodes::InputNode { points_to };
                 node_map.insert(name, Box::new(node));
             }
 
-            NodeDef::Task => {
-                let points_to = get_point_to(&name, &conns);
+            NodeDef::Task(_) if subgraphs.contains_key(&name) => {
+                let node = SubgraphNode {
+                    name: name.clone(),
+                    subgraph: subgraphs[&name].clone(),
+                    on_error: get_on_error(&name, conns),
+                    points_to: get_point_to(&name, conns),
+                };
+                node_map.insert(name, Box::new(node));
+            }
+
+            NodeDef::Task(options) => {
+                let points_to = get_point_to(&name, conns);
 
                 let task = TaskNode {
                     command: name.clone(),
+                    options,
+                    on_error: get_on_error(&name, conns),
                     points_to,
                 };
                 node_map.insert(name, Box::new(task));
             }
 
+            NodeDef::Exec(def) => {
+                let node = ExecNode {
+                    name: name.clone(),
+                    def,
+                    on_error: get_on_error(&name, conns),
+                    points_to: get_point_to(&name, conns),
+                };
+                node_map.insert(name, Box::new(node));
+            }
+
             NodeDef::IfStatement(condition) => {
                 let Some(true_point) = conns
                     .iter()
                     .find(|c| c.c_type == ConnectionType::IfResult(true) && c.from == name)
                 else {
-                    panic!("Error: If node {} has no true connection", name);
+                    return Err(TransformError::MissingBranch(name, true));
                 };
 
                 let Some(false_point) = conns
                     .iter()
                     .find(|c| c.c_type == ConnectionType::IfResult(false) && c.from == name)
                 else {
-                    panic!("Error: If node {} has no false connection", name);
+                    return Err(TransformError::MissingBranch(name, false));
                 };
 
                 let node = IfNode {
@@ -84,26 +203,30 @@ pub fn defs_to_graph(defs: Defs) -> Result<NodeMap, TransformError> {
             }
 
             NodeDef::Count => {
-                let Some(points_to) = get_point_to(&name, &conns) else {
+                let Some(points_to) = get_point_to(&name, conns) else {
                     return Err(TransformError::NoConnection(name));
                 };
-                let node = CountNode::new(points_to);
+                let node = CountNode {
+                    name: name.clone(),
+                    points_to,
+                };
                 node_map.insert(name, Box::new(node));
             }
 
-            NodeDef::Multi => {
+            NodeDef::Multi(merge) => {
                 let before: Vec<String> = conns
                     .iter()
-                    .filter(|conn| conn.from == name || conn.c_type == ConnectionType::MultiOut)
+                    .filter(|conn| conn.from == name && conn.c_type == ConnectionType::MultiOut)
                     .map(|c| c.to.clone())
                     .collect();
 
-                let Some(points_to) = get_point_to(&name, &conns) else {
+                let Some(points_to) = get_point_to(&name, conns) else {
                     return Err(TransformError::NoConnection(name));
                 };
 
                 let node = MultiNode {
                     run_before: before,
+                    merge,
                     points_to,
                 };
 
@@ -113,21 +236,12 @@ pub fn defs_to_graph(defs: Defs) -> Result<NodeMap, TransformError> {
             NodeDef::Switch(field) => {
                 let cases: Vec<(Value, String)> = conns
                     .iter()
-                    .filter(|conn| {
-                        if conn.from != name {
-                            return false;
-                        }
-
-                        match conn.c_type {
-                            ConnectionType::SwitchBranch(_) => true,
-                            _ => false,
-                        }
-                    })
-                    .map(|conn| match conn.c_type.clone() {
-                        ConnectionType::SwitchBranch(value) => {
-                            (safe_parse_to_value(value.as_str()), conn.to.clone())
+                    .filter(|conn| conn.from == name)
+                    .filter_map(|conn| match conn.c_type {
+                        ConnectionType::SwitchBranch(ref value) => {
+                            Some((safe_parse_to_value(value.as_str()), conn.to.clone()))
                         }
-                        _ => panic!("Error: Switch node {} has non-switch connection", name),
+                        _ => None,
                     })
                     .collect();
 
@@ -147,21 +261,12 @@ pub fn defs_to_graph(defs: Defs) -> Result<NodeMap, TransformError> {
             NodeDef::Match(field) => {
                 let cases: Vec<(Value, String)> = conns
                     .iter()
-                    .filter(|conn| {
-                        if conn.from != name {
-                            return false;
+                    .filter(|conn| conn.from == name)
+                    .filter_map(|conn| match conn.c_type {
+                        ConnectionType::MatchBranch(ref value) => {
+                            Some((safe_parse_to_value(value.as_str()), conn.to.clone()))
                         }
-
-                        match conn.c_type {
-                            ConnectionType::MatchBranch(_) => true,
-                            _ => false,
-                        }
-                    })
-                    .map(|conn| match conn.c_type.clone() {
-                        ConnectionType::MatchBranch(value) => {
-                            (safe_parse_to_value(value.as_str()), conn.to.clone())
-                        }
-                        _ => panic!("Error: Switch node {} has non-switch connection", name),
+                        _ => None,
                     })
                     .collect();
 
@@ -175,14 +280,53 @@ pub fn defs_to_graph(defs: Defs) -> Result<NodeMap, TransformError> {
                     default_to: default_to.map(|c| c.to.clone()),
                 };
 
+                node_map.insert(name, Box::new(node));
+            }
+
+            NodeDef::Loop { condition, max } => {
+                let Some(body) = conns
+                    .iter()
+                    .find(|c| c.from == name && c.c_type == ConnectionType::Body)
+                else {
+                    return Err(TransformError::MissingBody(name));
+                };
+
+                let node = LoopNode {
+                    name: name.clone(),
+                    condition,
+                    max,
+                    body: body.to.clone(),
+                    points_to: get_point_to(&name, conns),
+                };
+
+                node_map.insert(name, Box::new(node));
+            }
+
+            NodeDef::ForEach(def) => {
+                let body = conns
+                    .iter()
+                    .find(|c| c.from == name && c.c_type == ConnectionType::Body)
+                    .map(|c| c.to.clone());
+                if body.is_none() && def.mode != ForEachMode::Filter {
+                    return Err(TransformError::MissingBody(name));
+                }
+
+                let node = ForEachNode {
+                    init: safe_parse_to_value(&def.init),
+                    def,
+                    body,
+                    points_to: get_point_to(&name, conns),
+                };
 
                 node_map.insert(name, Box::new(node));
             }
 
             NodeDef::Setter(label) => {
-                let points_to = get_point_to(&name, &conns);
+                let points_to = get_point_to(&name, conns);
+                let ops = conditional::parse_setter(&label)
+                    .map_err(|e| TransformError::InvalidSetter(name.clone(), e.to_string()))?;
 
-                let node = nodes::AddFieldNode { label, points_to };
+                let node = nodes::AddFieldNode { ops, points_to };
 
                 node_map.insert(name, Box::new(node));
             }
@@ -0,0 +1,842 @@
+use std::collections::{BTreeMap, HashMap};
+
+use pest::{error::Error, iterators::Pair, Parser};
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use serde_json::Value;
+
+use crate::system::types::JobType;
+
+use super::{
+    modules::{with_module, Module, Program},
+    parser::{
+        conditional::{self, ConditionParser, SetterOp},
+        ConnectionType, Defs, ExecDef, ForEachMode, MergePolicy, NodeDef, Rule,
+    },
+    transform::get_point_to,
+    validate::build_error,
+};
+
+// Gives up on graphs that keep changing the payload, loops settle in a few rounds
+const MAX_ROUNDS: usize = 100;
+
+// What is known about a payload before anything runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Any,
+    Null,
+    Bool,
+    Number,
+    String,
+    Array(Box<Type>),
+    Object(Shape),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Shape {
+    fields: BTreeMap<String, Field>,
+    // Other fields might be there too, like anything in the script input
+    open: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    ty: Type,
+    required: bool, // Set on every path
+}
+
+impl Type {
+    fn open() -> Type {
+        Type::Object(Shape {
+            fields: BTreeMap::new(),
+            open: true,
+        })
+    }
+
+    // Anything that isn't an object is replaced by the next job result anyway
+    fn shape(&self) -> Shape {
+        match self {
+            Type::Object(shape) => shape.clone(),
+            _ => Shape {
+                fields: BTreeMap::new(),
+                open: true,
+            },
+        }
+    }
+
+    fn with(&self, key: &str, ty: Type) -> Type {
+        let mut shape = self.shape();
+        shape.fields.insert(key.to_owned(), Field { ty, required: true });
+        Type::Object(shape)
+    }
+
+    // Like `with` for a field that can be null, see `from_object`
+    fn with_optional(&self, key: &str, ty: Type) -> Type {
+        let mut shape = self.shape();
+        shape.fields.insert(key.to_owned(), Field { ty, required: false });
+        Type::Object(shape)
+    }
+
+    // A payload that could be either
+    fn join(&self, other: &Type) -> Type {
+        match (self, other) {
+            (a, b) if a == b => a.clone(),
+            (Type::Array(a), Type::Array(b)) => Type::Array(Box::new(a.join(b))),
+            (Type::Object(a), Type::Object(b)) => {
+                let mut fields = BTreeMap::new();
+                for (key, field) in &a.fields {
+                    let joined = match b.fields.get(key) {
+                        Some(other) => Field {
+                            ty: field.ty.join(&other.ty),
+                            required: field.required && other.required,
+                        },
+                        None => Field {
+                            ty: field.ty.clone(),
+                            required: false,
+                        },
+                    };
+                    fields.insert(key.clone(), joined);
+                }
+                for (key, field) in &b.fields {
+                    fields.entry(key.clone()).or_insert(Field {
+                        ty: field.ty.clone(),
+                        required: false,
+                    });
+                }
+                Type::Object(Shape {
+                    fields,
+                    open: a.open || b.open,
+                })
+            }
+            _ => Type::Any,
+        }
+    }
+
+    // Like `merge_result`, what a job gives overwrites what was there. Anything
+    // but an object is replaced by the result as a whole.
+    fn merge(&self, result: &Type) -> Type {
+        let Type::Object(result) = result else {
+            return result.clone();
+        };
+        if !matches!(self, Type::Object(_) | Type::Any) {
+            return Type::Object(result.clone());
+        }
+        let mut shape = self.shape();
+        for (key, field) in &result.fields {
+            let merged = match (shape.fields.get(key), field.required) {
+                (Some(old), false) => Field {
+                    ty: old.ty.join(&field.ty),
+                    required: old.required,
+                },
+                _ => field.clone(),
+            };
+            shape.fields.insert(key.clone(), merged);
+        }
+        shape.open |= result.open;
+        Type::Object(shape)
+    }
+
+    // Like `with` for a path such as `.result.items`. A path through an array
+    // index leaves the type as it was.
+    fn with_path(&self, path: &str, ty: Type) -> Type {
+        let path = path.trim_start_matches('.');
+        match path.split_once('.') {
+            _ if path.contains('[') => self.clone(),
+            Some((key, rest)) => self.with(key, self.lookup(key).with_path(rest, ty)),
+            None => self.with(path, ty),
+        }
+    }
+
+    // Follows a for each `over` path like `.errors` or `.result.items`
+    fn lookup(&self, path: &str) -> Type {
+        let mut current = self.clone();
+        for part in path.split('.').filter(|p| !p.is_empty()) {
+            let name = part.split('[').next().unwrap_or_default();
+            current = match current {
+                Type::Object(ref shape) => match shape.fields.get(name) {
+                    Some(field) => field.ty.clone(),
+                    None if name == "length" => Type::Number,
+                    None => return Type::Any,
+                },
+                Type::Array(_) | Type::String if name == "length" => Type::Number,
+                _ => return Type::Any,
+            };
+            for _ in part.matches('[') {
+                current = match current {
+                    Type::Array(item) => *item,
+                    _ => return Type::Any,
+                };
+            }
+        }
+        current
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Type::Any => "anything".to_owned(),
+            Type::Null => "null".to_owned(),
+            Type::Bool => "a boolean".to_owned(),
+            Type::Number => "a number".to_owned(),
+            Type::String => "a string".to_owned(),
+            Type::Array(item) => format!("an array of {}", item.describe()),
+            Type::Object(shape) if shape.open || shape.fields.is_empty() => "an object".to_owned(),
+            Type::Object(shape) => {
+                let names: Vec<&str> = shape.fields.keys().map(|k| k.as_str()).collect();
+                format!("an object with {}", names.join(", "))
+            }
+        }
+    }
+}
+
+// A short spelling of a type, like `{files: [string], fix_warnings?: bool}`
+pub fn signature(ty: &Type) -> String {
+    match ty {
+        Type::Any => "any".to_owned(),
+        Type::Null => "null".to_owned(),
+        Type::Bool => "bool".to_owned(),
+        Type::Number => "number".to_owned(),
+        Type::String => "string".to_owned(),
+        Type::Array(item) => format!("[{}]", signature(item)),
+        Type::Object(shape) if shape.fields.is_empty() => "object".to_owned(),
+        Type::Object(shape) => {
+            let mut fields: Vec<String> = shape
+                .fields
+                .iter()
+                .map(|(name, field)| {
+                    let optional = if field.required { "" } else { "?" };
+                    format!("{}{}: {}", name, optional, signature(&field.ty))
+                })
+                .collect();
+            if shape.open {
+                fields.push("...".to_owned());
+            }
+            format!("{{{}}}", fields.join(", "))
+        }
+    }
+}
+
+// Why a payload can't go into a job
+enum Mismatch {
+    Type {
+        path: String,
+        expected: Type,
+        actual: Type,
+    },
+    Missing(String),
+    MaybeMissing(String),
+}
+
+// Checks `actual` has everything `expected` asks for. Fields nobody knows about
+// are fine as long as the payload is open.
+fn fits(actual: &Type, expected: &Type, path: &str) -> Result<(), Mismatch> {
+    match (actual, expected) {
+        (_, Type::Any) | (Type::Any, _) => Ok(()),
+        (a, e) if a == e => Ok(()),
+        (Type::Array(a), Type::Array(e)) => fits(a, e, &format!("{}[]", path)),
+        (Type::Object(a), Type::Object(e)) => {
+            for (key, field) in &e.fields {
+                let field_path = format!("{}.{}", path, key);
+                match a.fields.get(key) {
+                    Some(found) => {
+                        fits(&found.ty, &field.ty, &field_path)?;
+                        if field.required && !found.required && !a.open {
+                            return Err(Mismatch::MaybeMissing(field_path));
+                        }
+                    }
+                    None if field.required && !a.open => return Err(Mismatch::Missing(field_path)),
+                    None => {}
+                }
+            }
+            Ok(())
+        }
+        _ => Err(Mismatch::Type {
+            path: match path.is_empty() {
+                true => ".".to_owned(),
+                false => path.to_owned(),
+            },
+            expected: expected.clone(),
+            actual: actual.clone(),
+        }),
+    }
+}
+
+// Schemas ----------------------------
+
+pub fn from_root(root: &RootSchema) -> Type {
+    from_object(&root.schema, &root.definitions)
+}
+
+fn from_schema(schema: &Schema, definitions: &schemars::Map<String, Schema>) -> Type {
+    match schema {
+        Schema::Bool(_) => Type::Any,
+        Schema::Object(object) => from_object(object, definitions),
+    }
+}
+
+fn from_object(object: &SchemaObject, definitions: &schemars::Map<String, Schema>) -> Type {
+    if let Some(ref reference) = object.reference {
+        let name = reference.trim_start_matches("#/definitions/");
+        return match definitions.get(name) {
+            Some(schema) => from_schema(schema, definitions),
+            None => Type::Any,
+        };
+    }
+
+    // Options and enums with data come out as a list of alternatives
+    if let Some(ref subschemas) = object.subschemas {
+        let options = [&subschemas.all_of, &subschemas.any_of, &subschemas.one_of];
+        if let Some(options) = options.into_iter().flatten().next() {
+            return options
+                .iter()
+                .map(|option| from_schema(option, definitions))
+                .filter(|ty| *ty != Type::Null)
+                .reduce(|a, b| a.join(&b))
+                .unwrap_or(Type::Null);
+        }
+    }
+
+    let instance = match object.instance_type {
+        Some(SingleOrVec::Single(ref instance)) => Some(**instance),
+        // `Option<T>` is T or null, whether it's there is tracked by `required`
+        Some(SingleOrVec::Vec(ref instances)) => {
+            let types: Vec<&InstanceType> = instances.iter().filter(|t| **t != InstanceType::Null).collect();
+            match types.as_slice() {
+                [single] => Some(**single),
+                _ => None,
+            }
+        }
+        None => None,
+    };
+
+    match instance {
+        Some(InstanceType::Null) => Type::Null,
+        Some(InstanceType::Boolean) => Type::Bool,
+        Some(InstanceType::Integer | InstanceType::Number) => Type::Number,
+        Some(InstanceType::String) => Type::String,
+        Some(InstanceType::Array) => {
+            let item = match object.array.as_ref().and_then(|a| a.items.as_ref()) {
+                Some(SingleOrVec::Single(item)) => from_schema(item, definitions),
+                _ => Type::Any,
+            };
+            Type::Array(Box::new(item))
+        }
+        Some(InstanceType::Object) => {
+            let Some(ref validation) = object.object else {
+                return Type::open();
+            };
+            let fields = validation
+                .properties
+                .iter()
+                .map(|(name, schema)| {
+                    let field = Field {
+                        ty: from_schema(schema, definitions),
+                        required: validation.required.contains(name),
+                    };
+                    (name.clone(), field)
+                })
+                .collect();
+            Type::Object(Shape { fields, open: false })
+        }
+        None => Type::Any,
+    }
+}
+
+// A literal set by a setter node
+fn type_of(value: &Value) -> Type {
+    match value {
+        Value::Null => Type::Null,
+        Value::Bool(_) => Type::Bool,
+        Value::Number(_) => Type::Number,
+        Value::String(_) => Type::String,
+        Value::Array(items) => Type::Array(Box::new(
+            items.iter().map(type_of).reduce(|a, b| a.join(&b)).unwrap_or(Type::Any),
+        )),
+        Value::Object(map) => Type::Object(Shape {
+            fields: map
+                .iter()
+                .map(|(key, value)| {
+                    let field = Field {
+                        ty: type_of(value),
+                        required: true,
+                    };
+                    (key.clone(), field)
+                })
+                .collect(),
+            open: false,
+        }),
+    }
+}
+
+// Named inputs are all a graph gets, without them a subgraph could get anything
+fn entry_type(defs: &Defs) -> Type {
+    match defs.field_list("inputs") {
+        Some(inputs) => Type::Object(Shape {
+            fields: inputs
+                .into_iter()
+                .map(|name| (name, Field { ty: Type::Any, required: true }))
+                .collect(),
+            open: false,
+        }),
+        None => Type::open(),
+    }
+}
+
+// The main graph is run on the files to compile, see `compile` in main.rs
+fn main_entry_type(defs: &Defs) -> Type {
+    match defs.field_list("inputs") {
+        Some(_) => entry_type(defs),
+        None => from_root(&JobType::Compile.input_schema()),
+    }
+}
+
+// What a setter expression gives, anything it can't tell is `Any`
+fn expression_type(pair: Pair<conditional::Rule>, input: &Type) -> Type {
+    use conditional::Rule as Expr;
+
+    let parts: Vec<Pair<Expr>> = pair.clone().into_inner().collect();
+    match pair.as_rule() {
+        Expr::or_expr | Expr::and_expr | Expr::comparison if parts.len() > 1 => Type::Bool,
+        Expr::or_expr | Expr::and_expr | Expr::comparison => expression_type(parts[0].clone(), input),
+        Expr::unary if parts.len() > 1 => Type::Bool,
+        Expr::unary => expression_type(parts[0].clone(), input),
+        Expr::sum | Expr::product if parts.len() == 1 => expression_type(parts[0].clone(), input),
+        Expr::sum | Expr::product => {
+            let operands: Vec<Type> = parts
+                .into_iter()
+                .filter(|p| !is_operator(p.as_rule()))
+                .map(|p| expression_type(p, input))
+                .collect();
+            if pair.as_rule() == Expr::sum && operands.contains(&Type::String) {
+                Type::String
+            } else if operands.iter().all(|t| *t == Type::Number) {
+                Type::Number
+            } else if operands.iter().all(|t| matches!(t, Type::Array(_))) {
+                operands.into_iter().reduce(|a, b| a.join(&b)).unwrap_or(Type::Any)
+            } else {
+                Type::Any
+            }
+        }
+        Expr::json_path => input.lookup(pair.as_str()),
+        Expr::number_value => Type::Number,
+        Expr::bool_value => Type::Bool,
+        Expr::null_value => Type::Null,
+        Expr::string_value | Expr::quoted_string => Type::String,
+        _ => Type::Any,
+    }
+}
+
+fn is_operator(rule: conditional::Rule) -> bool {
+    use conditional::Rule as Expr;
+    matches!(rule, Expr::plus | Expr::minus | Expr::times | Expr::divide | Expr::modulo)
+}
+
+fn set_fields(input: &Type, ops: &[SetterOp]) -> Type {
+    let mut output = input.clone();
+    for op in ops {
+        output = match op {
+            SetterOp::Set(field, expression) => {
+                let ty = ConditionParser::parse(conditional::Rule::expression, expression)
+                    .ok()
+                    .and_then(|mut pairs| pairs.next())
+                    .and_then(|expression| expression.into_inner().next())
+                    .map(|pair| expression_type(pair, &output))
+                    .unwrap_or(Type::Any);
+                output.with(field, ty)
+            }
+            SetterOp::Literal(field, value) => output.with(field, type_of(value)),
+            SetterOp::Remove(field) => {
+                let mut shape = output.shape();
+                shape.fields.remove(field);
+                Type::Object(shape)
+            }
+            SetterOp::Rename(from, to) => {
+                let mut shape = output.shape();
+                if let Some(field) = shape.fields.remove(from) {
+                    shape.fields.insert(to.clone(), field);
+                }
+                Type::Object(shape)
+            }
+        };
+    }
+    output
+}
+
+// The payload after an exec node
+fn exec_output(input: &Type, def: &ExecDef) -> Type {
+    let output = Type::Object(Shape::default())
+        .with("stdout", Type::String)
+        .with("stderr", Type::String)
+        .with_optional("exit_code", Type::Number);
+    match def.into {
+        Some(ref into) => input.with_path(into, output),
+        None => input.merge(&output),
+    }
+}
+
+fn join_into(map: &mut HashMap<String, Type>, key: &str, ty: Type) -> bool {
+    let joined = match map.get(key) {
+        Some(old) => old.join(&ty),
+        None => ty,
+    };
+    map.insert(key.to_owned(), joined.clone()) != Some(joined)
+}
+
+// Checking ---------------------------
+
+struct Checker<'a> {
+    program: &'a Program,
+    // What each subgraph gives back, they're checked once no matter how often they're called
+    results: HashMap<String, Type>,
+    errors: Vec<Error<Rule>>,
+    // What reaches each node, by subgraph name with None for the main graph
+    payloads: HashMap<Option<String>, HashMap<String, Type>>,
+}
+
+// One graph while it's being checked
+struct Flow<'a> {
+    defs: &'a Defs,
+    inputs: HashMap<String, Type>,
+    results: HashMap<String, Type>,
+}
+
+impl Flow<'_> {
+    fn targets(&self, name: &str, matches: impl Fn(&ConnectionType) -> bool) -> Vec<&String> {
+        self.defs
+            .connections
+            .iter()
+            .filter(|c| c.from == name && matches(&c.c_type))
+            .map(|c| &c.to)
+            .collect()
+    }
+
+    fn result(&self, name: &str) -> Option<&Type> {
+        self.results.get(name)
+    }
+
+    // What a node gives back once the path after it is done. `None` until the
+    // nodes after it have results.
+    fn then(&self, next: &Option<String>, payload: Type) -> Option<Type> {
+        match next {
+            Some(next) => self.result(next).cloned(),
+            None => Some(payload),
+        }
+    }
+}
+
+fn join_all<'t>(types: impl Iterator<Item = Option<&'t Type>>) -> Option<Type> {
+    let mut joined: Option<Type> = None;
+    for ty in types {
+        let ty = ty?;
+        joined = Some(match joined {
+            Some(joined) => joined.join(ty),
+            None => ty.clone(),
+        });
+    }
+    joined
+}
+
+impl Checker<'_> {
+    // What a call node gets back from the subgraph it calls
+    fn subgraph_result(&mut self, name: &str) -> Type {
+        if let Some(result) = self.results.get(name) {
+            return result.clone();
+        }
+        let graph = self.program.subgraphs[name];
+        let module = &self.program.modules[graph.module];
+        let defs = self.program.graph(graph);
+
+        let result = self.check_graph(Some(name), module, defs, entry_type(defs));
+        let result = match (defs.field_list("outputs"), result) {
+            (Some(outputs), Type::Object(shape)) => Type::Object(Shape {
+                fields: shape
+                    .fields
+                    .into_iter()
+                    .filter(|(key, _)| outputs.contains(key))
+                    .collect(),
+                open: false,
+            }),
+            (_, result) => result,
+        };
+        self.results.insert(name.to_owned(), result.clone());
+        result
+    }
+
+    // Where a node sends the payload and what it gives back, given what came in
+    fn step(&mut self, flow: &Flow, name: &str, input: &Type) -> (Vec<(String, Type)>, Option<Type>) {
+        let defs = flow.defs;
+        let next = get_point_to(name, &defs.connections);
+        let to_next = |payload: &Type| next.iter().map(|n| (n.clone(), payload.clone())).collect();
+
+        match &defs.variables[name] {
+            NodeDef::Input => (to_next(input), flow.then(&next, input.clone())),
+
+            NodeDef::Task(_) | NodeDef::Exec(_) => {
+                let output = match (&defs.variables[name], JobType::from_name(name)) {
+                    (NodeDef::Exec(def), _) => exec_output(input, def),
+                    (_, Some(job)) => input.merge(&from_root(&job.output_schema())),
+                    _ if self.program.subgraphs.contains_key(name) => {
+                        input.merge(&self.subgraph_result(name))
+                    }
+                    _ => Type::Any,
+                };
+                let mut sends: Vec<(String, Type)> = to_next(&output);
+                let mut result = flow.then(&next, output);
+
+                let error = Type::Object(Shape::default())
+                    .with("node", Type::String)
+                    .with("message", Type::String);
+                for handler in flow.targets(name, |c| *c == ConnectionType::OnError) {
+                    sends.push((handler.clone(), input.with("__error", error.clone())));
+                    result = join_all([result.as_ref(), flow.result(handler)].into_iter());
+                }
+                (sends, result)
+            }
+
+            NodeDef::IfStatement(_) | NodeDef::Switch(_) => {
+                let branches = flow.targets(name, |c| {
+                    matches!(
+                        c,
+                        ConnectionType::IfResult(_) | ConnectionType::SwitchBranch(_) | ConnectionType::Default
+                    )
+                });
+                let sends = branches.iter().map(|b| ((*b).clone(), input.clone())).collect();
+                // A switch without a default hands the payload back when nothing matches
+                let fallthrough = match (&defs.variables[name], &next) {
+                    (NodeDef::Switch(_), None) => Some(input),
+                    _ => None,
+                };
+                let results = branches.iter().map(|b| flow.result(b)).chain(fallthrough.map(Some));
+                (sends, join_all(results))
+            }
+
+            NodeDef::Count => {
+                let output = input.with("__count", Type::Number);
+                (to_next(&output), flow.then(&next, output))
+            }
+
+            NodeDef::Match(_) => {
+                let cases = flow.targets(name, |c| matches!(c, ConnectionType::MatchBranch(_)));
+                let mut sends: Vec<(String, Type)> = cases.iter().map(|c| ((*c).clone(), input.clone())).collect();
+                let Some(after) = join_all(cases.iter().map(|c| flow.result(c)).chain([Some(input)])) else {
+                    return (sends, None);
+                };
+                sends.extend(to_next(&after));
+                (sends, flow.then(&next, after))
+            }
+
+            NodeDef::Setter(label) => {
+                let ops = conditional::parse_setter(label).unwrap_or_default();
+                let output = set_fields(input, &ops);
+                (to_next(&output), flow.then(&next, output))
+            }
+
+            NodeDef::Multi(policy) => {
+                let branches = flow.targets(name, |c| *c == ConnectionType::MultiOut);
+                let mut sends: Vec<(String, Type)> =
+                    branches.iter().map(|b| ((*b).clone(), input.clone())).collect();
+                let mut merged = input.clone();
+                for branch in &branches {
+                    let Some(result) = flow.result(branch) else {
+                        return (sends, None);
+                    };
+                    merged = match policy {
+                        MergePolicy::NestByBranch => merged.with(branch, result.clone()),
+                        _ => merged.merge(result),
+                    };
+                }
+                sends.extend(to_next(&merged));
+                (sends, flow.then(&next, merged))
+            }
+
+            NodeDef::Loop { .. } => {
+                let body = flow.targets(name, |c| *c == ConnectionType::Body);
+                // Every iteration gets what the last one gave back
+                let value = join_all(body.iter().map(|b| flow.result(b)))
+                    .map(|result| input.join(&result))
+                    .unwrap_or(input.clone());
+                let mut sends: Vec<(String, Type)> = body
+                    .iter()
+                    .map(|b| ((*b).clone(), value.with("__iteration", Type::Number)))
+                    .collect();
+                sends.extend(to_next(&value));
+                (sends, flow.then(&next, value))
+            }
+
+            NodeDef::ForEach(def) => {
+                let items = input.lookup(&def.over);
+                let item = match items {
+                    Type::Array(ref item) => (**item).clone(),
+                    _ => Type::Any,
+                };
+                let mut element = input.with(&def.item, item).with("index", Type::Number);
+                if def.mode == ForEachMode::Reduce {
+                    element = element.with_path(&def.into, Type::Any);
+                }
+                let mut sends: Vec<(String, Type)> = flow
+                    .targets(name, |c| *c == ConnectionType::Body)
+                    .into_iter()
+                    .map(|b| (b.clone(), element.clone()))
+                    .collect();
+
+                let collected = match def.mode {
+                    ForEachMode::Map => Type::Array(Box::new(Type::Any)),
+                    ForEachMode::Filter => items,
+                    ForEachMode::Reduce => Type::Any,
+                };
+                let output = input.with_path(&def.into, collected);
+                sends.extend(to_next(&output));
+                (sends, flow.then(&next, output))
+            }
+        }
+    }
+
+    // Runs the graph on types until nothing changes, then checks every edge
+    // into a job or subgraph. Gives back what the graph returns.
+    fn check_graph(&mut self, scope: Option<&str>, module: &Module, defs: &Defs, entry: Type) -> Type {
+        let mut flow = Flow {
+            defs,
+            inputs: HashMap::from([("input".to_owned(), entry)]),
+            results: HashMap::new(),
+        };
+        let mut names: Vec<&String> = defs.variables.keys().collect();
+        names.sort();
+
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for name in &names {
+                let Some(input) = flow.inputs.get(*name).cloned() else {
+                    continue;
+                };
+                let (sends, result) = self.step(&flow, name, &input);
+                for (target, payload) in sends {
+                    changed |= join_into(&mut flow.inputs, &target, payload);
+                }
+                if let Some(result) = result {
+                    changed |= join_into(&mut flow.results, name, result);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for name in &names {
+            let Some(input) = flow.inputs.get(*name).cloned() else {
+                continue;
+            };
+            let (sends, _) = self.step(&flow, name, &input);
+            for (target, payload) in sends {
+                self.check_edge(module, defs, name, &target, &payload);
+            }
+        }
+        self.payloads.insert(scope.map(|s| s.to_owned()), flow.inputs);
+        flow.results.get("input").cloned().unwrap_or(Type::Any)
+    }
+
+    fn check_edge(&mut self, module: &Module, defs: &Defs, from: &str, to: &str, payload: &Type) {
+        let expected = match JobType::from_name(to) {
+            Some(job) => from_root(&job.input_schema()),
+            None => match self.program.subgraphs.get(to) {
+                Some(graph) => entry_type(self.program.graph(*graph)),
+                None => return,
+            },
+        };
+
+        let Err(mismatch) = fits(payload, &expected, "") else {
+            return;
+        };
+        let msg = match mismatch {
+            Mismatch::Type {
+                path,
+                expected,
+                actual,
+            } => format!(
+                "{} needs {} to be {} but coming from {} it is {}",
+                to,
+                path,
+                expected.describe(),
+                from,
+                actual.describe()
+            ),
+            Mismatch::Missing(path) => format!("{} needs {} which isn't set coming from {}", to, path, from),
+            Mismatch::MaybeMissing(path) => {
+                format!("{} needs {} which isn't always set coming from {}", to, path, from)
+            }
+        };
+        let span = defs
+            .connections
+            .iter()
+            .find(|c| c.from == from && c.to == to)
+            .map(|c| c.span.clone())
+            .unwrap_or(0..0);
+        self.errors.push(with_module(build_error(&module.script, &span, &msg), module));
+    }
+}
+
+// A script can name its inputs like a subgraph does, `inputs="files, fix_warnings"`,
+// so jobs reading anything else are caught
+pub fn check(program: &Program) -> Vec<Error<Rule>> {
+    run(program).errors
+}
+
+// The payload each node gets, for showing in an editor
+pub fn payloads(program: &Program) -> HashMap<Option<String>, HashMap<String, Type>> {
+    run(program).payloads
+}
+
+fn run(program: &Program) -> Checker<'_> {
+    let mut checker = Checker {
+        program,
+        results: HashMap::new(),
+        errors: Vec::new(),
+        payloads: HashMap::new(),
+    };
+    checker.check_graph(None, &program.modules[0], program.main(), main_entry_type(program.main()));
+
+    let mut names: Vec<&String> = program.subgraphs.keys().collect();
+    names.sort();
+    for name in names {
+        checker.subgraph_result(name);
+    }
+    checker
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flowscript::modules::resolve;
+
+    fn errors(edges: &str) -> Vec<String> {
+        let script = format!("digraph {{\n  input;\n  {}\n}}", edges);
+        let program = resolve(&script, None).expect("Script parses");
+        check(&program).iter().map(|e| e.variant.message().to_string()).collect()
+    }
+
+    #[test]
+    fn jobs_get_what_the_caller_passes_in() {
+        assert!(errors("input -> Compile -> Output;").is_empty());
+        assert!(errors("input -> Compile -> Output -> SelectError -> FixCode;").is_empty());
+    }
+
+    #[test]
+    fn jobs_in_the_wrong_order_are_caught() {
+        assert_eq!(
+            errors("input -> Output -> Compile;"),
+            ["Output needs .errors which isn't set coming from input"]
+        );
+        assert_eq!(
+            errors("input -> FixCode;"),
+            ["FixCode needs .file_contents which isn't set coming from input"]
+        );
+        assert_eq!(
+            errors("input -> Compile -> FixCode;"),
+            ["FixCode needs .file_contents which isn't set coming from Compile"]
+        );
+    }
+
+    #[test]
+    fn named_inputs_replace_the_compile_input() {
+        let script = "digraph {\n  inputs=\"errors\";\n  input -> Compile;\n}";
+        let program = resolve(script, None).expect("Script parses");
+        assert_eq!(check(&program).len(), 1);
+    }
+}
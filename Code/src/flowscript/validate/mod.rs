@@ -0,0 +1,478 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::Range,
+};
+
+use pest::{
+    error::{Error, ErrorVariant, InputLocation},
+    Span,
+};
+
+use crate::system::types::JobType;
+
+use super::{
+    exec,
+    parser::{conditional, ConnectionDef, ConnectionType, Defs, ForEachMode, NodeDef, Rule, TaskOptions},
+};
+
+// Points an error from parsing an attribute of a node, like its label, at the
+// place in the attribute where parsing failed
+fn attribute_error(
+    script: &str,
+    span: &Range<usize>,
+    attribute: &str,
+    e: &Error<conditional::Rule>,
+    msg: &str,
+) -> Error<Rule> {
+    let quoted = format!("\"{}\"", attribute);
+    let start = script
+        .get(span.clone())
+        .and_then(|node| node.find(&quoted))
+        .map(|start| start + 1);
+    let (from, to) = match e.location {
+        InputLocation::Pos(at) => (at, at),
+        InputLocation::Span(span) => span,
+    };
+    match start {
+        Some(start) => {
+            let start = span.start + start;
+            let msg = format!("{}: {}", msg, e.variant.message());
+            build_error(script, &(start + from..start + to), &msg)
+        }
+        // Escapes and unquoted values read differently from how they're written
+        None => build_error(script, span, &format!("{}:\n{}", msg, e)),
+    }
+}
+
+pub fn build_error(script: &str, span: &Range<usize>, msg: &str) -> Error<Rule> {
+    let span = Span::new(script, span.start, span.end)
+        .unwrap_or_else(|| Span::new(script, 0, 0).expect("Empty span is valid"));
+    Error::new_from_span(
+        ErrorVariant::CustomError {
+            message: msg.to_string(),
+        },
+        span,
+    )
+}
+
+// Checks a parsed graph before it is turned into nodes. Every problem is
+// reported instead of stopping at the first one. Task nodes may also call any
+// of the `subgraphs`.
+pub fn validate(script: &str, defs: &Defs, subgraphs: &HashSet<&String>) -> Vec<Error<Rule>> {
+    let mut errors = Vec::new();
+    let span_of = |name: &str| defs.spans.get(name).cloned().unwrap_or(0..0);
+
+    // Sorted so errors come out in a stable order
+    let mut names: Vec<&String> = defs.variables.keys().collect();
+    names.sort_by_key(|name| (span_of(name).start, name.to_owned()));
+
+    for name in &names {
+        let outgoing: Vec<_> = defs.connections.iter().filter(|c| &c.from == *name).collect();
+        let defaults: Vec<_> = outgoing
+            .iter()
+            .filter(|c| c.c_type == ConnectionType::Default)
+            .collect();
+
+        let handlers: Vec<_> = outgoing
+            .iter()
+            .filter(|c| c.c_type == ConnectionType::OnError)
+            .collect();
+        for extra in handlers.iter().skip(1) {
+            errors.push(build_error(
+                script,
+                &extra.span,
+                &format!("Node {} has more than one on_error connection", name),
+            ));
+        }
+
+        for extra in defaults.iter().skip(1) {
+            errors.push(build_error(
+                script,
+                &extra.span,
+                &format!("Node {} has more than one default connection", name),
+            ));
+        }
+
+        let node = &defs.variables[*name];
+        let needs_default = matches!(node, NodeDef::Input | NodeDef::Count | NodeDef::Multi(_));
+        if needs_default && defaults.is_empty() {
+            errors.push(build_error(
+                script,
+                &span_of(name),
+                &format!("Node {} needs a connection to continue to", name),
+            ));
+        }
+
+        match node {
+            NodeDef::Task(_) if JobType::from_name(name).is_none() && !subgraphs.contains(name) => {
+                errors.push(build_error(
+                    script,
+                    &span_of(name),
+                    &format!("{} is not a known job type or subgraph", name),
+                ));
+            }
+            NodeDef::Task(options) if subgraphs.contains(name) && *options != TaskOptions::default() => {
+                errors.push(build_error(
+                    script,
+                    &span_of(name),
+                    &format!("Subgraph call {} can't retry or time out, only jobs can", name),
+                ));
+            }
+            // A job that times out keeps running, these would still prompt or
+            // write the file after the script moved on
+            NodeDef::Task(options)
+                if options.timeout.is_some()
+                    && JobType::from_name(name).is_some_and(|job| job.runs_on_host()) =>
+            {
+                errors.push(build_error(
+                    script,
+                    &span_of(name),
+                    &format!("{} runs on the main thread and can't time out", name),
+                ));
+            }
+            NodeDef::IfStatement(condition) => {
+                check_condition(script, name, condition, &span_of(name), &mut errors);
+                for branch in [true, false] {
+                    if !outgoing
+                        .iter()
+                        .any(|c| c.c_type == ConnectionType::IfResult(branch))
+                    {
+                        errors.push(build_error(
+                            script,
+                            &span_of(name),
+                            &format!("If node {} has no {} connection", name, branch),
+                        ));
+                    }
+                }
+            }
+            NodeDef::Switch(_) | NodeDef::Match(_) => {
+                let has_case = outgoing.iter().any(|c| {
+                    matches!(
+                        c.c_type,
+                        ConnectionType::SwitchBranch(_) | ConnectionType::MatchBranch(_)
+                    )
+                });
+                if !has_case {
+                    errors.push(build_error(
+                        script,
+                        &span_of(name),
+                        &format!("Node {} has no labeled branches", name),
+                    ));
+                }
+            }
+            NodeDef::Loop { condition, .. } => {
+                check_condition(script, name, condition, &span_of(name), &mut errors);
+                check_body(script, defs, name, true, &outgoing, &mut errors);
+            }
+            NodeDef::Setter(label) => {
+                if let Err(e) = conditional::parse_setter(label) {
+                    let msg = format!("Node {} has an invalid setter", name);
+                    errors.push(attribute_error(script, &span_of(name), label, &e, &msg));
+                }
+            }
+            NodeDef::Exec(def) => {
+                if let Err(e) = exec::config().and_then(|config| config.check(&def.command)) {
+                    errors.push(build_error(script, &span_of(name), &e));
+                }
+                for arg in &def.args {
+                    if let Err(e) = exec::check_template(arg) {
+                        errors.push(build_error(
+                            script,
+                            &span_of(name),
+                            &format!("Node {} has an invalid arg {}:\n{}", name, arg, e),
+                        ));
+                    }
+                }
+            }
+            NodeDef::ForEach(def) => {
+                if let Err(e) = conditional::check_path(&def.over) {
+                    let msg = format!("Node {} has an invalid path to go over", name);
+                    errors.push(attribute_error(script, &span_of(name), &def.over, &e, &msg));
+                }
+                if let Some(ref condition) = def.condition {
+                    check_condition(script, name, condition, &span_of(name), &mut errors);
+                }
+                let needs_body = def.mode != ForEachMode::Filter;
+                check_body(script, defs, name, needs_body, &outgoing, &mut errors);
+            }
+            _ => {}
+        }
+
+        // Branch edges only make sense coming out of the matching node type
+        for conn in &outgoing {
+            let fits = match conn.c_type {
+                ConnectionType::Default => true,
+                ConnectionType::IfResult(_) => matches!(node, NodeDef::IfStatement(_)),
+                ConnectionType::SwitchBranch(_) => matches!(node, NodeDef::Switch(_)),
+                ConnectionType::MatchBranch(_) => matches!(node, NodeDef::Match(_)),
+                ConnectionType::MultiOut => matches!(node, NodeDef::Multi(_)),
+                ConnectionType::OnError => matches!(node, NodeDef::Task(_) | NodeDef::Exec(_)),
+                ConnectionType::Body => match node {
+                    NodeDef::Loop { .. } => true,
+                    NodeDef::ForEach(def) => def.mode != ForEachMode::Filter,
+                    _ => false,
+                },
+            };
+            if !fits {
+                errors.push(build_error(
+                    script,
+                    &conn.span,
+                    &format!("{:?} connection can not come from node {}", conn.c_type, name),
+                ));
+            }
+        }
+    }
+
+    // Everything has to be reachable from input
+    let mut reachable = HashSet::new();
+    let mut queue = VecDeque::from(["input".to_owned()]);
+    while let Some(name) = queue.pop_front() {
+        if !reachable.insert(name.clone()) {
+            continue;
+        }
+        for conn in defs.connections.iter().filter(|c| c.from == name) {
+            queue.push_back(conn.to.clone());
+        }
+    }
+    for name in &names {
+        if !reachable.contains(*name) {
+            errors.push(build_error(
+                script,
+                &span_of(name),
+                &format!("Node {} can never be reached from input", name),
+            ));
+        }
+    }
+
+    // Only the whole script has a step limit
+    if let Some(max) = defs.attributes.get("max_steps") {
+        let start = defs.span.start..defs.span.start;
+        if defs.name.is_some() {
+            errors.push(build_error(script, &start, "max_steps can only be set on the main graph"));
+        } else if !max.parse::<usize>().is_ok_and(|max| max > 0) {
+            errors.push(build_error(
+                script,
+                &start,
+                &format!("max_steps has to be a whole number above 0, not {}", max),
+            ));
+        }
+    }
+
+    if let Some(cycle) = find_unconditional_cycle(defs) {
+        let span = defs
+            .connections
+            .iter()
+            .find(|c| c.from == cycle[cycle.len() - 2] && c.to == cycle[cycle.len() - 1])
+            .map(|c| c.span.clone())
+            .unwrap_or(0..0);
+        errors.push(build_error(
+            script,
+            &span,
+            &format!("Cycle without a condition to leave it: {}", cycle.join(" -> ")),
+        ));
+    }
+
+    errors
+}
+
+fn check_condition(
+    script: &str,
+    name: &str,
+    condition: &str,
+    span: &Range<usize>,
+    errors: &mut Vec<Error<Rule>>,
+) {
+    if let Err(e) = conditional::check_condition(condition) {
+        let msg = format!("Node {} has an invalid condition", name);
+        errors.push(attribute_error(script, span, condition, &e, &msg));
+    }
+}
+
+fn check_body(
+    script: &str,
+    defs: &Defs,
+    name: &str,
+    needs_body: bool,
+    outgoing: &[&ConnectionDef],
+    errors: &mut Vec<Error<Rule>>,
+) {
+    match outgoing.iter().find(|c| c.c_type == ConnectionType::Body) {
+        None if needs_body => errors.push(build_error(
+            script,
+            &defs.spans.get(name).cloned().unwrap_or(0..0),
+            &format!("Node {} has no body connection", name),
+        )),
+        // The body ends by running out of connections, going back into the
+        // node would start it again inside itself
+        Some(body) if leads_to(defs, &body.to, name) => errors.push(build_error(
+            script,
+            &body.span,
+            &format!("The body of {} leads back into it", name),
+        )),
+        _ => {}
+    }
+}
+
+fn leads_to(defs: &Defs, start: &str, target: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(name) = queue.pop_front() {
+        if name == target {
+            return true;
+        }
+        if !seen.insert(name) {
+            continue;
+        }
+        for conn in defs.connections.iter().filter(|c| c.from == name) {
+            queue.push_back(&conn.to);
+        }
+    }
+    false
+}
+
+// A loop is only allowed if one of its edges is a branch that can be skipped
+fn find_unconditional_cycle(defs: &Defs) -> Option<Vec<String>> {
+    let mut edges: HashMap<&str, Vec<&str>> = HashMap::new();
+    for conn in &defs.connections {
+        let conditional = match conn.c_type {
+            ConnectionType::IfResult(_)
+            | ConnectionType::SwitchBranch(_)
+            | ConnectionType::MatchBranch(_)
+            | ConnectionType::Body
+            | ConnectionType::OnError => true,
+            // A switch only follows its default when no case matched
+            ConnectionType::Default => {
+                matches!(defs.variables.get(&conn.from), Some(NodeDef::Switch(_)))
+            }
+            ConnectionType::MultiOut => false,
+        };
+        if !conditional {
+            edges.entry(&conn.from).or_default().push(&conn.to);
+        }
+    }
+
+    fn visit<'a>(
+        node: &'a str,
+        edges: &HashMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == node) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(node.to_owned());
+            return Some(cycle);
+        }
+        if !done.insert(node) {
+            return None;
+        }
+
+        path.push(node);
+        for next in edges.get(node).into_iter().flatten() {
+            if let Some(cycle) = visit(next, edges, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    let mut starts: Vec<&str> = edges.keys().copied().collect();
+    starts.sort();
+
+    let mut done = HashSet::new();
+    for start in starts {
+        if let Some(cycle) = visit(start, &edges, &mut Vec::new(), &mut done) {
+            return Some(cycle);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flowscript::parser::extract_definitions;
+
+    fn errors(body: &str) -> Vec<String> {
+        let script = format!("digraph {{\n{}\n}}", body);
+        let defs = extract_definitions(&script).expect("Script parses");
+        validate(&script, &defs, &HashSet::new())
+            .iter()
+            .map(|e| e.variant.message().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn nodes_input_never_reaches_are_reported() {
+        assert_eq!(
+            errors("  input -> Compile;\n  Output;"),
+            ["Node Output can never be reached from input"]
+        );
+    }
+
+    #[test]
+    fn if_nodes_need_both_branches() {
+        let script = "  input -> check;
+  check [kind=\"if\", label=\"files\"];
+  check -> Compile [label=\"true\"];";
+        assert_eq!(errors(script), ["If node check has no false connection"]);
+    }
+
+    #[test]
+    fn nodes_only_continue_one_way() {
+        assert_eq!(
+            errors("  input -> Compile;\n  input -> Output;"),
+            ["Node input has more than one default connection"]
+        );
+
+        let script = "  input -> Compile;
+  Compile -> first [kind=\"on_error\"];
+  Compile -> second [kind=\"on_error\"];
+  first [kind=\"set\", label=\"failed = true\"];
+  second [kind=\"set\", label=\"failed = true\"];";
+        assert_eq!(errors(script), ["Node Compile has more than one on_error connection"]);
+    }
+
+    #[test]
+    fn bodies_cant_lead_back_into_their_node() {
+        let script = "  input -> repeat;
+  repeat [kind=\"loop\", label=\".done != true\"];
+  repeat -> finish [kind=\"body\"];
+  finish [kind=\"set\", label=\"done = true\"];
+  finish -> repeat;";
+        assert_eq!(errors(script), ["The body of repeat leads back into it"]);
+    }
+
+    #[test]
+    fn cycles_need_a_way_out() {
+        let script = "  input -> first -> second -> first;
+  first [kind=\"set\", label=\"a = 1\"];
+  second [kind=\"set\", label=\"b = 1\"];";
+        assert_eq!(errors(script), ["Cycle without a condition to leave it: first -> second -> first"]);
+    }
+
+    #[test]
+    fn invalid_conditions_are_reported_in_their_label() {
+        let script = "digraph {
+  input -> check;
+  check [kind=\"if\", label=\".a == == 1\"];
+  check -> Compile [label=\"true\"];
+  check -> Output [label=\"false\"];
+}";
+        let defs = extract_definitions(script).unwrap();
+        let errors = validate(script, &defs, &HashSet::new());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].variant.message().starts_with("Node check has an invalid condition: "));
+        assert_eq!(errors[0].line_col, pest::error::LineColLocation::Span((3, 34), (3, 34)));
+    }
+
+    #[test]
+    fn invalid_setters_are_reported_once_in_their_label() {
+        let script = "digraph {\n  input -> a;\n  a [kind=\"set\", label=\"x = = 1\"];\n}";
+        let defs = extract_definitions(script).unwrap();
+        let errors = validate(script, &defs, &HashSet::new());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].variant.message(), "Node a has an invalid setter: expected setter");
+        assert_eq!(errors[0].line_col, pest::error::LineColLocation::Span((3, 25), (3, 25)));
+    }
+}
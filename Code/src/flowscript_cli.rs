@@ -0,0 +1,330 @@
+use std::{
+    env, fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Subcommand, ValueEnum};
+use indicatif::ProgressBar;
+use serde_json::Value;
+
+use crate::{
+    ai::{Message, Role},
+    flows,
+    flowscript::{self, trace::TraceFormat},
+    fs_prompt,
+    report::Outcome,
+    system,
+    ui::{self, FlowOption},
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Svg,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FlowscriptCommand {
+    /// Parse and validate a script
+    Check { script: PathBuf },
+
+    /// Run a script and print the resulting JSON
+    Run {
+        script: PathBuf,
+
+        #[arg(short, long, help = "JSON input file, reads stdin when left out or set to -")]
+        input: Option<PathBuf>,
+
+        #[arg(long, help = "Record every step the script takes to this file")]
+        trace: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value = "jsonl", requires = "trace")]
+        trace_format: TraceFormat,
+
+        #[arg(long, help = "Print how often each node ran and how long it took", conflicts_with = "trace")]
+        metrics: bool,
+    },
+
+    /// Run a script, pausing before nodes marked `breakpoint="true"` or named with --break
+    Debug {
+        script: PathBuf,
+
+        #[arg(short, long, help = "JSON input file")]
+        input: PathBuf,
+
+        #[arg(long = "break", help = "Pause before this node, can be repeated")]
+        breakpoints: Vec<String>,
+    },
+
+    /// Step through a trace recorded with `run --trace`
+    Replay {
+        trace: PathBuf,
+
+        #[arg(short, long, help = "Wait for enter after every step, q to stop", default_value = "false")]
+        step: bool,
+
+        #[arg(short, long, help = "Print whole payloads instead of what changed", default_value = "false")]
+        full: bool,
+    },
+
+    /// Print a script in canonical form
+    Fmt {
+        script: PathBuf,
+
+        #[arg(short, long, help = "Rewrite the file instead of printing it", default_value = "false")]
+        write: bool,
+    },
+
+    /// Give every node an explicit kind instead of a shape that picks its type
+    Migrate {
+        script: PathBuf,
+
+        #[arg(short, long, help = "Rewrite the file instead of printing it", default_value = "false")]
+        write: bool,
+    },
+
+    /// Ask ChatGPT to write a script from a description and save it as a flow
+    New {
+        description: String,
+
+        #[arg(short, long, help = "Name to save the flow as in .code-agent/flows")]
+        name: String,
+
+        #[arg(short, long, help = "Project directory to save the flow in", default_value = ".")]
+        directory: PathBuf,
+
+        #[arg(long, help = "Also write the graph as SVG to this file")]
+        svg: Option<PathBuf>,
+
+        #[arg(short, long, help = "Save the first script that validates without asking", default_value = "false")]
+        yes: bool,
+    },
+
+    /// Export a script as a Graphviz graph
+    Graph {
+        script: PathBuf,
+
+        #[arg(short, long, value_enum, default_value = "dot")]
+        format: GraphFormat,
+
+        #[arg(short, long, help = "Write to a file instead of stdout")]
+        output: Option<PathBuf>,
+    },
+
+    /// Serve diagnostics, completion, hover, go to definition and rename to editors over stdio
+    Lsp,
+}
+
+pub fn run(command: FlowscriptCommand) -> Result<Outcome> {
+    match command {
+        FlowscriptCommand::Check { script } => {
+            let text = fs::read_to_string(&script)?;
+            if flowscript::check_flowscript(&text, Some(&script)).is_err() {
+                return Ok(Outcome::Error);
+            }
+            println!("{} is valid", script.to_string_lossy());
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Run {
+            script,
+            input,
+            trace,
+            trace_format,
+            metrics,
+        } => {
+            let text = fs::read_to_string(&script)?;
+            let input = read_input(input)?;
+
+            let workers = system::Workers::start();
+            let result = match trace {
+                Some(ref trace) => {
+                    flowscript::trace_flowscript(&text, Some(&script), input, trace, trace_format)
+                }
+                None if metrics => flowscript::profile_flowscript(&text, Some(&script), input),
+                None => flowscript::execute_flowscript_json(&text, Some(&script), input),
+            };
+            drop(workers);
+
+            println!("{}", serde_json::to_string_pretty(&result?)?);
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Debug {
+            script,
+            input,
+            breakpoints,
+        } => {
+            let text = fs::read_to_string(&script)?;
+            // Stdin is left for the debugger prompt
+            let input = read_input(Some(input))?;
+
+            let workers = system::Workers::start();
+            let result = flowscript::debug_flowscript(&text, Some(&script), input, breakpoints);
+            drop(workers);
+
+            println!("{}", serde_json::to_string_pretty(&result?)?);
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Lsp => {
+            flowscript::lsp::serve()?;
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Replay { trace, step, full } => {
+            let steps = flowscript::trace::read_trace(&trace)?;
+            flowscript::trace::replay(&steps, step, full)?;
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Fmt { script, write } => {
+            let text = fs::read_to_string(&script)?;
+            let formatted = flowscript::format_flowscript(&text)?;
+            if write {
+                fs::write(&script, formatted)?;
+            } else {
+                print!("{}", formatted);
+            }
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::Migrate { script, write } => {
+            let text = fs::read_to_string(&script)?;
+            let migration = flowscript::migrate_flowscript(&text)?;
+            if !write {
+                print!("{}", migration.script);
+            } else if migration.nodes + migration.edges == 0 {
+                println!("Nothing to migrate in {}", script.display());
+            } else {
+                fs::write(&script, &migration.script)?;
+                println!(
+                    "Migrated {} node and {} edge statement(s) in {}",
+                    migration.nodes,
+                    migration.edges,
+                    script.display()
+                );
+            }
+            Ok(Outcome::Clean)
+        }
+
+        FlowscriptCommand::New {
+            description,
+            name,
+            directory,
+            svg,
+            yes,
+        } => new_flow(&description, &name, &directory, svg, yes),
+
+        FlowscriptCommand::Graph {
+            script,
+            format,
+            output,
+        } => {
+            let text = fs::read_to_string(&script)?;
+            let dot = flowscript::flowscript_to_dot(&text, Some(&script))?;
+            let rendered = match format {
+                GraphFormat::Dot => dot,
+                GraphFormat::Svg => render_svg(&dot)?,
+            };
+            match output {
+                Some(path) => fs::write(path, rendered)?,
+                None => print!("{}", rendered),
+            }
+            Ok(Outcome::Clean)
+        }
+    }
+}
+
+// Generated flows are tried out on the job system before they are shown
+fn new_flow(description: &str, name: &str, directory: &Path, svg: Option<PathBuf>, yes: bool) -> Result<Outcome> {
+    if env::var("OPENAI_TOKEN").is_err() {
+        println!("Please set OPENAI_TOKEN in .env");
+        return Ok(Outcome::Error);
+    }
+    let path = flows::path(directory, name)?;
+    write_flow(description, name, directory, &path, svg, yes)
+}
+
+// Keeps asking for changes until the user saves or quits
+fn write_flow(
+    description: &str,
+    name: &str,
+    directory: &Path,
+    path: &Path,
+    svg: Option<PathBuf>,
+    yes: bool,
+) -> Result<Outcome> {
+    let mut prompt = fs_prompt::describe_flowscript(description);
+    loop {
+        let spin = ProgressBar::new_spinner();
+        spin.enable_steady_tick(Duration::from_millis(100));
+        spin.set_message("Getting flowscript...");
+        let script = fs_prompt::generate(&mut prompt, crate::dry_run_new_flow);
+        spin.finish_and_clear();
+        let script = script?;
+
+        print!("{}", flowscript::flowscript_outline(&script)?);
+        println!("-----------------------------------------");
+        println!("{}", script);
+        println!("-----------------------------------------");
+        if let Some(ref svg) = svg {
+            fs::write(svg, render_svg(&flowscript::flowscript_to_dot(&script, None)?)?)?;
+            println!("Wrote the graph to {}", svg.to_string_lossy());
+        }
+
+        let choice = match yes {
+            true => FlowOption::Save,
+            false => ui::prompt_flow_options(path),
+        };
+        match choice {
+            FlowOption::Save => {
+                let path = flows::save(directory, name, &script)?;
+                println!("Saved the new flow to {}", path.to_string_lossy());
+                return Ok(Outcome::Clean);
+            }
+            FlowOption::Change(change) => prompt.push(Message {
+                role: Role::User,
+                content: format!("Please change it: {}", change),
+            }),
+            FlowOption::Quit => return Ok(Outcome::Clean),
+        }
+    }
+}
+
+fn read_input(path: Option<PathBuf>) -> Result<Value> {
+    let text = match path {
+        Some(path) if path.as_os_str() != "-" => fs::read_to_string(path)?,
+        _ => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        }
+    };
+    Ok(serde_json::from_str(&text)?)
+}
+
+fn render_svg(dot: &str) -> Result<String> {
+    let mut child = Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|_| anyhow!("SVG output needs Graphviz `dot` on the PATH"))?;
+
+    child
+        .stdin
+        .take()
+        .expect("Stdin is piped")
+        .write_all(dot.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("dot failed: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
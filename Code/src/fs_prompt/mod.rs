@@ -1,64 +1,180 @@
-use std::{env, fs, vec};
+use anyhow::{anyhow, Result};
 
-use anyhow::Result;
+use crate::{
+    ai::{make_ai_request, Message, Model, Role},
+    flowscript,
+    system::types::JobType,
+};
 
-use crate::ai::{make_ai_request, Message, Model, Role};
+// Times the errors are sent back before giving up on a response
+const MAX_REPAIRS: usize = 3;
 
-pub fn get_flowscript_compile(reprompt: bool) -> Result<String> {
-    if reprompt {
-        get_flowscript_from_gpt()
-    } else if let Some(flowscript) = get_saved_flowscript() {
-        Ok(flowscript)
-    } else {
-        get_flowscript_from_gpt()
+// Asks for the compile pipeline, the flow used when nothing else is named
+pub fn get_flowscript_from_gpt(dry_run: impl Fn(&str) -> Result<()>) -> Result<String> {
+    let mut prompt = get_prompt("Please give me a flowscript file that runs the \"Compile\" job and then the \"Output\" job.");
+    generate(&mut prompt, dry_run)
+}
+
+// Asks for a flow that does what the user described
+pub fn describe_flowscript(description: &str) -> Vec<Message> {
+    get_prompt(&format!("Please give me a flowscript file that does this: {}", description))
+}
+
+// Asks for a script until one validates and passes `dry_run`, sending the
+// errors back to ChatGPT after every failed attempt. The accepted script is
+// added to the prompt so the conversation can go on.
+pub fn generate(prompt: &mut Vec<Message>, dry_run: impl Fn(&str) -> Result<()>) -> Result<String> {
+    generate_with(prompt, dry_run, |prompt| {
+        let response = make_ai_request(prompt, &Model::ChatGpt)?;
+
+        // Get the content from the first choice
+        match response.choices.first() {
+            Some(choice) => Ok(choice.message.content.clone()),
+            None => Err(anyhow!("ChatGPT did not answer")),
+        }
+    })
+}
+
+fn generate_with(
+    prompt: &mut Vec<Message>,
+    dry_run: impl Fn(&str) -> Result<()>,
+    mut ask: impl FnMut(&[Message]) -> Result<String>,
+) -> Result<String> {
+    for round in 0..=MAX_REPAIRS {
+        let script = extract_script(&ask(prompt)?);
+        prompt.push(Message {
+            role: Role::Assistant,
+            content: script.clone(),
+        });
+
+        let errors = flowscript::flowscript_errors(&script);
+        let problem = match errors.is_empty() {
+            true => match dry_run(&script) {
+                Ok(()) => return Ok(script),
+                Err(e) => format!("The script is valid but running it failed: {}", e),
+            },
+            false => errors.join("\n"),
+        };
+
+        if round == MAX_REPAIRS {
+            break;
+        }
+        println!("The generated flowscript has problems, asking for a fix ({}/{})", round + 1, MAX_REPAIRS);
+        prompt.push(Message {
+            role: Role::User,
+            content: format!(
+                "That flowscript doesn't work:\n{}\nReply with the whole corrected file only.",
+                problem
+            ),
+        });
     }
+
+    Err(anyhow!("ChatGPT did not write a working flowscript in {} tries", MAX_REPAIRS + 1))
 }
 
-pub fn save_flowscript(prompt: &str) -> Result<()> {
-    if let Ok(home) = env::var("HOME") {
-        let path = format!("{}/.fsprompt", home);
-        fs::write(path, prompt)?;
+// Answers can come wrapped in a code block or with an explanation around them,
+// only the graph is kept
+fn extract_script(response: &str) -> String {
+    let mut text = response.trim();
+    if let Some(start) = text.find("```") {
+        let fenced = &text[start + 3..];
+        // Skips the language name after the opening fence
+        let fenced = fenced.split_once('\n').map_or(fenced, |(_, rest)| rest);
+        text = fenced.find("```").map_or(fenced, |end| &fenced[..end]);
+    }
+
+    match (text.find("digraph"), text.rfind('}')) {
+        (Some(start), Some(end)) if start < end => text[start..=end].to_owned(),
+        _ => text.trim().to_owned(),
     }
-    Ok(())
 }
 
-fn get_flowscript_from_gpt() -> Result<String> {
-    let prompt = get_prompt();
+// The node kinds and jobs are listed from this build so the model only ever
+// hears about what the parser accepts
+fn reference() -> String {
+    let mut text = include_str!("./prompt.txt").to_owned();
 
-    let response = make_ai_request(&prompt, &Model::ChatGpt)?;
+    text.push_str("\nNode kinds, set with the kind attribute of a node:\n");
+    for kind in flowscript::NODE_KINDS {
+        text.push_str(&format!("- {}: {}\n", kind, flowscript::kind_help(kind)));
+    }
 
-    // Get the first choice
-    let choice = response.choices.first().unwrap();
+    text.push_str("\nEdge kinds, set with the kind attribute of an edge:\n");
+    for kind in flowscript::EDGE_KINDS {
+        text.push_str(&format!("- {}: {}\n", kind, flowscript::edge_kind_help(kind)));
+    }
 
-    // Get the content from the message
-    let content = choice.message.content.clone();
+    text.push_str("\nJobs, a node named after one runs it. `?` marks fields that can be left out:\n");
+    for job in JobType::ALL {
+        text.push_str(&format!(
+            "- {}: {}\n  reads {}\n  gives back {}\n",
+            job.name(),
+            job.description(),
+            flowscript::schema_signature(&job.input_schema()),
+            flowscript::schema_signature(&job.output_schema())
+        ));
+    }
+    text
+}
 
-    Ok(content)
+fn get_prompt(request: &str) -> Vec<Message> {
+    vec![
+        Message {
+            role: Role::System,
+            content: reference(),
+        },
+        Message {
+            role: Role::User,
+            content: request.to_owned(),
+        },
+    ]
 }
 
-fn get_prompt() -> Vec<Message> {
-    let mut result: Vec<Message> = vec::Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    result.push(Message {
-        role: Role::System,
-        content: include_str!("./prompt.txt").to_string(),
-    });
+    const SCRIPT: &str = "digraph {\n  input -> Compile;\n}";
 
-    result
-}
+    #[test]
+    fn scripts_are_cut_out_of_the_answer() {
+        assert_eq!(extract_script(SCRIPT), SCRIPT);
+        assert_eq!(extract_script(&format!("```dot\n{}\n```", SCRIPT)), SCRIPT);
+        assert_eq!(extract_script(&format!("Here it is:\n```\n{}\n```\nIt compiles.", SCRIPT)), SCRIPT);
+        assert_eq!(extract_script(&format!("Sure! {} Hope that helps", SCRIPT)), SCRIPT);
+        assert_eq!(extract_script("  I can't do that  "), "I can't do that");
+    }
 
-fn get_saved_flowscript() -> Option<String> {
-    // Check for .fsprompt in home directory
-    // If it exists, return the contents
-    // If it doesn't exist, return None
-
-    if let Ok(home) = env::var("HOME") {
-        let path = format!("{}/.fsprompt", home);
-        if let Ok(contents) = fs::read_to_string(path) {
-            if !contents.is_empty() {
-                return Some(contents);
-            }
-        }
+    #[test]
+    fn problems_are_sent_back_until_a_script_works() {
+        let answers = ["digraph {\n  input -> Nope;\n}", "digraph {\n  input -> Compile -> Output;\n}", SCRIPT];
+        let mut answers = answers.into_iter();
+        let mut prompt = get_prompt("A compile flow");
+        let dry_run = |script: &str| match script.contains("Output") {
+            true => Err(anyhow!("it found no errors")),
+            false => Ok(()),
+        };
+        let script = generate_with(&mut prompt, dry_run, |_| Ok(answers.next().unwrap().to_owned())).unwrap();
+        assert_eq!(script, SCRIPT);
+
+        // Every answer and every problem with it stays in the conversation
+        let repairs: Vec<_> = prompt[2..].iter().filter(|m| matches!(m.role, Role::User)).collect();
+        assert_eq!(repairs.len(), 2);
+        assert!(repairs[0].content.contains("Nope"), "{}", repairs[0].content);
+        assert!(repairs[1].content.contains("running it failed: it found no errors"));
+        assert_eq!(prompt.last().unwrap().content, SCRIPT);
+    }
+
+    #[test]
+    fn generating_gives_up_after_the_last_repair() {
+        let mut asked = 0;
+        let mut prompt = get_prompt("A compile flow");
+        let error = generate_with(&mut prompt, |_| Ok(()), |_| {
+            asked += 1;
+            Ok("digraph {\n  input -> Nope;\n}".to_owned())
+        })
+        .unwrap_err();
+        assert_eq!(asked, MAX_REPAIRS + 1);
+        assert!(error.to_string().contains("in 4 tries"), "{}", error);
     }
-    None
 }
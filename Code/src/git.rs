@@ -1,7 +1,13 @@
-use std::path::PathBuf;
+use std::{
+    cell::Cell,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use git2::Repository;
+use anyhow::{anyhow, Result};
+use git2::{build::CheckoutBuilder, Index, Repository, ResetType, Signature};
 
+use crate::output::MappedJsonError;
 
 pub fn check_unsaved_files(path: &PathBuf) -> bool {
     let Ok(repo) = Repository::open(path) else {
@@ -41,3 +47,201 @@ pub fn check_unsaved_files(path: &PathBuf) -> bool {
 
     false
 }
+
+// Commits every accepted fix on its own branch so they can be reviewed individually
+pub struct FixCommitter {
+    repo: Repository,
+    pub branch: String,
+    commits: Cell<usize>,
+}
+
+impl FixCommitter {
+    pub fn start(path: &Path) -> Result<FixCommitter> {
+        let repo = Repository::discover(path)?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let branch = format!("code-agent/{}", timestamp);
+        repo.branch(&branch, &repo.head()?.peel_to_commit()?, false)?;
+
+        // --commit-fixes refuses dirty trees, so the working tree already
+        // matches HEAD and only HEAD has to move
+        repo.set_head(&format!("refs/heads/{}", branch))?;
+
+        Ok(FixCommitter {
+            repo,
+            branch,
+            commits: Cell::new(0),
+        })
+    }
+
+    pub fn commit_fix(&self, error: &MappedJsonError, explanation: &str) -> Result<()> {
+        let workdir = self
+            .repo
+            .workdir()
+            .ok_or(anyhow!("Repository has no working directory"))?
+            .canonicalize()?;
+        let file = error.filepath.canonicalize()?;
+        let relative = file.strip_prefix(&workdir)?;
+
+        // The tree is HEAD's with only the fixed file swapped, whatever else is
+        // staged stays out of the commit
+        let parent = self.repo.head()?.peel_to_commit()?;
+        let mut index = Index::new()?;
+        index.read_tree(&parent.tree()?)?;
+        let mut entry = index
+            .get_path(relative, 0)
+            .ok_or(anyhow!("{} is not tracked by git", relative.display()))?;
+        entry.id = self.repo.blob_path(&file)?;
+        entry.file_size = std::fs::metadata(&file)?.len() as u32;
+        index.add(&entry)?;
+        let tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+
+        // Keeps `git status` clean for the fixed file
+        let mut staged = self.repo.index()?;
+        staged.add(&entry)?;
+        staged.write()?;
+
+        let signature = self
+            .repo
+            .signature()
+            .or_else(|_| Signature::now("code-agent", "code-agent@localhost"))?;
+
+        let message = format!("{}\n\n{}\n", error.message.trim(), explanation.trim());
+        self.repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &[&parent],
+        )?;
+        self.commits.set(self.commits.get() + 1);
+        Ok(())
+    }
+
+    // Drops the last fix commit and puts the file it fixed back the way the parent
+    // has it, in the index and the working tree. Anything else the user staged stays.
+    pub fn undo_last(&self) -> Result<()> {
+        if self.commits.get() == 0 {
+            return Ok(());
+        }
+
+        let head = self.repo.head()?.peel_to_commit()?;
+        let parent = head.parent(0)?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(Some(&parent.tree()?), Some(&head.tree()?), None)?;
+        let fixed: Vec<PathBuf> = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+            .collect();
+
+        // The user may have staged more since the fix, the index is read again so it's kept
+        self.repo.index()?.read(true)?;
+        self.repo.reset(parent.as_object(), ResetType::Soft, None)?;
+        if !fixed.is_empty() {
+            self.repo.reset_default(Some(parent.as_object()), &fixed)?;
+            let mut checkout = CheckoutBuilder::new();
+            checkout.force();
+            for path in &fixed {
+                checkout.path(path);
+            }
+            self.repo.checkout_tree(parent.as_object(), Some(&mut checkout))?;
+        }
+        self.commits.set(self.commits.get() - 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn commit_all(repo: &Repository, message: &str) {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("test", "test@localhost").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+            .unwrap();
+    }
+
+    fn main_error(dir: &Path) -> MappedJsonError {
+        MappedJsonError {
+            column: 21,
+            line: 1,
+            filepath: dir.join("main.cpp"),
+            message: "x was not declared".to_owned(),
+            snippet: String::new(),
+        }
+    }
+
+    #[test]
+    fn fix_commits_leave_staged_files_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("main.cpp"), "int main() { return x; }\n").unwrap();
+        fs::write(dir.path().join("other.cpp"), "int y;\n").unwrap();
+        commit_all(&repo, "start");
+
+        let committer = FixCommitter::start(dir.path()).unwrap();
+
+        // Staged by the user while the agent runs
+        fs::write(dir.path().join("other.cpp"), "int z;\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("other.cpp")).unwrap();
+        index.write().unwrap();
+
+        fs::write(dir.path().join("main.cpp"), "int main() { return 0; }\n").unwrap();
+        committer.commit_fix(&main_error(dir.path()), "Return 0").unwrap();
+
+        let tree = repo.head().unwrap().peel_to_commit().unwrap().tree().unwrap();
+        let content = |name: &str| {
+            let blob = repo.find_blob(tree.get_name(name).unwrap().id()).unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        };
+        assert_eq!(content("main.cpp"), "int main() { return 0; }\n");
+        assert_eq!(content("other.cpp"), "int y;\n");
+
+        // What the user staged is still staged
+        let status = repo.status_file(Path::new("other.cpp")).unwrap();
+        assert!(status.is_index_modified());
+        assert!(repo.status_file(Path::new("main.cpp")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn undoing_a_fix_commit_only_restores_the_fixed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("main.cpp"), "int main() { return x; }\n").unwrap();
+        fs::write(dir.path().join("other.cpp"), "int y;\n").unwrap();
+        commit_all(&repo, "start");
+        let start = repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let committer = FixCommitter::start(dir.path()).unwrap();
+        fs::write(dir.path().join("main.cpp"), "int main() { return 0; }\n").unwrap();
+        committer.commit_fix(&main_error(dir.path()), "Return 0").unwrap();
+
+        // Staged by the user after the fix
+        fs::write(dir.path().join("other.cpp"), "int z;\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("other.cpp")).unwrap();
+        index.write().unwrap();
+
+        committer.undo_last().unwrap();
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), start);
+        assert_eq!(fs::read_to_string(dir.path().join("main.cpp")).unwrap(), "int main() { return x; }\n");
+        assert!(repo.status_file(Path::new("main.cpp")).unwrap().is_empty());
+        assert!(repo.status_file(Path::new("other.cpp")).unwrap().is_index_modified());
+        assert_eq!(fs::read_to_string(dir.path().join("other.cpp")).unwrap(), "int z;\n");
+
+        // Nothing left to undo
+        committer.undo_last().unwrap();
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), start);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::files;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JournalEntry {
+    path: PathBuf,
+    before: String,
+}
+
+// Records the contents of every file before a fix is written so a session can be
+// rolled back, even after a crash. One JSON line is appended per fix, the session
+// is only written to disk once it has one.
+pub struct Journal {
+    pub id: String,
+    file: PathBuf,
+}
+
+fn sessions_dir(directory: &Path) -> PathBuf {
+    directory.join(".code-agent").join("sessions")
+}
+
+impl Journal {
+    pub fn start(directory: &Path) -> Result<Journal> {
+        // Sessions started at the same time, even from two processes, never
+        // share a journal
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let id = format!("{}-{}", nanos, std::process::id());
+        let file = sessions_dir(directory).join(&id).join("journal.jsonl");
+        Ok(Journal { id, file })
+    }
+
+    // Writes the session out for its first fix
+    fn create(&self) -> Result<()> {
+        let session = self.file.parent().ok_or(anyhow!("Journal {} has no session", self.id))?;
+        let sessions = session.parent().ok_or(anyhow!("Session {} has no directory", self.id))?;
+        fs::create_dir_all(sessions)?;
+
+        // Keep journals out of `git status` so they don't trip the dirty check
+        let ignore = sessions.join(".gitignore");
+        if !ignore.exists() {
+            fs::write(ignore, "*\n")?;
+        }
+
+        fs::create_dir(session)?;
+        OpenOptions::new().write(true).create_new(true).open(&self.file)?;
+        Ok(())
+    }
+
+    // Opens a previous session, or the most recent one when no id is given
+    pub fn open(directory: &Path, id: Option<&str>) -> Result<Journal> {
+        let sessions = sessions_dir(directory);
+        let id = match id {
+            Some(id) => id.to_owned(),
+            None => {
+                let mut ids: Vec<String> = fs::read_dir(&sessions)?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path().is_dir())
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .collect();
+                // Ids start with when the session started
+                ids.sort_by_key(|id| {
+                    let started = id.split('-').next().unwrap_or_default();
+                    started.parse::<u128>().unwrap_or_default()
+                });
+                ids.pop().ok_or(anyhow!("No sessions found"))?
+            }
+        };
+
+        let file = sessions.join(&id).join("journal.jsonl");
+        if !file.exists() {
+            return Err(anyhow!("No session with id {}", id));
+        }
+        Ok(Journal { id, file })
+    }
+
+    pub fn record(&self, path: &Path, before: &str) -> Result<()> {
+        let entry = JournalEntry {
+            path: path.canonicalize()?,
+            before: before.to_owned(),
+        };
+        if !self.file.exists() {
+            self.create()?;
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&self.file)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        // Must be on disk before the fix is written
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<JournalEntry>> {
+        if !self.file.exists() {
+            return Ok(Vec::new());
+        }
+        fs::read_to_string(&self.file)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    fn save(&self, entries: &[JournalEntry]) -> Result<()> {
+        let mut contents = String::new();
+        for entry in entries {
+            contents.push_str(&serde_json::to_string(entry)?);
+            contents.push('\n');
+        }
+        fs::write(&self.file, contents)?;
+        Ok(())
+    }
+
+    // Restores the file changed by the last fix and returns its path
+    pub fn undo_last(&self) -> Result<Option<PathBuf>> {
+        let mut entries = self.entries()?;
+        let Some(entry) = entries.pop() else {
+            return Ok(None);
+        };
+
+        files::write_atomic(&entry.path, &entry.before)?;
+        self.save(&entries)?;
+        Ok(Some(entry.path))
+    }
+
+    pub fn undo_all(&self) -> Result<Vec<PathBuf>> {
+        let mut restored = Vec::new();
+        while let Some(path) = self.undo_last()? {
+            restored.push(path);
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.cpp");
+        let util = dir.path().join("util.cpp");
+        fs::write(&main, "int main() {}\n").unwrap();
+        fs::write(&util, "int util() {}\n").unwrap();
+        (dir, main.canonicalize().unwrap(), util.canonicalize().unwrap())
+    }
+
+    // Records what the file held and writes the fix, like the workspace does
+    fn fix(journal: &Journal, path: &Path, code: &str) {
+        journal.record(path, &fs::read_to_string(path).unwrap()).unwrap();
+        fs::write(path, code).unwrap();
+    }
+
+    #[test]
+    fn sessions_without_fixes_leave_nothing_behind() {
+        let (dir, _, _) = project();
+        let journal = Journal::start(dir.path()).unwrap();
+        assert_eq!(journal.undo_last().unwrap(), None);
+        assert!(!sessions_dir(dir.path()).exists());
+        assert!(Journal::open(dir.path(), None).is_err());
+    }
+
+    #[test]
+    fn undo_last_restores_the_last_fix_only() {
+        let (dir, main, util) = project();
+        let journal = Journal::start(dir.path()).unwrap();
+        fix(&journal, &main, "int main() { return 0; }\n");
+        fix(&journal, &util, "int util() { return 1; }\n");
+
+        assert_eq!(journal.undo_last().unwrap(), Some(util.clone()));
+        assert_eq!(fs::read_to_string(&util).unwrap(), "int util() {}\n");
+        assert_eq!(fs::read_to_string(&main).unwrap(), "int main() { return 0; }\n");
+        assert_eq!(fs::read_to_string(sessions_dir(dir.path()).join(".gitignore")).unwrap(), "*\n");
+    }
+
+    #[test]
+    fn undo_all_restores_every_fix_from_the_latest_session() {
+        let (dir, main, util) = project();
+        let first = Journal::start(dir.path()).unwrap();
+        fix(&first, &util, "int util() { return 1; }\n");
+        let second = Journal::start(dir.path()).unwrap();
+        fix(&second, &main, "int main() { return 0; }\n");
+        fix(&second, &main, "int main() { return 1; }\n");
+        // A later session that never fixed anything isn't the latest one
+        Journal::start(dir.path()).unwrap();
+
+        let latest = Journal::open(dir.path(), None).unwrap();
+        assert_eq!(latest.id, second.id);
+        assert_eq!(latest.undo_all().unwrap(), [main.clone(), main.clone()]);
+        assert_eq!(fs::read_to_string(&main).unwrap(), "int main() {}\n");
+        assert_eq!(fs::read_to_string(&util).unwrap(), "int util() { return 1; }\n");
+        assert_eq!(latest.undo_last().unwrap(), None);
+    }
+}
@@ -1,42 +1,98 @@
 use ai::{FixCodeJob, FixCodeResult};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
-use git::check_unsaved_files;
+use git::{check_unsaved_files, FixCommitter};
 use indicatif::ProgressBar;
-use std::{env, fs, path::PathBuf, time::Duration};
+use serde_json::{json, Value};
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 use system::types::JobType;
 use ui::{tweak_code, MenuOption};
 
-use anyhow::Result;
-
-use fs_prompt::get_flowscript_compile;
+use anyhow::{anyhow, Result};
 
 use crate::{
     compiler::CompileJob,
-    fs_prompt::save_flowscript,
+    files::{ApplyFixJob, ApplyFixResult},
+    flowscript_cli::FlowscriptCommand,
+    flows::Flow,
     output::MappedJsonError,
-    ui::{prompt_options, render_fix_code_result},
+    journal::Journal,
+    report::{FixStatus, Outcome, Report},
+    ui::{prompt_options, render_fix_code_result, ApproveResult},
+    workspace::{Overlay, Workspace},
 };
 
 mod ai; // Sends requests to ChatGPT
 mod compiler; // Compiles provides c++ source code
 mod files; // Utility for default file input
 mod flowscript; // Parse and execute Flowscript
+mod flowscript_cli; // The `flowscript` subcommands
+mod flows; // Finds named flows in the project, the home directory or the binary
 mod fs_prompt; // Asks ChatGPT to write Flowscript
-mod git; // Checks to make sure there are no uncommitted changes
+mod git; // Checks for uncommitted changes and commits accepted fixes
+mod journal; // Records file contents before each fix so a session can be undone
 mod output; // Maps the g++ error json shape to the desired shape
+mod report; // Records every fix for --report and maps outcomes to exit codes
 mod system; // Job System and C++ bindings
 mod ui; // Renders console output
+mod workspace; // Applies fixes to disk or to an in-memory overlay for --dry-run
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Policy {
+    /// Apply a fix only if the code compiles with it: the error it fixes is gone and no new ones appear
+    AutoAccept,
+    /// Never touch the files, only record the suggested fixes
+    ProposeOnly,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Restore the files changed by a previous session
+    Undo {
+        #[arg(long, help = "Session to undo, defaults to the most recent one")]
+        session: Option<String>,
+
+        #[arg(long, help = "Undo every fix in the session instead of just the last one", default_value = "false")]
+        all: bool,
+
+        #[arg(short, long, help = "Project directory the session was run in", default_value = ".")]
+        directory: PathBuf,
+    },
+
+    /// Check, run, debug, replay, format, migrate, graph or write a Flowscript file, or serve it to editors
+    Flowscript {
+        #[command(subcommand)]
+        command: FlowscriptCommand,
+    },
+}
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    after_help = "Exit codes: 0 = clean, 1 = fixed, 2 = unfixable errors remain, 3 = agent error"
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(num_args = 1.., value_delimiter = ' ', help = "Input files")]
     files: Vec<PathBuf>,
 
     #[arg(short, long, help="Reprompts ChatGPT to get a new flowscript file", default_value = "false")]
     reprompt_flowscript: bool,
 
+    #[arg(long, help = "Compile with this flow from .code-agent/flows", default_value = flows::DEFAULT_FLOW)]
+    flow: String,
+
     #[arg(short, long, name = "Directory", help="Compile all C++ files in directory", default_value = ".")]
     directory: PathBuf,
 
@@ -48,13 +104,78 @@ struct Args {
 
     #[arg(long, name = "Allow dirty", help = "Allows running agent with uncommitted files", default_value = "false")]
     allow_dirty: bool,
+
+    #[arg(short = 'y', long, visible_alias = "yes", help = "Never prompt, handle every fix with --policy", default_value = "false")]
+    non_interactive: bool,
+
+    #[arg(long, value_enum, help = "How fixes are handled in non-interactive mode", default_value = "auto-accept")]
+    policy: Policy,
+
+    #[arg(long, help = "Stop after asking for this many fixes")]
+    max_iterations: Option<usize>,
+
+    #[arg(long, help = "Write a report of every fix to this path (.md for Markdown, JSON otherwise)")]
+    report: Option<PathBuf>,
+
+    #[arg(long, help = "Never touch the working tree, write the fixes as patches instead", default_value = "false")]
+    dry_run: bool,
+
+    #[arg(long, help = "Where --dry-run writes patches (a .patch file or a directory)", default_value = "code-agent.patch")]
+    patch_output: PathBuf,
+
+    #[arg(long, help = "Commit each accepted fix on a new code-agent/<timestamp> branch", default_value = "false", conflicts_with_all = ["dry_run", "Allow dirty"])]
+    commit_fixes: bool,
+
+    #[arg(long, help = "Run the fix cycle from this Flowscript file instead of the built-in one, see src/flows/fix.fs", conflicts_with_all = ["commit_fixes", "report"])]
+    fix_flow: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(outcome) => outcome.exit_code(),
+        Err(e) => {
+            println!("Error: {}", e);
+            Outcome::Error.exit_code()
+        }
+    }
+}
+
+fn run() -> Result<Outcome> {
+    let args = Args::parse();
+
     let _ = dotenv();
+
+    match args.command {
+        Some(Command::Undo {
+            ref session,
+            all,
+            ref directory,
+        }) => return undo(directory, session.as_deref(), all),
+        Some(Command::Flowscript { command }) => return flowscript_cli::run(command),
+        None => {}
+    }
+
+    // The report is written however the session ends, batch runs need it most when they fail
+    let mut report = Report::default();
+    let outcome = match fix_session(&args, &mut report) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            println!("Error: {}", e);
+            Outcome::Error
+        }
+    };
+    report.outcome = outcome;
+
+    if let Some(ref path) = args.report {
+        report.write(path)?;
+    }
+    Ok(outcome)
+}
+
+fn fix_session(args: &Args, report: &mut Report) -> Result<Outcome> {
     // Ensure API Token is set
     if let Err(e) = env::var("OPENAI_TOKEN") {
-        if let Some(api_key) = Args::parse().api_key {
+        if let Some(ref api_key) = args.api_key {
             env::set_var("OPENAI_TOKEN", api_key);
         } else {
             // Load env file from home directory
@@ -64,31 +185,45 @@ fn main() -> Result<()> {
             println!("OPENAI_TOKEN not set");
             println!("Error: {}", e);
             println!("Please set OPENAI_TOKEN in .env");
-            return Ok(());
+            return Ok(Outcome::Error);
         }
     }
 
-    let args = Args::parse();
-
     // Get C++ files
-    let mut file_paths = args.files;
+    let mut file_paths = args.files.clone();
     if file_paths.is_empty() {
         // Use the directory flag instead
         file_paths = files::get_all_cpp_files_in_folder_path(&args.directory)?;
         if file_paths.is_empty() {
             println!("No C++ files found in directory");
-            return Ok(());
+            return Ok(Outcome::Clean);
         }
     }
 
-    // Check for unsaved files
-    if !args.allow_dirty {
-        if check_unsaved_files(&args.directory) {
-            println!("Uncommitted files found. Please commit, discard or stash them before running the code agent, or run with --allow-dirty");
-            return Ok(());
+    // Check that file_paths are cpp files
+    for path in &file_paths {
+        if path.extension().unwrap_or_default() != "cpp" {
+            println!("Error: {} is not a cpp file", path.to_string_lossy());
+            return Ok(Outcome::Error);
         }
     }
 
+    // Check for unsaved files, a dry run never writes to them
+    if !args.allow_dirty && !args.dry_run && check_unsaved_files(&args.directory) {
+        println!("Uncommitted files found. Please commit, discard or stash them before running the code agent, or run with --allow-dirty");
+        return Ok(Outcome::Error);
+    }
+
+    if let Some(ref path) = args.fix_flow {
+        let flow = fs::read_to_string(path)?;
+        let mut workspace = open_workspace(args)?;
+        let workers = system::Workers::start();
+        let outcome = run_fix_flow(&flow, path, &file_paths, &mut workspace, args);
+        drop(workers);
+        close_workspace(&workspace, args)?;
+        return outcome;
+    }
+
     let mut spinner = Option::None;
     if args.reprompt_flowscript {
         spinner = Some(ProgressBar::new_spinner());
@@ -102,94 +237,363 @@ fn main() -> Result<()> {
             .set_message("Getting flowscript...");
     }
 
-    let Ok(script) = get_flowscript_compile(args.reprompt_flowscript) else {
-        println!("Error getting flowscript");
-        return Ok(());
-    };
+    // Generated flows are tried out before they are saved
+    let workers = system::Workers::start();
+
+    if args.reprompt_flowscript {
+        let script = match fs_prompt::get_flowscript_from_gpt(dry_run_flow) {
+            Ok(script) => script,
+            Err(e) => {
+                println!("Error getting flowscript: {}", e);
+                return Ok(Outcome::Error);
+            }
+        };
+        let path = flows::save(&args.directory, &args.flow, &script)?;
+        println!("Saved the new flow to {}", path.to_string_lossy());
+    }
 
     if let Some(spinner) = spinner {
         spinner.finish_and_clear();
     }
 
-    if args.reprompt_flowscript {
-        save_flowscript(&script)?;
+    let flow = flows::find(&args.directory, &args.flow)?;
+    if let Some(ref path) = flow.path {
+        println!("Using the {} flow from {}", flow.name, path.to_string_lossy());
     }
 
-    system::create_worker_thread();
+    let mut workspace = open_workspace(args)?;
 
-    // Check that file_paths are cpp files
-    for path in &file_paths {
-        if path.extension().unwrap_or_default() != "cpp" {
-            println!("Error: {} is not a cpp file", path.to_string_lossy());
-            return Ok(());
+    let committer = if args.commit_fixes {
+        let committer = FixCommitter::start(&args.directory)?;
+        println!("Committing fixes to branch {}", committer.branch);
+        Some(committer)
+    } else {
+        None
+    };
+
+    let outcome = match fix_loop(args, &flow, &file_paths, &mut workspace, committer.as_ref(), report) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            println!("Error: {}", e);
+            Outcome::Error
         }
+    };
+
+    drop(workers);
+    close_workspace(&workspace, args)?;
+    Ok(outcome)
+}
+
+// A dry run only writes to a mirror of the project, anything else is journaled for undo
+fn open_workspace(args: &Args) -> Result<Workspace> {
+    if args.dry_run {
+        return Ok(Workspace::Overlay(Overlay::new(&args.directory)?));
+    }
+    let journal = Journal::start(&args.directory)?;
+    println!("Session {} (undo with `code-agent undo --session {}`)", journal.id, journal.id);
+    Ok(Workspace::Disk(journal))
+}
+
+fn close_workspace(workspace: &Workspace, args: &Args) -> Result<()> {
+    if let Workspace::Overlay(overlay) = workspace {
+        let written = overlay.write_patches(&args.patch_output)?;
+        println!("Wrote {} fix(es) as patches to {}", written, args.patch_output.to_string_lossy());
     }
+    Ok(())
+}
+
+fn fix_loop(
+    args: &Args,
+    flow: &Flow,
+    file_paths: &[PathBuf],
+    workspace: &mut Workspace,
+    committer: Option<&FixCommitter>,
+    report: &mut Report,
+) -> Result<Outcome> {
+    // Errors that were already proposed or rejected are not asked about again
+    let mut skipped: Vec<MappedJsonError> = Vec::new();
+    let mut errors = compile(flow, file_paths, workspace, args.fix_warnings)?;
+    let mut fixed_any = false;
 
     loop {
-        let spin = ProgressBar::new_spinner();
-        spin.enable_steady_tick(Duration::from_millis(100));
-        spin.set_message("Compiling");
-        let result = flowscript::execute_flowscript(
-            &script,
-            CompileJob {
-                files: file_paths.clone(),
-                fix_warnings: args.fix_warnings,
-            },
-        )?;
-        spin.finish_and_clear();
-
-        let errors: Vec<MappedJsonError> = serde_json::from_value(result)?;
-
-        if errors.len() == 0 {
+        if errors.is_empty() {
             println!("No errors found :)");
-            break;
+            return Ok(if fixed_any { Outcome::Fixed } else { Outcome::Clean });
         }
 
-        spin.finish_with_message(format!("Errors found: {}", errors.len()));
-
-        let first_error = errors.get(0).expect("Vec has an error");
+        println!("Errors found: {}", errors.len());
 
-        // Restart spin
-        let spin = ProgressBar::new_spinner();
-        spin.enable_steady_tick(Duration::from_millis(100));
-        spin.set_message(format!(
-            "Asking ChatGPT to fix first error.... ({})",
-            first_error.message.trim()
-        ));
-
-        let fix = FixCodeJob {
-            model: ai::Model::ChatGpt,
-            output_json: first_error.clone(),
-            file_contents: fs::read_to_string(&first_error.filepath)?,
-        };
+        if args.max_iterations.is_some_and(|max| report.iterations >= max) {
+            println!("Reached the maximum number of iterations");
+            return Ok(Outcome::Unfixable);
+        }
 
-        let Ok(result) =
-            serde_json::from_value::<FixCodeResult>(system::run_job(JobType::FixCode, fix))
-        else {
-            println!("Error getting  code result");
-            return Ok(());
+        let Some(error) = errors.iter().find(|e| !skipped.contains(e)).cloned() else {
+            println!("No fixable errors left");
+            return Ok(Outcome::Unfixable);
         };
 
-        spin.finish_and_clear();
+        report.iterations += 1;
+        let result = ask_for_fix(&error, workspace)?;
         render_fix_code_result(&result);
-        let choice = prompt_options();
 
-        match choice {
-            MenuOption::Quit => break,
+        if args.non_interactive {
+            match args.policy {
+                Policy::ProposeOnly => {
+                    report.record(&error, &result, FixStatus::Proposed);
+                    skipped.push(error);
+                }
+                Policy::AutoAccept => {
+                    workspace.apply_fix(&error.filepath, &result.code)?;
+
+                    let new_errors = compile(flow, file_paths, workspace, args.fix_warnings)?;
+                    if fix_compiles(&errors, &new_errors) {
+                        if let Some(committer) = committer {
+                            committer.commit_fix(&error, &result.explanation)?;
+                        }
+                        report.record(&error, &result, FixStatus::Accepted);
+                        fixed_any = true;
+                        errors = new_errors;
+                    } else {
+                        println!("The code does not compile with the fix, reverting");
+                        workspace.undo_last()?;
+                        report.record(&error, &result, FixStatus::Rejected);
+                        skipped.push(error);
+                    }
+                }
+            }
+            continue;
+        }
+
+        match prompt_options(report.can_undo()) {
+            MenuOption::Undo => {
+                report.record(&error, &result, FixStatus::Rejected);
+                if let Some(path) = workspace.undo_last()? {
+                    if let Some(committer) = committer {
+                        committer.undo_last()?;
+                    }
+                    report.undo_last();
+                    println!("Restored {}", path.to_string_lossy());
+                }
+                // Nothing new was applied, only what is left counts as fixed
+                fixed_any = report.can_undo();
+                errors = compile(flow, file_paths, workspace, args.fix_warnings)?;
+                continue;
+            }
+            MenuOption::Quit => {
+                report.record(&error, &result, FixStatus::Rejected);
+                return Ok(Outcome::Unfixable);
+            }
             MenuOption::Tweak => {
-                if let Some(new_code) = tweak_code(&result.code) {
-                    files::replace_code(&first_error.filepath, new_code);
-                } else {
-                    break;
+                let Some(new_code) = tweak_code(&result.code) else {
+                    report.record(&error, &result, FixStatus::Rejected);
+                    return Ok(Outcome::Unfixable);
+                };
+                let tweaked = FixCodeResult {
+                    code: new_code,
+                    explanation: result.explanation.clone(),
+                };
+                workspace.apply_fix(&error.filepath, &tweaked.code)?;
+                if let Some(committer) = committer {
+                    committer.commit_fix(&error, &tweaked.explanation)?;
                 }
+                report.record(&error, &tweaked, FixStatus::Tweaked);
             }
             MenuOption::Accept => {
-                files::replace_code(&first_error.filepath, result.code);
+                workspace.apply_fix(&error.filepath, &result.code)?;
+                if let Some(committer) = committer {
+                    committer.commit_fix(&error, &result.explanation)?;
+                }
+                report.record(&error, &result, FixStatus::Accepted);
             }
         };
+
+        fixed_any = true;
+        errors = compile(flow, file_paths, workspace, args.fix_warnings)?;
     }
+}
+
+// Errors move when lines are added or removed, so they are matched by file and
+// message. A fix compiles when fewer errors are left and none of them are new.
+fn fix_compiles(before: &[MappedJsonError], after: &[MappedJsonError]) -> bool {
+    after.len() < before.len()
+        && after
+            .iter()
+            .all(|e| before.iter().any(|b| b.filepath == e.filepath && b.message == e.message))
+}
 
+fn compile(
+    flow: &Flow,
+    file_paths: &[PathBuf],
+    workspace: &Workspace,
+    fix_warnings: bool,
+) -> Result<Vec<MappedJsonError>> {
+    let spin = ProgressBar::new_spinner();
+    spin.enable_steady_tick(Duration::from_millis(100));
+    spin.set_message("Compiling");
+    let result = flowscript::execute_flowscript(
+        &flow.script,
+        flow.path.as_deref(),
+        CompileJob {
+            files: workspace.compile_paths(file_paths)?,
+            fix_warnings,
+        },
+    )?;
+    spin.finish_and_clear();
+
+    let mut errors = errors_from(result)?;
+    for error in errors.iter_mut() {
+        error.filepath = workspace.real_path(&error.filepath);
+    }
+    Ok(errors)
+}
 
-    system::destroy();
+// Output gives `{"errors": [...]}`, older scripts may end on a bare list
+fn errors_from(result: Value) -> Result<Vec<MappedJsonError>> {
+    let result = match result {
+        Value::Object(mut map) => map.remove("errors").unwrap_or_default(),
+        other => other,
+    };
+    Ok(serde_json::from_value(result)?)
+}
+
+// What generated flows are tried out on
+const SAMPLE_CODE: &str = "int main() {\n    return missing;\n}\n";
+
+// A compile flow has to report the error in a file that doesn't compile
+fn dry_run_flow(script: &str) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let sample = dir.path().join("sample.cpp");
+    fs::write(&sample, SAMPLE_CODE)?;
+
+    let result = flowscript::execute_flowscript(
+        script,
+        None,
+        CompileJob {
+            files: vec![sample],
+            fix_warnings: false,
+        },
+    )?;
+    let errors = errors_from(result).map_err(|e| anyhow!("it didn't end with a list of errors ({})", e))?;
+    if errors.is_empty() {
+        return Err(anyhow!("it found no errors in a file that doesn't compile"));
+    }
+    Ok(())
+}
+
+// A new flow can do anything with the sample, it only has to run to the end. FixCode
+// gives the code back as it was, fixes are approved and never written.
+fn dry_run_new_flow(script: &str) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let sample = dir.path().join("sample.cpp");
+    fs::write(&sample, SAMPLE_CODE)?;
+
+    let input = json!({
+        "files": [sample],
+        "fix_warnings": false,
+        "auto_accept": true,
+        "propose_only": false,
+    });
+    let mut host = |job: JobType, input: Value| match job {
+        JobType::FixCode => Ok(json!(FixCodeResult {
+            code: SAMPLE_CODE.to_owned(),
+            explanation: "Left as it was for the dry run".to_owned(),
+        })),
+        JobType::Approve => Ok(json!(ApproveResult { approved: true, code: None, quit: false })),
+        JobType::ApplyFix => Ok(json!(ApplyFixResult { applied: false })),
+        other => system::run_job_here(other, input),
+    };
+    flowscript::execute_flowscript_stubbed(script, None, input, &mut host)?;
     Ok(())
 }
+
+// The whole compile, fix and approve cycle is left to the script. Approve and
+// ApplyFix run here on the main thread, fixes are written through the workspace.
+fn run_fix_flow(
+    flow: &str,
+    path: &Path,
+    file_paths: &[PathBuf],
+    workspace: &mut Workspace,
+    args: &Args,
+) -> Result<Outcome> {
+    let propose_only = args.non_interactive && args.policy == Policy::ProposeOnly;
+    let input = json!({
+        "files": workspace.compile_paths(file_paths)?,
+        "fix_warnings": args.fix_warnings,
+        "auto_accept": args.non_interactive,
+        "propose_only": propose_only,
+    });
+
+    let mut fixed_any = false;
+    let mut host = |job: JobType, input: Value| match job {
+        // Flows can't write around --policy propose-only, every fix comes back as not applied
+        JobType::ApplyFix if propose_only => Ok(serde_json::to_value(ApplyFixResult { applied: false })?),
+        JobType::ApplyFix => {
+            let fix: ApplyFixJob = serde_json::from_value(input)?;
+            fix.apply_with(|path, code| {
+                // Errors from a dry run point into the mirror
+                let path = workspace.real_path(path);
+                workspace.apply_fix(&path, code)?;
+                fixed_any = true;
+                Ok(())
+            })
+        }
+        other => system::run_job_here(other, input),
+    };
+    let result = flowscript::execute_flowscript_hosted(flow, Some(path), input, &mut host)?;
+    if !result.is_object() {
+        return Err(anyhow!("The fix flow did not end with an object"));
+    }
+
+    let remaining = result
+        .get("errors")
+        .and_then(|errors| errors.as_array())
+        .map(|errors| errors.len())
+        .unwrap_or(0);
+    if remaining > 0 {
+        println!("Errors left: {}", remaining);
+        return Ok(Outcome::Unfixable);
+    }
+
+    println!("No errors found :)");
+    Ok(if fixed_any { Outcome::Fixed } else { Outcome::Clean })
+}
+
+fn ask_for_fix(error: &MappedJsonError, workspace: &Workspace) -> Result<FixCodeResult> {
+    let spin = ProgressBar::new_spinner();
+    spin.enable_steady_tick(Duration::from_millis(100));
+    spin.set_message(format!(
+        "Asking ChatGPT to fix error.... ({})",
+        error.message.trim()
+    ));
+
+    let fix = FixCodeJob {
+        model: ai::Model::ChatGpt,
+        output_json: error.clone(),
+        file_contents: workspace.read(&error.filepath)?,
+    };
+
+    let result = serde_json::from_value::<FixCodeResult>(system::run_job(JobType::FixCode, fix))
+        .map_err(|_| anyhow!("Error getting code result"));
+    spin.finish_and_clear();
+
+    result
+}
+
+fn undo(directory: &Path, session: Option<&str>, all: bool) -> Result<Outcome> {
+    let journal = Journal::open(directory, session)?;
+
+    let restored = if all {
+        journal.undo_all()?
+    } else {
+        journal.undo_last()?.into_iter().collect()
+    };
+
+    if restored.is_empty() {
+        println!("Nothing to undo in session {}", journal.id);
+    }
+    for path in restored {
+        println!("Restored {}", path.to_string_lossy());
+    }
+    Ok(Outcome::Clean)
+}
@@ -2,29 +2,38 @@ use std::path::PathBuf;
 
 use anyhow::anyhow;
 use anyhow::Result;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{compiler::ClangOutputJson, system::job_core::Job};
+use crate::{
+    ai::{FixCodeJob, Model},
+    compiler::ClangOutputJson,
+    system::job_core::Job,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct MappedJsonError {
-    column: i32,
-    line: i32,
+    pub column: i32,
+    pub line: i32,
     pub filepath: PathBuf,
     pub message: String,
-    snippet: String,
+    pub snippet: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct OutputJob {
     errors: Vec<ClangOutputJson>,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct OutputResult {
+    pub errors: Vec<MappedJsonError>,
+}
+
 impl Job for OutputJob {
     fn run(&self) -> Result<serde_json::Value> {
-        self.map_output().map(|output| {
-            serde_json::to_value(output).expect("Struct with deserialize to deseralize")
-        })
+        let errors = self.map_output()?;
+        Ok(serde_json::to_value(OutputResult { errors })?)
     }
 }
 
@@ -33,7 +42,7 @@ impl OutputJob {
         let mut mapped_errors = vec![];
 
         for error in &self.errors {
-            let location = error.locations.get(0).ok_or(anyhow!("No location"))?;
+            let location = error.locations.first().ok_or(anyhow!("No location"))?;
 
             let mapped_error = MappedJsonError {
                 column: location.caret.column,
@@ -50,6 +59,24 @@ impl OutputJob {
     }
 }
 
+// Picks the next error in a fix flow and builds the input for the FixCode job
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SelectErrorJob {
+    errors: Vec<MappedJsonError>,
+}
+
+impl Job for SelectErrorJob {
+    fn run(&self) -> Result<serde_json::Value> {
+        let error = self.errors.first().ok_or(anyhow!("No errors to select"))?;
+        let file_contents = std::fs::read_to_string(&error.filepath)?;
+        Ok(serde_json::to_value(FixCodeJob {
+            model: Model::ChatGpt,
+            output_json: error.clone(),
+            file_contents,
+        })?)
+    }
+}
+
 fn get_file_snippet(filepath: &PathBuf, line: i32) -> Result<String> {
     let file_contents = std::fs::read_to_string(filepath)?;
     let lines: Vec<_> = file_contents.lines().collect();
@@ -0,0 +1,195 @@
+use std::{fmt::Write as _, fs, path::Path, process::ExitCode};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{ai::FixCodeResult, output::MappedJsonError};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Clean,     // Nothing to fix
+    Fixed,     // Every error was fixed
+    Unfixable, // Errors remain after the run
+    Error,     // The agent itself failed
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Outcome::Clean => ExitCode::from(0),
+            Outcome::Fixed => ExitCode::from(1),
+            Outcome::Unfixable => ExitCode::from(2),
+            Outcome::Error => ExitCode::from(3),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixStatus {
+    Accepted,
+    Tweaked,
+    Proposed,
+    Rejected,
+    Undone,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportEntry {
+    pub error: MappedJsonError,
+    pub status: FixStatus,
+    pub fix: FixCodeResult,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub outcome: Outcome,
+    pub iterations: usize,
+    pub entries: Vec<ReportEntry>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report {
+            outcome: Outcome::Clean,
+            iterations: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Report {
+    pub fn record(&mut self, error: &MappedJsonError, fix: &FixCodeResult, status: FixStatus) {
+        self.entries.push(ReportEntry {
+            error: error.clone(),
+            status,
+            fix: fix.clone(),
+        });
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|e| e.status == FixStatus::Accepted || e.status == FixStatus::Tweaked)
+    }
+
+    // Marks the most recently applied fix as undone
+    pub fn undo_last(&mut self) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|e| e.status == FixStatus::Accepted || e.status == FixStatus::Tweaked)
+        {
+            entry.status = FixStatus::Undone;
+        }
+    }
+
+    // Markdown is used for .md paths, everything else gets JSON
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let is_markdown = path
+            .extension()
+            .map(|ext| ext == "md" || ext == "markdown")
+            .unwrap_or(false);
+
+        let contents = if is_markdown {
+            self.to_markdown()
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        let _ = writeln!(md, "# Code Agent Report\n");
+        let _ = writeln!(md, "- Outcome: `{:?}`", self.outcome);
+        let _ = writeln!(md, "- Iterations: {}\n", self.iterations);
+
+        for entry in &self.entries {
+            let _ = writeln!(
+                md,
+                "## {}:{}:{}\n",
+                entry.error.filepath.to_string_lossy(),
+                entry.error.line,
+                entry.error.column
+            );
+            let _ = writeln!(md, "**Error:** {}\n", entry.error.message.trim());
+            let _ = writeln!(md, "**Status:** `{:?}`\n", entry.status);
+            let _ = writeln!(md, "```cpp\n{}```\n", entry.fix.code);
+            let _ = writeln!(md, "{}\n", entry.fix.explanation.trim());
+        }
+
+        md
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> Report {
+        let error = MappedJsonError {
+            column: 12,
+            line: 2,
+            filepath: "main.cpp".into(),
+            message: "'x' was not declared in this scope\n".to_owned(),
+            snippet: String::new(),
+        };
+        let fix = FixCodeResult {
+            code: "int x = 0;\n".to_owned(),
+            explanation: "Declares x".to_owned(),
+        };
+        let mut report = Report {
+            outcome: Outcome::Fixed,
+            iterations: 2,
+            entries: Vec::new(),
+        };
+        report.record(&error, &fix, FixStatus::Accepted);
+        report.record(&error, &fix, FixStatus::Proposed);
+        report
+    }
+
+    #[test]
+    fn outcomes_map_to_their_exit_codes() {
+        assert_eq!(Outcome::Clean.exit_code(), ExitCode::from(0));
+        assert_eq!(Outcome::Fixed.exit_code(), ExitCode::from(1));
+        assert_eq!(Outcome::Unfixable.exit_code(), ExitCode::from(2));
+        assert_eq!(Outcome::Error.exit_code(), ExitCode::from(3));
+    }
+
+    #[test]
+    fn undo_marks_the_last_applied_fix() {
+        let mut report = report();
+        assert!(report.can_undo());
+        report.undo_last();
+        let statuses: Vec<_> = report.entries.iter().map(|e| e.status).collect();
+        assert_eq!(statuses, [FixStatus::Undone, FixStatus::Proposed]);
+        assert!(!report.can_undo());
+    }
+
+    #[test]
+    fn reports_are_written_as_json_or_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = report();
+
+        let json_path = dir.path().join("report.json");
+        report.write(&json_path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(json["outcome"], "fixed");
+        assert_eq!(json["iterations"], 2);
+        assert_eq!(json["entries"][0]["status"], "accepted");
+        assert_eq!(json["entries"][1]["status"], "proposed");
+        assert_eq!(json["entries"][0]["fix"]["explanation"], "Declares x");
+
+        let md_path = dir.path().join("report.md");
+        report.write(&md_path).unwrap();
+        let md = fs::read_to_string(&md_path).unwrap();
+        assert!(md.starts_with("# Code Agent Report\n\n- Outcome: `Fixed`\n- Iterations: 2\n"), "{}", md);
+        assert!(md.contains("## main.cpp:2:12\n\n**Error:** 'x' was not declared in this scope\n"), "{}", md);
+        assert!(md.contains("**Status:** `Proposed`\n\n```cpp\nint x = 0;\n```\n"), "{}", md);
+    }
+}
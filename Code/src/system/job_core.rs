@@ -1,7 +1,14 @@
 use anyhow::Result;
-use serde_json::{from_value, Value};
+use serde::de::DeserializeOwned;
+use serde_json::{from_value, json, Value};
 
-use crate::{ai::FixCodeJob, compiler::CompileJob, output::OutputJob};
+use crate::{
+    ai::FixCodeJob,
+    compiler::CompileJob,
+    files::ApplyFixJob,
+    output::{OutputJob, SelectErrorJob},
+    ui::ApproveJob,
+};
 
 use super::types::JobType;
 
@@ -9,27 +16,30 @@ pub trait Job {
     fn run(&self) -> Result<Value>;
 }
 
+// Scripts can hand any payload to any job, so bad input is an error instead of a panic
+fn run_typed<T: Job + DeserializeOwned>(input: Value) -> Result<Value> {
+    from_value::<T>(input)?.run()
+}
+
+// Failed jobs come back as an error object so the caller can decide what to do
+pub const JOB_ERROR_KEY: &str = "__job_error";
+
+pub fn job_error(result: &Value) -> Option<&str> {
+    result.get(JOB_ERROR_KEY).and_then(|e| e.as_str())
+}
+
 pub fn run_job(job_type: JobType, input: Value) -> Value {
     let result = match job_type {
-        JobType::FixCode => {
-            let intoed: FixCodeJob = from_value(input).expect("Valid json");
-            intoed.run()
-        }
-        JobType::Compile => {
-            let intoed: CompileJob = from_value(input).expect("Valid json");
-            intoed.run()
-        }
-        JobType::Output => {
-            let intoed: OutputJob = from_value(input).expect("Valid json");
-            intoed.run()
-        }
+        JobType::FixCode => run_typed::<FixCodeJob>(input),
+        JobType::Compile => run_typed::<CompileJob>(input),
+        JobType::Output => run_typed::<OutputJob>(input),
+        JobType::SelectError => run_typed::<SelectErrorJob>(input),
+        JobType::Approve => run_typed::<ApproveJob>(input),
+        JobType::ApplyFix => run_typed::<ApplyFixJob>(input),
     };
 
     match result {
         Ok(response) => response,
-        Err(e) => {
-            println!("Error running job: {}", e);
-            Value::Null
-        }
+        Err(e) => json!({ JOB_ERROR_KEY: e.to_string() }),
     }
 }
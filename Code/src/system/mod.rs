@@ -1,4 +1,7 @@
 #![allow(dead_code)]
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -11,11 +14,15 @@ extern "C" {
     fn HasJobsActive() -> bool;
     fn Destroy();
     fn IsJobComplete(jobId: *const libc::c_char) -> bool;
+    fn WaitForJob(jobId: *const libc::c_char, timeoutMs: libc::c_longlong) -> bool;
     fn GetJobStatus(jobId: *const libc::c_char) -> i32;
     fn DumpHistoryToFile(filename: *const libc::c_char);
     fn DestroyJob(jobId: *const libc::c_char);
     fn RunJob(jobType: *const libc::c_char, input: *const libc::c_char) -> *const libc::c_char;
+    fn QueueJob(jobType: *const libc::c_char, input: *const libc::c_char) -> *const libc::c_char;
+    fn CompleteJob(jobId: *const libc::c_char) -> *const libc::c_char;
     fn CreateWorkerThread();
+    fn FreeString(string: *const libc::c_char);
 }
 
 pub enum JobStatus {
@@ -44,7 +51,28 @@ pub fn has_jobs_active() -> bool {
     unsafe { HasJobsActive() }
 }
 
-pub fn destroy() {
+// Jobs that timed out but are still running, their results are dropped once
+// they finish. Nothing may still wait on the job system when it's deleted.
+static TIMED_OUT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Drops the results of timed out jobs that have finished by now
+fn drop_finished_jobs() {
+    let mut timed_out = TIMED_OUT.lock().unwrap();
+    timed_out.retain(|job_id| {
+        if !is_job_complete(job_id) {
+            return true;
+        }
+        wait_for_job(job_id);
+        false
+    });
+}
+
+// Workers are still running here, so timed out jobs can finish before it's deleted
+fn destroy() {
+    let timed_out = std::mem::take(&mut *TIMED_OUT.lock().unwrap());
+    for job_id in timed_out {
+        wait_for_job(&job_id);
+    }
     unsafe { Destroy() }
 }
 
@@ -91,38 +119,110 @@ pub fn run_job<'a, T: job_core::Job + Serialize + Deserialize<'a>>(
     let input_json = serde_json::to_string(&input).unwrap();
     let c_input = std::ffi::CString::new(input_json).unwrap();
     let c_result = unsafe { RunJob(c_job_type.as_ptr(), c_input.as_ptr()) };
-    let result = unsafe {
-        std::ffi::CStr::from_ptr(c_result)
-            .to_str()
-            .unwrap()
-            .to_string()
-            .clone()
-    };
+    let result = unsafe { take_string(c_result) };
 
-    serde_json::from_str(result.as_str()).expect("Valid json")
+    let result: Value = serde_json::from_str(result.as_str()).expect("Valid json");
+    if let Some(e) = job_core::job_error(&result) {
+        println!("Error running job: {}", e);
+        return Value::Null;
+    }
+    result
 }
 
-// Used for running a job in flowscript
-pub fn run_job_fs(job_type: String, input: Value) -> Value {
-    let c_job_type = std::ffi::CString::new(serde_json::to_string(&job_type).unwrap()).unwrap();
-    let input_json = serde_json::to_string(&input).unwrap();
+// Queues a job without waiting for it, returns the job id
+pub fn queue_job_fs(job_type: &str, input: &Value) -> String {
+    drop_finished_jobs();
+    let c_job_type = std::ffi::CString::new(serde_json::to_string(job_type).unwrap()).unwrap();
+    let input_json = serde_json::to_string(input).unwrap();
     let c_input = std::ffi::CString::new(input_json).unwrap();
-    let c_result = unsafe { RunJob(c_job_type.as_ptr(), c_input.as_ptr()) };
-    let result = unsafe {
-        std::ffi::CStr::from_ptr(c_result)
-            .to_str()
-            .unwrap()
-            .to_string()
-            .clone()
+    let c_id = unsafe { QueueJob(c_job_type.as_ptr(), c_input.as_ptr()) };
+    unsafe { take_string(c_id) }
+}
+
+// Copies a string the job system handed out and frees the original
+unsafe fn take_string(c_string: *const libc::c_char) -> String {
+    let string = std::ffi::CStr::from_ptr(c_string).to_str().unwrap().to_owned();
+    FreeString(c_string);
+    string
+}
+
+// Blocks the calling thread until a queued job is done and returns its result
+pub fn wait_for_job(job_id: &str) -> Value {
+    let c_job_id = std::ffi::CString::new(job_id).unwrap();
+    unsafe { WaitForJob(c_job_id.as_ptr(), -1) };
+
+    let c_result = unsafe { CompleteJob(c_job_id.as_ptr()) };
+    let result = unsafe { take_string(c_result) };
+
+    serde_json::from_str(&result).expect("Valid json")
+}
+
+// Like wait_for_job but gives up after the timeout. The job can't be stopped
+// once it started, so it's left for `drop_finished_jobs` or `destroy`.
+pub fn wait_for_job_timeout(job_id: &str, timeout: Duration) -> Option<Value> {
+    let c_job_id = std::ffi::CString::new(job_id).unwrap();
+    let timeout_ms = timeout.as_millis().min(i64::MAX as u128) as libc::c_longlong;
+    if !unsafe { WaitForJob(c_job_id.as_ptr(), timeout_ms) } {
+        TIMED_OUT.lock().unwrap().push(job_id.to_owned());
+        return None;
+    }
+    Some(wait_for_job(job_id))
+}
+
+// Used for running a job in flowscript, a failed or timed out job is an error
+pub fn run_job_fs(job_type: String, input: Value, timeout: Option<Duration>) -> Result<Value> {
+    let job_id = queue_job_fs(&job_type, &input);
+    let result = match timeout {
+        Some(timeout) => wait_for_job_timeout(&job_id, timeout)
+            .ok_or(anyhow!("{} timed out after {:?}, it keeps running in the background and its result is dropped", job_type, timeout))?,
+        None => wait_for_job(&job_id),
     };
+    match job_core::job_error(&result) {
+        Some(e) => Err(anyhow!("{}", e)),
+        None => Ok(result),
+    }
+}
 
-    serde_json::from_str(result.as_str()).expect("Valid json")
+// Runs a job on the calling thread instead of a worker, a failed job is an error
+pub fn run_job_here(job_type: JobType, input: Value) -> Result<Value> {
+    let result = job_core::run_job(job_type, input);
+    match job_core::job_error(&result) {
+        Some(e) => Err(anyhow!("{}", e)),
+        None => Ok(result),
+    }
 }
 
 pub fn create_worker_thread() {
     unsafe { CreateWorkerThread() }
 }
 
+// One worker per core so Flowscript branches can run side by side
+fn create_worker_threads() {
+    let count = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(2)
+        .max(2);
+    for _ in 0..count {
+        create_worker_thread();
+    }
+}
+
+// The job system for as long as it's held, dropping it destroys it on every way out
+pub struct Workers;
+
+impl Workers {
+    pub fn start() -> Workers {
+        create_worker_threads();
+        Workers
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        destroy();
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn run_rust_job(
     job_type: *const libc::c_char,
@@ -144,10 +244,12 @@ pub extern "C" fn run_rust_job(
         let proper_job_type: JobType = serde_json::from_str(&job_type).unwrap();
         let result = crate::system::job_core::run_job(proper_job_type, input);
 
-        let c_result = Box::into_raw(Box::new(
-            std::ffi::CString::new(result.to_string()).unwrap(),
-        ));
-
-        return c_result.as_ref().unwrap().as_ptr();
+        // Freed by the worker with free_rust_string once it copied it
+        std::ffi::CString::new(result.to_string()).unwrap().into_raw()
     }
 }
+
+#[no_mangle]
+pub extern "C" fn free_rust_string(string: *mut libc::c_char) {
+    unsafe { drop(std::ffi::CString::from_raw(string)) }
+}
@@ -1,8 +1,83 @@
+use schemars::{schema::RootSchema, schema_for};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{
+    ai::{FixCodeJob, FixCodeResult},
+    compiler::{CompileJob, CompileResult},
+    files::{ApplyFixJob, ApplyFixResult},
+    output::{OutputJob, OutputResult, SelectErrorJob},
+    ui::{ApproveJob, ApproveResult},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum JobType {
     Compile,
     Output,
     FixCode,
+    SelectError,
+    Approve,
+    ApplyFix,
+}
+
+impl JobType {
+    pub const ALL: &'static [JobType] = &[
+        JobType::Compile,
+        JobType::Output,
+        JobType::SelectError,
+        JobType::FixCode,
+        JobType::Approve,
+        JobType::ApplyFix,
+    ];
+
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    // Jobs that prompt or write files run on the thread that started the
+    // script instead of a worker, see scheduler::Context::run_hosted
+    pub fn runs_on_host(&self) -> bool {
+        matches!(self, JobType::Approve | JobType::ApplyFix)
+    }
+
+    // What the job is for, shown to people and models writing Flowscript
+    pub fn description(&self) -> &'static str {
+        match self {
+            JobType::Compile => "Compiles the C++ files and gives back the raw compiler errors",
+            JobType::Output => "Maps raw compiler errors to errors with a file, line, column, message and snippet",
+            JobType::SelectError => "Picks the first error and reads its file, giving back the input FixCode needs",
+            JobType::FixCode => "Asks the model to fix one error, giving back the fixed code and an explanation",
+            JobType::Approve => "Shows a fix and lets the user accept, tweak or quit, accepts on its own with auto_accept",
+            JobType::ApplyFix => "Writes an approved fix into its file",
+        }
+    }
+
+    // Job types are referenced by name in Flowscript
+    pub fn from_name(name: &str) -> Option<JobType> {
+        serde_json::from_value(serde_json::Value::String(name.to_owned())).ok()
+    }
+
+    // What the job reads from the payload, Flowscript checks every edge into it
+    pub fn input_schema(&self) -> RootSchema {
+        match self {
+            JobType::Compile => schema_for!(CompileJob),
+            JobType::Output => schema_for!(OutputJob),
+            JobType::FixCode => schema_for!(FixCodeJob),
+            JobType::SelectError => schema_for!(SelectErrorJob),
+            JobType::Approve => schema_for!(ApproveJob),
+            JobType::ApplyFix => schema_for!(ApplyFixJob),
+        }
+    }
+
+    // What the job merges into the payload when it succeeds
+    pub fn output_schema(&self) -> RootSchema {
+        match self {
+            JobType::Compile => schema_for!(CompileResult),
+            JobType::Output => schema_for!(OutputResult),
+            JobType::FixCode => schema_for!(FixCodeResult),
+            // Select error builds the input for FixCode
+            JobType::SelectError => schema_for!(FixCodeJob),
+            JobType::Approve => schema_for!(ApproveResult),
+            JobType::ApplyFix => schema_for!(ApplyFixResult),
+        }
+    }
 }
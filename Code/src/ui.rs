@@ -1,6 +1,12 @@
-use dialoguer::{Editor, Select};
+use std::path::Path;
 
-use crate::ai::FixCodeResult;
+use anyhow::Result;
+use dialoguer::{Editor, Input, Select};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{ai::FixCodeResult, system::job_core::Job};
 
 pub fn render_fix_code_result(result: &FixCodeResult) {
     println!("Fixed Code:\n\n");
@@ -15,31 +21,107 @@ pub fn render_fix_code_result(result: &FixCodeResult) {
 pub enum MenuOption {
     Accept,
     Tweak,
+    Undo,
     Quit,
 }
 
-pub fn prompt_options() -> MenuOption {
-    let items = vec!["Accept", "Tweak", "Quit"];
+pub fn prompt_options(can_undo: bool) -> MenuOption {
+    let mut items = vec![("Accept", MenuOption::Accept), ("Tweak", MenuOption::Tweak)];
+    if can_undo {
+        items.push(("Undo last fix", MenuOption::Undo));
+    }
+    items.push(("Quit", MenuOption::Quit));
+
+    let labels: Vec<&str> = items.iter().map(|(label, _)| *label).collect();
+    let selection = Select::new()
+        .with_prompt("What do you choose?")
+        .items(&labels)
+        .default(0)
+        .interact()
+        .unwrap();
+
+    items.swap_remove(selection).1
+}
+
+pub fn tweak_code(code: &str) -> Option<String> {
+    Editor::new().extension(".cpp").edit(code).unwrap()
+}
+
+pub enum FlowOption {
+    Save,
+    Change(String), // What to ask ChatGPT to do differently
+    Quit,
+}
 
+pub fn prompt_flow_options(path: &Path) -> FlowOption {
+    let save = format!("Save to {}", path.to_string_lossy());
     let selection = Select::new()
         .with_prompt("What do you choose?")
-        .items(&items)
+        .items(&[save.as_str(), "Ask for changes", "Quit"])
         .default(0)
         .interact()
         .unwrap();
 
     match selection {
-        0 => MenuOption::Accept,
-        1 => MenuOption::Tweak,
-        2 => MenuOption::Quit,
-        _ => panic!("Invalid selection"),
+        0 => FlowOption::Save,
+        1 => FlowOption::Change(
+            Input::new()
+                .with_prompt("What should be different?")
+                .interact_text()
+                .unwrap(),
+        ),
+        _ => FlowOption::Quit,
     }
 }
 
-pub fn tweak_code(code: &str) -> Option<String> {
-    if let Some(rv) = Editor::new().extension(".cpp").edit(code).unwrap() {
-        Some(rv)
-    } else {
-        None
+// Lets the user accept, tweak or quit on a fix inside a fix flow
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ApproveJob {
+    code: String,
+    explanation: String,
+    #[serde(default)]
+    auto_accept: bool,
+}
+
+// `code` is only there when the fix was tweaked
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+pub struct ApproveResult {
+    pub approved: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quit: bool,
+}
+
+impl Job for ApproveJob {
+    fn run(&self) -> Result<Value> {
+        render_fix_code_result(&FixCodeResult {
+            code: self.code.clone(),
+            explanation: self.explanation.clone(),
+        });
+
+        let approved = ApproveResult {
+            approved: true,
+            ..Default::default()
+        };
+        let quit = ApproveResult {
+            quit: true,
+            ..Default::default()
+        };
+        let result = match self.auto_accept {
+            true => approved,
+            false => match prompt_options(false) {
+                MenuOption::Accept => approved,
+                MenuOption::Tweak => match tweak_code(&self.code) {
+                    Some(code) => ApproveResult {
+                        code: Some(code),
+                        ..approved
+                    },
+                    None => quit,
+                },
+                MenuOption::Quit | MenuOption::Undo => quit,
+            },
+        };
+        Ok(serde_json::to_value(result)?)
     }
 }
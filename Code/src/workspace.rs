@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use git2::Patch;
+use tempfile::TempDir;
+
+use crate::{files, journal::Journal};
+
+// Where fixes are read from and written to
+pub enum Workspace {
+    Disk(Journal),
+    Overlay(Overlay),
+}
+
+impl Workspace {
+    pub fn read(&self, path: &Path) -> Result<String> {
+        match self {
+            Workspace::Disk(_) => Ok(fs::read_to_string(path)?),
+            Workspace::Overlay(overlay) => overlay.read(path),
+        }
+    }
+
+    pub fn apply_fix(&mut self, path: &Path, new_code: &str) -> Result<()> {
+        if new_code.trim().is_empty() {
+            return Err(anyhow!("Fix for {} contains no code", path.display()));
+        }
+
+        match self {
+            Workspace::Disk(journal) => {
+                journal.record(path, &fs::read_to_string(path)?)?;
+                files::replace_code(path, new_code)
+            }
+            Workspace::Overlay(overlay) => {
+                let merged = files::splice_code(&overlay.read(path)?, new_code);
+                overlay.write(path, merged)
+            }
+        }
+    }
+
+    // Reverts the most recent fix, returning the file it touched
+    pub fn undo_last(&mut self) -> Result<Option<PathBuf>> {
+        match self {
+            Workspace::Disk(journal) => journal.undo_last(),
+            Workspace::Overlay(overlay) => overlay.undo_last(),
+        }
+    }
+
+    // Paths that should be handed to the compiler
+    pub fn compile_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        match self {
+            Workspace::Disk(_) => Ok(paths.to_vec()),
+            Workspace::Overlay(overlay) => paths.iter().map(|p| overlay.mirror_path(p)).collect(),
+        }
+    }
+
+    // Maps a path reported by the compiler back to the working tree
+    pub fn real_path(&self, path: &Path) -> PathBuf {
+        match self {
+            Workspace::Disk(_) => path.to_path_buf(),
+            Workspace::Overlay(overlay) => overlay.real_path(path),
+        }
+    }
+}
+
+// A mirror of the project in a temp directory. Untouched files are symlinks back
+// to the working tree, fixed files are written into the mirror instead.
+pub struct Overlay {
+    root: PathBuf,
+    mirror: TempDir,
+    originals: HashMap<PathBuf, String>,
+    current: HashMap<PathBuf, String>,
+    history: Vec<(PathBuf, String, String)>,
+}
+
+impl Overlay {
+    pub fn new(root: &Path) -> Result<Overlay> {
+        let root = root.canonicalize()?;
+        let mirror = tempfile::Builder::new().prefix("code-agent-").tempdir()?;
+        link_tree(&root, mirror.path())?;
+
+        Ok(Overlay {
+            root,
+            mirror,
+            originals: HashMap::new(),
+            current: HashMap::new(),
+            history: Vec::new(),
+        })
+    }
+
+    fn relative(&self, path: &Path) -> Result<PathBuf> {
+        let canonical = path.canonicalize()?;
+        canonical
+            .strip_prefix(&self.root)
+            .map(|p| p.to_path_buf())
+            .map_err(|_| anyhow!("{} is outside of the project directory", path.display()))
+    }
+
+    pub fn mirror_path(&self, path: &Path) -> Result<PathBuf> {
+        Ok(self.mirror.path().join(self.relative(path)?))
+    }
+
+    pub fn real_path(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(self.mirror.path()) {
+            Ok(relative) => self.root.join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    pub fn read(&self, path: &Path) -> Result<String> {
+        let relative = self.relative(path)?;
+        match self.current.get(&relative) {
+            Some(contents) => Ok(contents.clone()),
+            None => Ok(fs::read_to_string(path)?),
+        }
+    }
+
+    pub fn write(&mut self, path: &Path, contents: String) -> Result<()> {
+        let relative = self.relative(path)?;
+        let before = self.read(path)?;
+        self.originals
+            .entry(relative.clone())
+            .or_insert_with(|| before.clone());
+
+        // Replace the symlink so the working tree is never written to
+        let mirrored = self.mirror.path().join(&relative);
+        let _ = fs::remove_file(&mirrored);
+        fs::write(&mirrored, &contents)?;
+
+        self.history.push((relative.clone(), before, contents.clone()));
+        self.current.insert(relative, contents);
+        Ok(())
+    }
+
+    pub fn undo_last(&mut self) -> Result<Option<PathBuf>> {
+        let Some((relative, before, _)) = self.history.pop() else {
+            return Ok(None);
+        };
+
+        let mirrored = self.mirror.path().join(&relative);
+        fs::write(&mirrored, &before)?;
+
+        // Nothing left to patch if the file is back to how it started
+        if self.originals.get(&relative) == Some(&before) {
+            self.originals.remove(&relative);
+            self.current.remove(&relative);
+        } else {
+            self.current.insert(relative.clone(), before);
+        }
+        Ok(Some(self.root.join(relative)))
+    }
+
+    // A `.patch` path gets one combined patch, anything else is treated as a
+    // directory with one numbered patch per fix
+    pub fn write_patches(&self, output: &Path) -> Result<usize> {
+        if output.extension().unwrap_or_default() == "patch" {
+            let mut combined = String::new();
+            let mut changed: Vec<&PathBuf> = self.current.keys().collect();
+            changed.sort();
+            for relative in changed {
+                combined.push_str(&diff(relative, &self.originals[relative], &self.current[relative])?);
+            }
+            fs::write(output, combined)?;
+            return Ok(self.history.len());
+        }
+
+        fs::create_dir_all(output)?;
+        for (i, (relative, before, after)) in self.history.iter().enumerate() {
+            let name = relative.to_string_lossy().replace(['/', '\\'], "-");
+            let file = output.join(format!("{:04}-{}.patch", i + 1, name));
+            fs::write(file, diff(relative, before, after)?)?;
+        }
+        Ok(self.history.len())
+    }
+}
+
+fn diff(relative: &Path, before: &str, after: &str) -> Result<String> {
+    let mut patch = Patch::from_buffers(
+        before.as_bytes(),
+        Some(relative),
+        after.as_bytes(),
+        Some(relative),
+        None,
+    )?;
+    let buf = patch.to_buf()?;
+    match buf.as_str() {
+        Some(text) => Ok(text.to_owned()),
+        None => Err(anyhow!("The patch for {} is not valid UTF-8", relative.display())),
+    }
+}
+
+fn link_tree(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        let target = to.join(entry.file_name());
+
+        // Skip .git and other hidden folders
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if path.is_dir() {
+            fs::create_dir_all(&target)?;
+            link_tree(&path, &target)?;
+        } else {
+            link_file(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn link_file(from: &Path, to: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(from, to)?)
+}
+
+// Symlinks need extra privileges elsewhere, a copy is taken when the run starts
+#[cfg(not(unix))]
+fn link_file(from: &Path, to: &Path) -> Result<()> {
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "int main() {\n    return x;\n}\n";
+    const UTIL: &str = "int add(int a, int b) {\n    return a + c;\n}\n";
+
+    const MAIN_PATCH: &str = "diff --git a/main.cpp b/main.cpp
+index 33a5fb3..33c14ce 100644
+--- a/main.cpp
++++ b/main.cpp
+@@ -1,3 +1,3 @@
+ int main() {
+-    return x;
++    return 0;
+ }
+";
+
+    const UTIL_PATCH: &str = "diff --git a/lib/util.cpp b/lib/util.cpp
+index 4a01dac..59aab49 100644
+--- a/lib/util.cpp
++++ b/lib/util.cpp
+@@ -1,3 +1,3 @@
+ int add(int a, int b) {
+-    return a + c;
++    return a + b;
+ }
+";
+
+    #[test]
+    fn dry_runs_write_patches_and_leave_the_working_tree_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let main = root.join("main.cpp");
+        let util = root.join("lib").join("util.cpp");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(&main, MAIN).unwrap();
+        fs::write(&util, UTIL).unwrap();
+
+        let mut workspace = Workspace::Overlay(Overlay::new(&root).unwrap());
+        let compiled = workspace.compile_paths(&[main.clone(), util.clone()]).unwrap();
+        assert!(compiled.iter().all(|path| !path.starts_with(&root)));
+        assert_eq!(fs::read_to_string(&compiled[0]).unwrap(), MAIN);
+        assert_eq!(workspace.real_path(&compiled[1]), util);
+
+        workspace.apply_fix(&main, "int main() {\n    return 0;\n}").unwrap();
+        workspace.apply_fix(&util, "int add(int a, int b) {\n    return a + b;\n}").unwrap();
+
+        // The compiler sees the fixes, the working tree doesn't
+        assert_eq!(fs::read_to_string(&compiled[0]).unwrap(), "int main() {\n    return 0;\n}\n");
+        assert_eq!(workspace.read(&util).unwrap(), "int add(int a, int b) {\n    return a + b;\n}\n");
+        assert_eq!(fs::read_to_string(&main).unwrap(), MAIN);
+        assert_eq!(fs::read_to_string(&util).unwrap(), UTIL);
+
+        let Workspace::Overlay(ref overlay) = workspace else {
+            unreachable!()
+        };
+        let patches = dir.path().join("patches");
+        assert_eq!(overlay.write_patches(&patches).unwrap(), 2);
+        let read = |name: &str| fs::read_to_string(patches.join(name)).unwrap();
+        assert_eq!(read("0001-main.cpp.patch"), MAIN_PATCH);
+        assert_eq!(read("0002-lib-util.cpp.patch"), UTIL_PATCH);
+
+        let combined = dir.path().join("fixes.patch");
+        overlay.write_patches(&combined).unwrap();
+        assert_eq!(fs::read_to_string(&combined).unwrap(), format!("{}{}", UTIL_PATCH, MAIN_PATCH));
+
+        // Undone fixes drop out of the patch
+        assert_eq!(workspace.undo_last().unwrap(), Some(util.clone()));
+        assert_eq!(fs::read_to_string(&compiled[1]).unwrap(), UTIL);
+        let Workspace::Overlay(ref overlay) = workspace else {
+            unreachable!()
+        };
+        overlay.write_patches(&combined).unwrap();
+        assert_eq!(fs::read_to_string(&combined).unwrap(), MAIN_PATCH);
+        assert_eq!(fs::read_to_string(&util).unwrap(), UTIL);
+    }
+}
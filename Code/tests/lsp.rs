@@ -0,0 +1,61 @@
+// Talks to `code-agent flowscript lsp` the way an editor would, over the
+// process's stdin and stdout
+
+use std::{
+    io::BufReader,
+    process::{Command, Stdio},
+};
+
+use lsp_server::{Message, Notification, Request, RequestId};
+use serde_json::json;
+
+#[test]
+fn diagnostics_come_back_over_stdio() {
+    let dir = tempfile::tempdir().unwrap();
+    let uri = format!("file://{}/main.fs", dir.path().display());
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_code-agent"))
+        .args(["flowscript", "lsp"])
+        .current_dir(dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut to_server = server.stdin.take().unwrap();
+    let mut from_server = BufReader::new(server.stdout.take().unwrap());
+    let mut send = |message: Message| message.write(&mut to_server).unwrap();
+
+    send(Message::Request(Request::new(
+        RequestId::from(1),
+        "initialize".to_owned(),
+        json!({ "capabilities": {} }),
+    )));
+    let Some(Message::Response(initialized)) = Message::read(&mut from_server).unwrap() else {
+        panic!("The server did not answer initialize");
+    };
+    assert_eq!(initialized.id, RequestId::from(1));
+    assert_eq!(initialized.result.unwrap()["capabilities"]["hoverProvider"], json!(true));
+    send(Message::Notification(Notification::new("initialized".to_owned(), json!({}))));
+
+    // The if node has no condition
+    let text = "digraph {\n  input -> check;\n  check [kind=\"if\"];\n}\n";
+    send(Message::Notification(Notification::new(
+        "textDocument/didOpen".to_owned(),
+        json!({ "textDocument": { "uri": uri, "languageId": "flowscript", "version": 1, "text": text } }),
+    )));
+    let Some(Message::Notification(published)) = Message::read(&mut from_server).unwrap() else {
+        panic!("The server did not publish diagnostics");
+    };
+    assert_eq!(published.method, "textDocument/publishDiagnostics");
+    assert_eq!(published.params["uri"], json!(uri));
+    let diagnostics = published.params["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert!(diagnostics[0]["message"].as_str().unwrap().contains("requires a condition"));
+    // At the edge that brought the node in, the first statement to name it
+    assert_eq!(diagnostics[0]["range"]["start"]["line"], json!(1));
+
+    send(Message::Request(Request::new(RequestId::from(2), "shutdown".to_owned(), json!(null))));
+    assert!(matches!(Message::read(&mut from_server).unwrap(), Some(Message::Response(_))));
+    send(Message::Notification(Notification::new("exit".to_owned(), json!(null))));
+    assert!(server.wait().unwrap().success());
+}